@@ -31,6 +31,7 @@ pub fn message_component(_attr: TokenStream, item: TokenStream) -> TokenStream {
                     broker: value.broker,
                     subject: value.subject,
                     response_subject: value.response_subject,
+                    headers: value.headers,
                 }
             }
         }
@@ -42,6 +43,7 @@ pub fn message_component(_attr: TokenStream, item: TokenStream) -> TokenStream {
                     broker: value.broker,
                     subject: value.subject,
                     response_subject: value.response_subject,
+                    headers: value.headers,
                 }
             }
         }
@@ -53,6 +55,7 @@ pub fn message_component(_attr: TokenStream, item: TokenStream) -> TokenStream {
                     subject: value.subject,
                     broker: value.broker,
                     response_subject: value.response_subject,
+                    headers: value.headers,
                 }
             }
         }
@@ -64,17 +67,21 @@ pub fn message_component(_attr: TokenStream, item: TokenStream) -> TokenStream {
                     subject: value.subject,
                     broker: value.broker,
                     response_subject: value.response_subject,
+                    headers: value.headers,
                 }
             }
         }
 
-        impl From<Result<Vec<spin_message_types::OutputMessage>, spin_message_types::MessageError>> for Outcome {
-            fn from(value: Result<Vec<spin_message_types::OutputMessage>, spin_message_types::MessageError>) -> Self {
+        impl From<spin_message_types::MessageOutcome> for Outcome {
+            fn from(value: spin_message_types::MessageOutcome) -> Self {
                 match value {
-                    Ok(vec) => {
+                    spin_message_types::MessageOutcome::Publish(vec) => {
                         Outcome::Publish(vec.into_iter().map(|v| v.into()).collect())
                     }
-                    Err(err) => Outcome::Error(err.to_string()),
+                    spin_message_types::MessageOutcome::Error(err) => Outcome::Error(err.to_string()),
+                    spin_message_types::MessageOutcome::Ack => Outcome::Ack,
+                    spin_message_types::MessageOutcome::Nack { requeue } => Outcome::Nack(requeue),
+                    spin_message_types::MessageOutcome::RetryAfter(delay) => Outcome::RetryAfter(delay.as_millis() as u64),
                 }
             }
         }
@@ -88,18 +95,18 @@ pub fn message_component(_attr: TokenStream, item: TokenStream) -> TokenStream {
                 let response_subject = message.response_subject.clone();
 
                 let Ok(runtime) = spin_message_types::runtime::runtime() else {
-                    return Result::<Vec<spin_message_types::OutputMessage>, spin_message_types::MessageError>::Err(spin_message_types::MessageError("Couldn't generate runtime".to_string())).into();
+                    return spin_message_types::MessageOutcome::Error(spin_message_types::MessageError("Couldn't generate runtime".to_string())).into();
                 };
 
                 let mut result = runtime.block_on(async {
                     let mut result = #func_name(message);
                     let output = result.await;
-                    output
+                    spin_message_types::IntoMessageOutcome::into_outcome(output)
                 });
 
                 println!("Responding with {:?}", response_subject);
 
-                if let Ok(mut v) = result.as_mut() {
+                if let spin_message_types::MessageOutcome::Publish(v) = &mut result {
                     for mut msg in v.iter_mut() {
                         if msg.subject.is_none() {
                             if let Some(response) = &response_subject {
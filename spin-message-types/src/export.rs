@@ -4,14 +4,15 @@ mod inner {
     wasmtime::component::bindgen!({
         path: "wit-message",
         async: true,
-        world: "spin-message-trigger-guest",
+        world: "spin-message-trigger-host",
     });
 }
 
+pub use self::inner::leeorr::spin_message_trigger::broker::Host as BrokerHost;
 pub use self::inner::leeorr::spin_message_trigger::spin_message_types::{
     InternalMessage, InternalOutputMessage, Outcome,
 };
-pub use self::inner::SpinMessageTriggerGuest;
+pub use self::inner::SpinMessageTriggerHost;
 
 impl From<InternalOutputMessage> for OutputMessage {
     fn from(value: InternalOutputMessage) -> Self {
@@ -20,6 +21,7 @@ impl From<InternalOutputMessage> for OutputMessage {
             subject: value.subject,
             broker: value.broker,
             response_subject: value.response_subject,
+            headers: value.headers,
         }
     }
 }
@@ -31,6 +33,19 @@ impl From<InternalMessage> for InputMessage {
             subject: value.subject.to_string(),
             broker: value.broker.to_string(),
             response_subject: value.response_subject.map(|a| a.to_owned()),
+            headers: value.headers,
+        }
+    }
+}
+
+impl From<InputMessage> for InternalMessage {
+    fn from(value: InputMessage) -> Self {
+        Self {
+            message: value.message,
+            subject: value.subject,
+            broker: value.broker,
+            response_subject: value.response_subject,
+            headers: value.headers,
         }
     }
 }
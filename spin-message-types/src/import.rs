@@ -10,3 +10,67 @@ mod platform {
 
 pub use platform::fermyon::spin2_0_0 as spin;
 pub use platform::wasi;
+
+mod message_broker {
+    wit_bindgen::generate!({
+        path: "wit-message",
+        world: "spin-message-broker"
+    });
+}
+
+pub mod broker {
+    use std::time::Duration;
+
+    use super::message_broker::leeorr::spin_message_trigger::{
+        broker,
+        spin_message_types::{InternalMessage, InternalOutputMessage},
+    };
+    use crate::{InputMessage, MessageError, OutputMessage};
+
+    impl From<OutputMessage> for InternalOutputMessage {
+        fn from(value: OutputMessage) -> Self {
+            Self {
+                message: value.message,
+                subject: value.subject,
+                broker: value.broker,
+                response_subject: value.response_subject,
+                headers: value.headers,
+            }
+        }
+    }
+
+    impl From<InternalMessage> for InputMessage {
+        fn from(value: InternalMessage) -> Self {
+            Self {
+                message: value.message,
+                subject: value.subject,
+                broker: value.broker,
+                response_subject: value.response_subject,
+                headers: value.headers,
+            }
+        }
+    }
+
+    /// Publish a message through the host. If no broker is set on the message,
+    /// the broker the current component is subscribed to is used.
+    pub fn publish(message: OutputMessage) -> Result<(), MessageError> {
+        broker::publish(&message.into()).map_err(MessageError)
+    }
+
+    pub fn publish_all(messages: Vec<OutputMessage>) -> Result<(), MessageError> {
+        let messages: Vec<InternalOutputMessage> = messages.into_iter().map(|v| v.into()).collect();
+        broker::publish_all(&messages).map_err(MessageError)
+    }
+
+    /// Publish a request and wait for the first response. The timeout defaults
+    /// to the host's request timeout if it isn't provided.
+    pub fn request(
+        message: OutputMessage,
+        timeout: Option<Duration>,
+    ) -> Result<InputMessage, MessageError> {
+        let timeout = timeout.map(|t| t.as_millis() as u64);
+        broker::request(&message.into(), timeout)
+            .map(|v| v.into())
+            .map_err(MessageError)
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::{bail, Result};
 use http::{HeaderMap, Method, StatusCode, Uri};
 use serde::{Deserialize, Serialize};
-use std::fmt::Display;
+use std::{fmt::Display, time::Duration};
 
 #[cfg(feature = "export")]
 pub mod export;
@@ -18,6 +18,8 @@ pub struct InputMessage {
     pub subject: String,
     pub broker: String,
     pub response_subject: Option<String>,
+    #[serde(default)]
+    pub headers: Vec<(String, Vec<u8>)>,
 }
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
@@ -26,6 +28,8 @@ pub struct OutputMessage {
     pub subject: Option<String>,
     pub broker: Option<String>,
     pub response_subject: Option<String>,
+    #[serde(default)]
+    pub headers: Vec<(String, Vec<u8>)>,
 }
 
 #[derive(Debug, Clone)]
@@ -37,6 +41,36 @@ impl Display for MessageError {
     }
 }
 
+/// The result of handling a message. Returning a `Result<Vec<OutputMessage>, MessageError>`
+/// from a handler is equivalent to `Publish` or `Error`.
+#[derive(Clone, Debug)]
+pub enum MessageOutcome {
+    Publish(Vec<OutputMessage>),
+    Error(MessageError),
+    Ack,
+    Nack { requeue: bool },
+    RetryAfter(Duration),
+}
+
+pub trait IntoMessageOutcome {
+    fn into_outcome(self) -> MessageOutcome;
+}
+
+impl IntoMessageOutcome for MessageOutcome {
+    fn into_outcome(self) -> MessageOutcome {
+        self
+    }
+}
+
+impl IntoMessageOutcome for Result<Vec<OutputMessage>, MessageError> {
+    fn into_outcome(self) -> MessageOutcome {
+        match self {
+            Ok(messages) => MessageOutcome::Publish(messages),
+            Err(err) => MessageOutcome::Error(err),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HttpRequest {
     #[serde(with = "http_serde::method")]
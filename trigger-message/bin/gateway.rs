@@ -3,9 +3,18 @@ use std::sync::Arc;
 use anyhow::Error;
 use clap::Parser;
 use trigger_message::{
+    auth::Authenticator,
     broker::MessageBroker,
-    configs::{BrokerTypeConfig, GatewayRequestResponseConfig, WebsocketConfig},
+    configs::{
+        BrokerTypeConfig, GatewayAuthConfig, GatewayBodyLimitConfig, GatewayCorsConfig,
+        GatewayRateLimitConfig, GatewayRequestResponseConfig, GatewayTlsConfig, LogFormat,
+        RateLimit, TelemetryConfig, WebsocketConfig,
+    },
     gateway::spawn_gateway,
+    limits::GatewayLimits,
+    metrics::MeteredBroker,
+    telemetry::{self, TracedBroker},
+    tls::rustls_config,
 };
 
 /// Simple program to greet a person
@@ -31,6 +40,46 @@ struct Args {
     /// A query string defining the broker
     #[clap(short, long)]
     broker: BrokerTypeConfig,
+
+    /// Log Format - text or json
+    #[clap(long, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Include message payloads in the logs
+    #[clap(long)]
+    log_payloads: bool,
+
+    /// Path to a json file with the gateway's api keys, jwt settings & acls
+    #[clap(long)]
+    auth: Option<String>,
+
+    /// Path to a PEM certificate chain, to serve the gateway over HTTPS
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<String>,
+
+    /// Path to the PEM private key for the certificate
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<String>,
+
+    /// Path to PEM CA certificates - clients need a certificate signed by one of them
+    #[clap(long, requires = "tls-cert")]
+    tls_client_ca: Option<String>,
+
+    /// An origin browsers can call the gateway from, or "*" for any. Can be repeated
+    #[clap(long)]
+    cors_origin: Vec<String>,
+
+    /// The largest body accepted by /publish & /request, in bytes
+    #[clap(long)]
+    max_body_size: Option<usize>,
+
+    /// Requests per second allowed from each client IP
+    #[clap(long)]
+    rate_limit_per_ip: Option<u32>,
+
+    /// Requests per second allowed from each authenticated identity
+    #[clap(long)]
+    rate_limit_per_key: Option<u32>,
 }
 
 #[tokio::main]
@@ -41,8 +90,58 @@ async fn main() -> Result<(), Error> {
         timeout,
         request_response,
         broker,
+        log_format,
+        log_payloads,
+        auth,
+        tls_cert,
+        tls_key,
+        tls_client_ca,
+        cors_origin,
+        max_body_size,
+        rate_limit_per_ip,
+        rate_limit_per_key,
     } = Args::parse();
 
+    telemetry::init(Some(&TelemetryConfig {
+        log_format,
+        log_payloads,
+        ..Default::default()
+    }))?;
+
+    let auth = match auth {
+        Some(path) => {
+            let config: GatewayAuthConfig = serde_json::from_slice(&std::fs::read(path)?)?;
+            Some(Authenticator::new(&config)?)
+        }
+        None => None,
+    };
+
+    let tls = match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => Some(rustls_config(&GatewayTlsConfig {
+            cert,
+            key,
+            client_ca: tls_client_ca,
+        })?),
+        _ => None,
+    };
+
+    let cors = (!cors_origin.is_empty()).then(|| GatewayCorsConfig {
+        origins: cors_origin,
+        ..Default::default()
+    });
+    let body_limit = max_body_size.map(|max_bytes| GatewayBodyLimitConfig { max_bytes });
+    let rate_limit = |per_second| RateLimit {
+        per_second,
+        burst: None,
+    };
+    let rate_limit = (rate_limit_per_ip.is_some() || rate_limit_per_key.is_some()).then(|| {
+        GatewayRateLimitConfig {
+            per_ip: rate_limit_per_ip.map(rate_limit),
+            per_key: rate_limit_per_key.map(rate_limit),
+        }
+    });
+    let limits = GatewayLimits::new(cors.as_ref(), body_limit.as_ref(), rate_limit.as_ref())?;
+
     let broker_key: String = "BROKER".to_string();
 
     let broker: Arc<dyn MessageBroker> =
@@ -50,18 +149,48 @@ async fn main() -> Result<(), Error> {
             BrokerTypeConfig::InMemoryBroker => Arc::new(
                 trigger_message::in_memory_broker::InMemoryBroker::new(broker_key.clone()),
             ),
+            BrokerTypeConfig::File(options) => Arc::new(
+                trigger_message::file_broker::FileBroker::new(options, broker_key.clone())?,
+            ),
             BrokerTypeConfig::Redis(address) => Arc::new(
                 trigger_message::redis_broker::RedisBroker::new(address, broker_key.clone()),
             ),
+            BrokerTypeConfig::RedisStreams(options) => Arc::new(
+                trigger_message::redis_broker::RedisBroker::new_with_streams(
+                    options,
+                    broker_key.clone(),
+                ),
+            ),
             BrokerTypeConfig::Nats(options) => Arc::new(
                 trigger_message::nats_broker::NatsBroker::new(options, broker_key.clone()),
             ),
             BrokerTypeConfig::Mqtt(options) => Arc::new(
                 trigger_message::mqtt_broker::MqttBroker::new(options, broker_key.clone()),
             ),
+            BrokerTypeConfig::Amqp(options) => Arc::new(
+                trigger_message::amqp_broker::AmqpBroker::new(options, broker_key.clone()),
+            ),
+            BrokerTypeConfig::Kafka(options) => Arc::new(
+                trigger_message::kafka_broker::KafkaBroker::new(options, broker_key.clone()),
+            ),
+            BrokerTypeConfig::Postgres(options) => Arc::new(
+                trigger_message::postgres_broker::PostgresBroker::new(options, broker_key.clone()),
+            ),
         };
 
-    spawn_gateway(port, websockets.clone(), broker, request_response, timeout).await;
+    let broker = TracedBroker::wrap(MeteredBroker::wrap(broker));
+
+    spawn_gateway(
+        port,
+        websockets.clone(),
+        broker,
+        request_response,
+        timeout,
+        auth,
+        tls,
+        limits,
+    )
+    .await;
 
     Ok(())
 }
@@ -0,0 +1,424 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::http::{header::AUTHORIZATION, HeaderMap};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use crate::{
+    broker::Wildcards,
+    configs::{GatewayAuthConfig, JwtConfig, JwtKey, SubjectAcl},
+};
+
+const API_KEY_HEADER: &str = "x-api-key";
+const ACCESS_TOKEN_PARAM: &str = "access_token";
+const DEFAULT_ACL: &str = "*";
+
+/// Characters that at least one broker reads as a wildcard, or as part of a glob pattern
+const WILDCARD_CHARS: [char; 7] = ['*', '>', '#', '+', '?', '[', '\\'];
+
+/// What a token of a subscribed subject can match, on a broker with the given wildcards
+enum SubjectToken<'a> {
+    Literal(&'a str),
+    /// Any single token
+    One,
+    /// The rest of the subject
+    Rest,
+    /// A token some brokers read as a pattern, so it's only allowed by exactly the same token
+    Special(&'a str),
+}
+
+impl<'a> SubjectToken<'a> {
+    fn new(token: &'a str, wildcards: Wildcards) -> Self {
+        match (token, wildcards) {
+            // MQTT & AMQP read `#` as the rest of the topic
+            (">" | "#", _) => SubjectToken::Rest,
+            ("*", Wildcards::Tokens) => SubjectToken::One,
+            ("*", Wildcards::Glob) => SubjectToken::Rest,
+            (token, _) if token.contains(WILDCARD_CHARS) => SubjectToken::Special(token),
+            (token, _) => SubjectToken::Literal(token),
+        }
+    }
+}
+
+/// A subject pattern in an ACL, split into `.` separated tokens. `*` matches a single
+/// token, and a trailing `>` (or `#`) matches one or more.
+#[derive(Clone, Debug)]
+struct SubjectPattern(Vec<String>);
+
+impl SubjectPattern {
+    fn new(pattern: &str) -> Self {
+        Self(pattern.split('.').map(str::to_string).collect())
+    }
+
+    /// Wildcards in the subject only match a pattern with the same or a broader wildcard,
+    /// so a subscription to a pattern is only allowed if it can't receive anything else
+    fn allows(&self, subject: &str, wildcards: Wildcards) -> bool {
+        let subject: Vec<_> = subject
+            .split('.')
+            .map(|token| SubjectToken::new(token, wildcards))
+            .collect();
+        for (index, pattern) in self.0.iter().enumerate() {
+            if pattern == ">" || pattern == "#" {
+                return subject.len() > index;
+            }
+            let allowed = match (pattern.as_str(), subject.get(index)) {
+                (_, None | Some(SubjectToken::Rest)) => false,
+                ("*", Some(SubjectToken::Literal(_) | SubjectToken::One)) => true,
+                (_, Some(SubjectToken::One)) => false,
+                (pattern, Some(SubjectToken::Literal(token) | SubjectToken::Special(token))) => {
+                    pattern == *token
+                }
+            };
+            if !allowed {
+                return false;
+            }
+        }
+        self.0.len() == subject.len()
+    }
+}
+
+/// The subjects an identity can publish & subscribe to
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    publish: Vec<SubjectPattern>,
+    subscribe: Vec<SubjectPattern>,
+}
+
+impl Acl {
+    pub fn new(config: &SubjectAcl) -> Self {
+        let patterns =
+            |patterns: &[String]| patterns.iter().map(|p| SubjectPattern::new(p)).collect();
+        Self {
+            publish: patterns(&config.publish),
+            subscribe: patterns(&config.subscribe),
+        }
+    }
+}
+
+/// Whoever made a request to the gateway
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub name: String,
+    /// `None` if the gateway doesn't authenticate requests, so everything is allowed
+    acl: Option<Arc<Acl>>,
+}
+
+impl Identity {
+    pub fn anonymous() -> Self {
+        Self {
+            name: "anonymous".to_string(),
+            acl: None,
+        }
+    }
+
+    pub fn can_publish(&self, subject: &str) -> bool {
+        self.acl.as_ref().is_none_or(|acl| {
+            acl.publish
+                .iter()
+                .any(|pattern| pattern.allows(subject, Wildcards::Tokens))
+        })
+    }
+
+    /// Subscribing to `orders.*` needs a pattern like `orders.*` or `orders.>`,
+    /// while `orders.>` is only allowed by `orders.>` or a broader pattern.
+    /// On brokers where `*` matches across tokens, `orders.*` needs `orders.>` too.
+    pub fn can_subscribe(&self, subject: &str, wildcards: Wildcards) -> bool {
+        self.acl.as_ref().is_none_or(|acl| {
+            acl.subscribe
+                .iter()
+                .any(|pattern| pattern.allows(subject, wildcards))
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+#[derive(Clone)]
+enum JwtKeys {
+    Single(DecodingKey),
+    /// Keys from a JWKS file, by their key id
+    Set(Vec<(Option<String>, DecodingKey)>),
+}
+
+#[derive(Clone)]
+struct JwtVerifier {
+    keys: JwtKeys,
+    validation: Validation,
+}
+
+impl JwtVerifier {
+    fn new(config: &JwtConfig) -> Result<Self> {
+        let read = |path: &str| {
+            std::fs::read(path).with_context(|| format!("Couldn't read JWT key from {path}"))
+        };
+        let (keys, algorithm) = match &config.key {
+            JwtKey::HS256(secret) => (
+                JwtKeys::Single(DecodingKey::from_secret(secret.as_bytes())),
+                Algorithm::HS256,
+            ),
+            JwtKey::RS256(path) => (
+                JwtKeys::Single(DecodingKey::from_rsa_pem(&read(path)?)?),
+                Algorithm::RS256,
+            ),
+            JwtKey::Jwks(path) => {
+                let jwks: JwkSet = serde_json::from_slice(&read(path)?)?;
+                let keys = jwks
+                    .keys
+                    .iter()
+                    .map(|jwk| Ok((jwk.common.key_id.clone(), DecodingKey::from_jwk(jwk)?)))
+                    .collect::<Result<_>>()?;
+                (JwtKeys::Set(keys), Algorithm::RS256)
+            }
+        };
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Ok(Self { keys, validation })
+    }
+
+    /// Checks the token, returning its subject
+    fn verify(&self, token: &str) -> Result<String> {
+        let key = match &self.keys {
+            JwtKeys::Single(key) => key,
+            JwtKeys::Set(keys) => {
+                let kid = decode_header(token)?.kid;
+                let mut candidates = keys.iter().filter(|(id, _)| kid.is_none() || *id == kid);
+                match (candidates.next(), candidates.next()) {
+                    (Some((_, key)), None) => key,
+                    (Some(_), Some(_)) => bail!("The token needs a kid to pick a key"),
+                    (None, _) => bail!("No key matches the token's kid"),
+                }
+            }
+        };
+        Ok(decode::<Claims>(token, key, &self.validation)?.claims.sub)
+    }
+}
+
+/// Checks the credentials on gateway requests, and finds the ACL for whoever sent them
+#[derive(Clone)]
+pub struct Authenticator {
+    api_keys: Vec<(String, String)>,
+    jwt: Option<JwtVerifier>,
+    acls: HashMap<String, Arc<Acl>>,
+}
+
+impl Authenticator {
+    pub fn new(config: &GatewayAuthConfig) -> Result<Self> {
+        if config.api_keys.is_empty() && config.jwt.is_none() {
+            bail!("Gateway auth needs api keys or a jwt key");
+        }
+        Ok(Self {
+            api_keys: config
+                .api_keys
+                .iter()
+                .map(|(identity, key)| (identity.clone(), key.clone()))
+                .collect(),
+            jwt: config.jwt.as_ref().map(JwtVerifier::new).transpose()?,
+            acls: config
+                .acl
+                .iter()
+                .map(|(identity, acl)| (identity.clone(), Arc::new(Acl::new(acl))))
+                .collect(),
+        })
+    }
+
+    /// Finds who's making a request, from an `Authorization: Bearer` or `X-Api-Key` header.
+    /// Browsers can't set headers on websockets, so an `access_token` query parameter works too.
+    pub fn authenticate(&self, headers: &HeaderMap, query: Option<&str>) -> Result<Identity> {
+        let token = credentials(headers, query).ok_or_else(|| anyhow!("No credentials"))?;
+        let api_key = self
+            .api_keys
+            .iter()
+            .find(|(_, key)| constant_time_eq(key.as_bytes(), token.as_bytes()));
+        let name = match (api_key, &self.jwt) {
+            (Some((identity, _)), _) => identity.clone(),
+            (None, Some(jwt)) => jwt.verify(&token)?,
+            (None, None) => bail!("Unknown API key"),
+        };
+        let acl = self
+            .acls
+            .get(&name)
+            .or_else(|| self.acls.get(DEFAULT_ACL))
+            .cloned()
+            .unwrap_or_default();
+        Ok(Identity {
+            name,
+            acl: Some(acl),
+        })
+    }
+}
+
+fn credentials(headers: &HeaderMap, query: Option<&str>) -> Option<String> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(token) = header(AUTHORIZATION.as_str()).and_then(|h| h.strip_prefix("Bearer ")) {
+        return Some(token.trim().to_string());
+    }
+    if let Some(key) = header(API_KEY_HEADER) {
+        return Some(key.to_string());
+    }
+    let mut params: HashMap<String, String> = serde_qs::from_str(query?).ok()?;
+    params.remove(ACCESS_TOKEN_PARAM)
+}
+
+/// Compares secrets without returning early, so the time taken doesn't give away how much matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use axum::http::HeaderMap;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::{Authenticator, SubjectPattern};
+    use crate::{
+        broker::Wildcards,
+        configs::{GatewayAuthConfig, JwtConfig, JwtKey, SubjectAcl},
+    };
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(&GatewayAuthConfig {
+            api_keys: HashMap::from([("reports".to_string(), "report-key".to_string())]),
+            jwt: Some(JwtConfig {
+                key: JwtKey::HS256("secret".to_string()),
+                issuer: None,
+                audience: None,
+            }),
+            acl: HashMap::from([
+                (
+                    "reports".to_string(),
+                    SubjectAcl {
+                        publish: vec!["reports.*".to_string()],
+                        subscribe: vec![],
+                    },
+                ),
+                (
+                    "*".to_string(),
+                    SubjectAcl {
+                        publish: vec![],
+                        subscribe: vec!["alerts.*".to_string()],
+                    },
+                ),
+            ]),
+        })
+        .unwrap()
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", format!("Bearer {token}").parse().unwrap());
+        headers
+    }
+
+    fn token(secret: &str) -> String {
+        let claims = json!({"sub": "dashboard", "exp": 4102444800u64});
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn api_keys_get_their_own_acl() {
+        let identity = authenticator()
+            .authenticate(&bearer("report-key"), None)
+            .unwrap();
+
+        assert_eq!(identity.name, "reports");
+        assert!(identity.can_publish("reports.daily"));
+        assert!(!identity.can_publish("orders.created"));
+        assert!(!identity.can_subscribe("alerts.fire", Wildcards::Tokens));
+    }
+
+    #[test]
+    fn jwts_use_the_default_acl() {
+        let auth = authenticator();
+        let identity = auth
+            .authenticate(
+                &HeaderMap::new(),
+                Some(&format!("access_token={}", token("secret"))),
+            )
+            .unwrap();
+
+        assert_eq!(identity.name, "dashboard");
+        assert!(identity.can_subscribe("alerts.fire", Wildcards::Tokens));
+        assert!(!identity.can_publish("alerts.fire"));
+        assert!(auth.authenticate(&bearer(&token("wrong")), None).is_err());
+    }
+
+    #[test]
+    fn acls_match_whole_tokens() {
+        let allows = |pattern: &str, subject: &str| {
+            SubjectPattern::new(pattern).allows(subject, Wildcards::Tokens)
+        };
+        assert!(allows("orders.*", "orders.created"));
+        assert!(allows("orders.*", "orders.*"));
+        assert!(!allows("orders.*", "orders.eu.created"));
+        assert!(!allows("orders.*", "orders.>"));
+        assert!(!allows("orders.*", "orders"));
+
+        assert!(allows("orders.>", "orders.eu.created"));
+        assert!(allows("orders.>", "orders.>"));
+        assert!(allows("orders.>", "orders.*.created"));
+        assert!(!allows("orders.>", "orders"));
+        assert!(!allows("orders.>", "ordersx.created"));
+
+        assert!(allows("orders.created", "orders.created"));
+        assert!(!allows("orders.created", "orders.*"));
+    }
+
+    #[test]
+    fn acls_reject_other_brokers_wildcards() {
+        let allows = |pattern: &str, subject: &str| {
+            SubjectPattern::new(pattern).allows(subject, Wildcards::Tokens)
+        };
+        // `#` is the rest of the topic on MQTT & AMQP, and `+` a single MQTT level
+        assert!(!allows("alerts.*", "alerts.#"));
+        assert!(!allows("*", "#"));
+        assert!(allows("alerts.>", "alerts.#"));
+        assert!(allows("alerts.#", "alerts.>"));
+        assert!(!allows("alerts.*", "alerts.+"));
+        assert!(!allows("alerts.*", "alerts.fi+e"));
+        assert!(!allows("alerts.*", "alerts.fi?e"));
+        assert!(!allows("alerts.*", "alerts.[f]ire"));
+        assert!(!allows("alerts.*", "alerts.fi*"));
+        assert!(allows("alerts.fi?e", "alerts.fi?e"));
+    }
+
+    #[test]
+    fn glob_wildcards_span_tokens() {
+        let allows = |pattern: &str, subject: &str| {
+            SubjectPattern::new(pattern).allows(subject, Wildcards::Glob)
+        };
+        // `orders.*` also receives `orders.eu.secret` on these brokers
+        assert!(!allows("orders.*", "orders.*"));
+        assert!(allows("orders.>", "orders.*"));
+        assert!(!allows("orders.*.created", "orders.*.created"));
+        assert!(allows("orders.created", "orders.created"));
+        assert!(allows("orders.*", "orders.created"));
+    }
+
+    #[test]
+    fn requests_without_credentials_are_rejected() {
+        let auth = authenticator();
+        assert!(auth.authenticate(&HeaderMap::new(), None).is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", "nope".parse().unwrap());
+        assert!(auth.authenticate(&headers, None).is_err());
+    }
+}
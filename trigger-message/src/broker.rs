@@ -1,22 +1,123 @@
+use std::{fmt::Debug, ops::Deref, sync::Arc, time::Duration};
+
 use anyhow::{bail, Result};
 use async_trait::async_trait;
 use serde::Serialize;
 use tokio::sync::broadcast;
+use tracing::debug;
 
 use spin_message_types::{HttpRequest, HttpResponse, InputMessage, OutputMessage};
 
-use crate::configs::{GatewayRequestResponseConfig, SubscriptionType};
+use crate::{
+    configs::{GatewayRequestResponseConfig, StreamSubscription, SubscriptionType},
+    connection::{BrokerHealth, ConnectionEvent},
+    telemetry,
+};
+
+pub type Receiver = broadcast::Receiver<Delivery>;
+pub type QueueReceiver = broadcast::Receiver<Delivery>;
+pub type Sender = broadcast::Sender<Delivery>;
+pub type QueueSender = broadcast::Sender<Delivery>;
+
+/// How a delivered message should be settled with the broker once it has been handled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Acknowledgement {
+    Ack,
+    Nack { requeue: bool },
+    RetryAfter(Duration),
+}
+
+#[async_trait]
+pub trait Acker: Debug + Send + Sync {
+    async fn acknowledge(&self, ack: Acknowledgement) -> Result<()>;
+}
+
+/// A message received from a subscription. Brokers that support redelivery attach an
+/// `Acker`, otherwise settling the delivery does nothing.
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    pub input: InputMessage,
+    pub acker: Option<Arc<dyn Acker>>,
+}
 
-pub type Receiver = broadcast::Receiver<InputMessage>;
-pub type QueueReceiver = broadcast::Receiver<InputMessage>;
-pub type Sender = broadcast::Sender<InputMessage>;
-pub type QueueSender = broadcast::Sender<InputMessage>;
+impl Delivery {
+    pub fn with_acker(input: InputMessage, acker: Arc<dyn Acker>) -> Self {
+        Self {
+            input,
+            acker: Some(acker),
+        }
+    }
+
+    pub fn can_acknowledge(&self) -> bool {
+        self.acker.is_some()
+    }
+
+    pub async fn acknowledge(&self, ack: Acknowledgement) -> Result<()> {
+        match &self.acker {
+            Some(acker) => acker.acknowledge(ack).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<InputMessage> for Delivery {
+    fn from(input: InputMessage) -> Self {
+        Self { input, acker: None }
+    }
+}
+
+impl Deref for Delivery {
+    type Target = InputMessage;
+
+    fn deref(&self) -> &Self::Target {
+        &self.input
+    }
+}
+
+/// How a broker matches wildcards in topic subscriptions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wildcards {
+    /// `*` matches a single `.` separated token, and a trailing `>` matches the rest
+    Tokens,
+    /// `*` matches anything, including `.`, like a glob pattern
+    Glob,
+}
 
 pub fn create_channel(capacity: usize) -> Sender {
     let (sender, _) = broadcast::channel(capacity);
     sender
 }
 
+/// Headers that only apply to a single HTTP connection, so they're never carried between HTTP & messages
+pub const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "host",
+    "connection",
+    "content-length",
+    "transfer-encoding",
+    "upgrade",
+    "keep-alive",
+    "te",
+    "trailer",
+];
+
+pub fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name))
+}
+
+/// Converts HTTP headers to message headers, dropping the hop by hop ones.
+/// Takes names & values rather than a `HeaderMap`, since the gateway & spin use different `http` versions.
+pub fn http_headers_to_message_headers<'a>(
+    headers: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+) -> Vec<(String, Vec<u8>)> {
+    headers
+        .into_iter()
+        .filter(|(name, _)| !is_hop_by_hop(name))
+        .map(|(name, value)| (name.to_string(), value.to_vec()))
+        .collect()
+}
+
 pub fn default_message_response_subject(subject: &str) -> Option<String> {
     if subject.starts_with("request") {
         Some(subject.replace("request", "response"))
@@ -47,6 +148,50 @@ pub trait MessageBroker: Send + Sync {
 
     async fn subscribe_to_queue(&self, topic: &str, group: &str) -> Result<QueueReceiver>;
 
+    /// Changes to the broker's connection state. Brokers that don't connect to a server have none.
+    fn connection_events(&self) -> Option<broadcast::Receiver<ConnectionEvent>> {
+        None
+    }
+
+    /// Whether the broker is connected, along with the last error it ran into.
+    /// Brokers that don't connect to a server are always connected.
+    fn health(&self) -> BrokerHealth {
+        BrokerHealth::default()
+    }
+
+    /// Finishes sending anything that's waiting to be published, then closes the broker's connections.
+    /// Publishing fails once this has been called.
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn subscribe_to_stream(
+        &self,
+        _subscription: &StreamSubscription,
+    ) -> Result<QueueReceiver> {
+        bail!("Stream subscriptions aren't supported by {}", self.name())
+    }
+
+    /// Subscribes to a topic, first redelivering anything published to it after the given sequence.
+    /// Returns `None` for brokers that can't replay messages.
+    async fn subscribe_to_topic_from(
+        &self,
+        _subject: &str,
+        _after: u64,
+    ) -> Result<Option<Receiver>> {
+        Ok(None)
+    }
+
+    /// Where a delivered message sits in the broker's log, for brokers that can replay messages
+    fn sequence(&self, _message: &InputMessage) -> Option<u64> {
+        None
+    }
+
+    /// How the broker matches wildcards in topic subscriptions
+    fn wildcards(&self) -> Wildcards {
+        Wildcards::Tokens
+    }
+
     async fn subscribe(&self, subscription: &SubscriptionType) -> Result<Receiver> {
         match subscription {
             SubscriptionType::Topic { topic, result: _ } => self.subscribe_to_topic(topic).await,
@@ -58,6 +203,7 @@ pub trait MessageBroker: Send + Sync {
                 group,
                 result: _,
             } => self.subscribe_to_queue(topic, group).await,
+            SubscriptionType::Stream(subscription) => self.subscribe_to_stream(subscription).await,
             SubscriptionType::None => bail!("No Subscription Type Set for {}", self.name()),
         }
     }
@@ -107,7 +253,7 @@ pub trait MessageBroker: Send + Sync {
             bail!("couldn't get result");
         };
 
-        Ok(result)
+        Ok(result.input)
     }
 
     async fn http_request(
@@ -141,15 +287,31 @@ pub trait MessageBroker: Send + Sync {
             message: body,
             broker: None,
             response_subject: Some(response_subject.clone()),
+            headers: http_headers_to_message_headers(
+                request
+                    .headers
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_bytes())),
+            ),
         };
 
-        println!("Generated HTTP Request: {message:?}");
+        debug!(
+            broker = self.name(),
+            %subject,
+            request_id = telemetry::request_id(&subject),
+            "Forwarding HTTP request"
+        );
 
         let result = self.request(message).await?;
 
-        println!("Got Response: {result:?}");
+        debug!(
+            broker = self.name(),
+            subject = %result.subject,
+            payload = %telemetry::payload(&result.message),
+            "Got HTTP response"
+        );
 
-        let result = match serializer {
+        let response = match serializer {
             GatewayRequestResponseConfig::Messagepack => {
                 rmp_serde::from_slice::<HttpResponse>(&result.message).ok()
             }
@@ -157,8 +319,26 @@ pub trait MessageBroker: Send + Sync {
                 serde_json::from_slice::<HttpResponse>(&result.message).ok()
             }
         };
-        if let Some(result) = result {
-            Ok(result)
+        if let Some(mut response) = response {
+            for (name, value) in result.headers.iter() {
+                if is_hop_by_hop(name) {
+                    continue;
+                }
+                let (Ok(name), Ok(value)) = (
+                    http::HeaderName::from_bytes(name.as_bytes()),
+                    http::HeaderValue::from_bytes(value),
+                ) else {
+                    continue;
+                };
+                if !response.headers.contains_key(&name) {
+                    response.headers.insert(name, value);
+                }
+            }
+            // The component's response is framed again by the gateway
+            for name in HOP_BY_HOP_HEADERS {
+                response.headers.remove(name);
+            }
+            Ok(response)
         } else {
             bail!("couldn't process result")
         }
@@ -1,8 +1,15 @@
-use std::str::FromStr;
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use rand::Rng;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{mqtt_broker::MqttConnectionInfo, nats_broker::NatsConnectionInfo};
+use crate::{
+    amqp_broker::AmqpConnectionInfo, file_broker::FileBrokerConfig,
+    kafka_broker::KafkaConnectionInfo, mqtt_broker::MqttConnectionInfo,
+    nats_broker::NatsConnectionInfo, postgres_broker::PostgresConnectionInfo,
+    redis_broker::RedisStreamsConnectionInfo,
+};
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct BrokerConfig {
@@ -11,13 +18,51 @@ pub struct BrokerConfig {
     pub gateway: GatewayConfig,
 }
 
+/// Where the trigger exports its traces - falling back to the standard
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` & `OTEL_SERVICE_NAME` environment variables
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TelemetryConfig {
+    pub otlp_endpoint: Option<String>,
+    pub service_name: Option<String>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Include message payloads in the logs, rather than just their size
+    #[serde(default)]
+    pub log_payloads: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow::Error::msg("Invalid Log Format")),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub enum BrokerTypeConfig {
     #[default]
     InMemoryBroker,
+    File(FileBrokerConfig),
     Redis(String),
+    RedisStreams(RedisStreamsConnectionInfo),
     Nats(NatsConnectionInfo),
     Mqtt(MqttConnectionInfo),
+    Amqp(AmqpConnectionInfo),
+    Kafka(KafkaConnectionInfo),
+    Postgres(PostgresConnectionInfo),
 }
 
 impl FromStr for BrokerTypeConfig {
@@ -73,6 +118,8 @@ impl FromStr for GatewayRequestResponseConfig {
     }
 }
 
+// Only read once on startup, so the size difference doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub enum GatewayConfig {
     #[default]
@@ -82,9 +129,109 @@ pub enum GatewayConfig {
         websockets: Option<WebsocketConfig>,
         request_response: Option<GatewayRequestResponseConfig>,
         timeout: Option<u64>,
+        auth: Option<GatewayAuthConfig>,
+        tls: Option<GatewayTlsConfig>,
+        cors: Option<GatewayCorsConfig>,
+        body_limit: Option<GatewayBodyLimitConfig>,
+        rate_limit: Option<GatewayRateLimitConfig>,
     },
 }
 
+/// Lets browsers on other origins call the gateway
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayCorsConfig {
+    /// Allowed origins, like "https://example.com", or "*" for any
+    pub origins: Vec<String>,
+    /// Allowed methods, or "*" for any. Defaults to GET & POST
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Headers browsers can send, or "*" for any.
+    /// Defaults to the ones the gateway reads - content-type, authorization, x-api-key & last-event-id
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// How long browsers can cache preflight responses, in seconds
+    pub max_age: Option<u64>,
+}
+
+/// Limits the size of `/publish` & `/request` bodies
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayBodyLimitConfig {
+    pub max_bytes: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayRateLimitConfig {
+    /// Limits requests from each client IP address
+    pub per_ip: Option<RateLimit>,
+    /// Limits requests from each authenticated identity. Only applies if the gateway has auth set up.
+    pub per_key: Option<RateLimit>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub per_second: u32,
+    /// How many requests can be made in a burst. Defaults to `per_second`
+    pub burst: Option<u32>,
+}
+
+/// Serves the gateway over HTTPS
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayTlsConfig {
+    /// Path to the PEM encoded certificate chain
+    pub cert: String,
+    /// Path to the PEM encoded private key
+    pub key: String,
+    /// Path to PEM encoded CA certificates - if set, clients need a certificate signed by one of them
+    pub client_ca: Option<String>,
+}
+
+/// Restricts who can use a gateway, and which subjects they can use
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayAuthConfig {
+    /// Static API keys, keyed by the identity they authenticate as
+    #[serde(default)]
+    pub api_keys: HashMap<String, String>,
+    pub jwt: Option<JwtConfig>,
+    /// The subjects each identity can use - identities without an entry use the "*" entry, if there is one
+    #[serde(default)]
+    pub acl: HashMap<String, SubjectAcl>,
+}
+
+/// Verifies bearer tokens, using their `sub` claim as the identity
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    pub key: JwtKey,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum JwtKey {
+    /// A shared secret
+    HS256(String),
+    /// The path to a PEM encoded public key
+    RS256(String),
+    /// The path to a JWKS file, with keys picked by the token's `kid`
+    Jwks(String),
+}
+
+/// Subject patterns an identity can publish & subscribe to, using the same wildcards as subscriptions
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubjectAcl {
+    #[serde(default)]
+    pub publish: Vec<String>,
+    #[serde(default)]
+    pub subscribe: Vec<String>,
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub enum SubscriptionType {
@@ -103,6 +250,40 @@ pub enum SubscriptionType {
         group: String,
         result: Option<MessageResultType>,
     },
+    Stream(StreamSubscription),
+}
+
+/// A durable subscription, which keeps track of the messages that were handled
+/// so nothing published while the app is down is lost.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamSubscription {
+    pub(crate) stream: String,
+    pub(crate) consumer: String,
+    pub(crate) topic: Option<String>,
+    pub(crate) deliver_policy: Option<StreamDeliverPolicy>,
+    pub(crate) ack_policy: Option<StreamAckPolicy>,
+    pub(crate) max_ack_pending: Option<i64>,
+    pub(crate) result: Option<MessageResultType>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub enum StreamDeliverPolicy {
+    #[default]
+    All,
+    Last,
+    New,
+    BySequence(u64),
+    /// An RFC 3339 timestamp
+    ByTime(String),
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub enum StreamAckPolicy {
+    #[default]
+    Explicit,
+    All,
+    None,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -111,6 +292,39 @@ pub struct MessageTriggerConfig {
     pub(crate) component: String,
     pub(crate) broker: String,
     pub(crate) subscription: SubscriptionType,
+    pub(crate) retry: Option<RetryConfig>,
+    pub(crate) dead_letter: Option<DeadLetterConfig>,
+    /// How many messages the component handles at once. Defaults to 1
+    pub(crate) max_concurrency: Option<usize>,
+    /// How many received messages can wait for the component. Defaults to 100
+    pub(crate) queue_size: Option<usize>,
+    pub(crate) overflow: Option<OverflowPolicy>,
+    /// Handles messages with the same key one at a time, in the order they were received
+    pub(crate) ordering: Option<OrderingKey>,
+}
+
+/// Which part of a message its ordering key comes from
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum OrderingKey {
+    /// A segment of the subject, counting from 0 - so 1 is "42" in "orders.42.created"
+    SubjectSegment(usize),
+    Header(String),
+    /// A path into a json payload, like "$.order.id" or "$.items[0].sku"
+    JsonPath(String),
+}
+
+/// What happens to received messages once a component's queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum OverflowPolicy {
+    /// Stop receiving until there's room in the queue
+    #[default]
+    Block,
+    /// Drop the oldest queued message to make room
+    DropOldest,
+    /// Drop the message that was just received
+    DropNewest,
+    /// Send the message that was just received to the component's dead letter subject
+    DeadLetter,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -118,3 +332,156 @@ pub struct MessageResultType {
     pub(crate) default_broker: String,
     pub(crate) default_subject: String,
 }
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    pub(crate) max_attempts: u32,
+    /// The delay before the first retry, in milliseconds
+    pub(crate) initial_backoff: Option<u64>,
+    /// The longest delay between retries, in milliseconds
+    pub(crate) max_backoff: Option<u64>,
+    pub(crate) multiplier: Option<f64>,
+    /// A fraction between 0 and 1 - each delay is randomly shortened by up to this amount
+    pub(crate) jitter: Option<f64>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: None,
+            max_backoff: None,
+            multiplier: None,
+            jitter: None,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// The delay before the next attempt, after `attempt` attempts have failed
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let initial = self.initial_backoff.unwrap_or(100) as f64;
+        let max = self.max_backoff.unwrap_or(10_000) as f64;
+        let multiplier = self.multiplier.unwrap_or(2.);
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = (initial * multiplier.powi(exponent)).min(max);
+        let delay = match self.jitter {
+            Some(jitter) if jitter > 0. => {
+                delay * (1. - rand::thread_rng().gen_range(0. ..=jitter.min(1.)))
+            }
+            _ => delay,
+        };
+        Duration::from_millis(delay as u64)
+    }
+}
+
+/// What happens to publishes while a network broker is disconnected
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PublishBufferPolicy {
+    /// Hold publishes until the connection is back, rejecting new ones once the buffer is full
+    #[default]
+    Reject,
+    /// Hold publishes until the connection is back, dropping the oldest once the buffer is full
+    DropOldest,
+    /// Fail publishes straight away
+    Fail,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReconnectConfig {
+    /// The delay before the first reconnection attempt, in milliseconds
+    pub(crate) initial_backoff: Option<u64>,
+    /// The longest delay between reconnection attempts, in milliseconds
+    pub(crate) max_backoff: Option<u64>,
+    pub(crate) multiplier: Option<f64>,
+    /// Give up after this many failed attempts in a row. Retries forever if not set
+    pub(crate) max_attempts: Option<u32>,
+    /// The number of publishes that can be held while disconnected
+    pub(crate) buffer_size: Option<usize>,
+    pub(crate) buffer_policy: Option<PublishBufferPolicy>,
+}
+
+impl ReconnectConfig {
+    /// The delay before the next reconnection attempt, after `attempt` attempts have failed
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let initial = self.initial_backoff.unwrap_or(100) as f64;
+        let max = self.max_backoff.unwrap_or(30_000) as f64;
+        let multiplier = self.multiplier.unwrap_or(2.);
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        Duration::from_millis((initial * multiplier.powi(exponent)).min(max) as u64)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeadLetterConfig {
+    pub(crate) broker: Option<String>,
+    pub(crate) subject: String,
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{ReconnectConfig, RetryConfig};
+
+    #[test]
+    fn backoff_grows_exponentially() {
+        let retry = RetryConfig {
+            max_attempts: 5,
+            initial_backoff: Some(100),
+            ..Default::default()
+        };
+
+        assert_eq!(retry.backoff(1), Duration::from_millis(100));
+        assert_eq!(retry.backoff(2), Duration::from_millis(200));
+        assert_eq!(retry.backoff(3), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let retry = RetryConfig {
+            max_attempts: 20,
+            initial_backoff: Some(100),
+            max_backoff: Some(1000),
+            multiplier: Some(3.),
+            ..Default::default()
+        };
+
+        assert_eq!(retry.backoff(3), Duration::from_millis(900));
+        assert_eq!(retry.backoff(4), Duration::from_millis(1000));
+        assert_eq!(retry.backoff(15), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_backoff() {
+        let retry = RetryConfig {
+            max_attempts: 5,
+            initial_backoff: Some(1000),
+            jitter: Some(0.5),
+            ..Default::default()
+        };
+
+        for _ in 0..20 {
+            let backoff = retry.backoff(1);
+            assert!(backoff >= Duration::from_millis(500));
+            assert!(backoff <= Duration::from_millis(1000));
+        }
+    }
+
+    #[test]
+    fn reconnect_backoff_is_capped() {
+        let reconnect = ReconnectConfig {
+            initial_backoff: Some(500),
+            max_backoff: Some(3000),
+            ..Default::default()
+        };
+
+        assert_eq!(reconnect.backoff(1), Duration::from_millis(500));
+        assert_eq!(reconnect.backoff(3), Duration::from_millis(2000));
+        assert_eq!(reconnect.backoff(4), Duration::from_millis(3000));
+        assert_eq!(reconnect.backoff(100), Duration::from_millis(3000));
+    }
+}
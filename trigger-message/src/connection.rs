@@ -0,0 +1,620 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+use spin_message_types::OutputMessage;
+use tokio::sync::{broadcast, oneshot, watch, Notify};
+use tracing::warn;
+
+use crate::configs::{PublishBufferPolicy, ReconnectConfig};
+
+const DEFAULT_BUFFER_SIZE: usize = 1000;
+
+/// A change in the state of a broker's connection to its server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected,
+    Disconnected(String),
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    /// Still connected, but operations on the broker are failing
+    Degraded(String),
+    Recovered,
+}
+
+impl Display for ConnectionEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionEvent::Connected => write!(f, "connected"),
+            ConnectionEvent::Disconnected(reason) => write!(f, "disconnected - {reason}"),
+            ConnectionEvent::Reconnecting { attempt, delay } => {
+                write!(f, "reconnecting in {delay:?} (attempt {attempt})")
+            }
+            ConnectionEvent::Degraded(reason) => write!(f, "degraded - {reason}"),
+            ConnectionEvent::Recovered => write!(f, "recovered"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub enum HealthState {
+    #[default]
+    Connected,
+    Degraded,
+    Disconnected,
+}
+
+impl Display for HealthState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthState::Connected => write!(f, "connected"),
+            HealthState::Degraded => write!(f, "degraded"),
+            HealthState::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+/// A snapshot of a broker's health, along with the last error it ran into
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct BrokerHealth {
+    pub state: HealthState,
+    pub last_error: Option<String>,
+}
+
+impl BrokerHealth {
+    /// Whether the broker can currently be used - degraded brokers are still connected
+    pub fn is_ready(&self) -> bool {
+        self.state != HealthState::Disconnected
+    }
+}
+
+#[derive(Debug, Default)]
+struct HealthStatus {
+    degraded: bool,
+    last_error: Option<String>,
+}
+
+/// Tracks whether a network broker is connected, and broadcasts any changes
+#[derive(Clone, Debug)]
+pub struct ConnectionMonitor {
+    events: broadcast::Sender<ConnectionEvent>,
+    connected: Arc<watch::Sender<bool>>,
+    status: Arc<Mutex<HealthStatus>>,
+}
+
+impl Default for ConnectionMonitor {
+    fn default() -> Self {
+        let (events, _) = broadcast::channel(16);
+        let (connected, _) = watch::channel(false);
+        Self {
+            events,
+            connected: Arc::new(connected),
+            status: Default::default(),
+        }
+    }
+}
+
+impl ConnectionMonitor {
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        *self.connected.borrow()
+    }
+
+    pub async fn wait_for_connection(&self) {
+        let mut connected = self.connected.subscribe();
+        let _ = connected.wait_for(|connected| *connected).await;
+    }
+
+    pub async fn wait_for_disconnect(&self) {
+        let mut connected = self.connected.subscribe();
+        let _ = connected.wait_for(|connected| !*connected).await;
+    }
+
+    pub fn health(&self) -> BrokerHealth {
+        let status = self.status.lock().unwrap();
+        let state = match (self.is_connected(), status.degraded) {
+            (false, _) => HealthState::Disconnected,
+            (true, true) => HealthState::Degraded,
+            (true, false) => HealthState::Connected,
+        };
+        BrokerHealth {
+            state,
+            last_error: status.last_error.clone(),
+        }
+    }
+
+    pub fn connected(&self) {
+        self.status.lock().unwrap().degraded = false;
+        if self.set_connected(true) {
+            let _ = self.events.send(ConnectionEvent::Connected);
+        }
+    }
+
+    pub fn disconnected(&self, reason: impl Display) {
+        self.status.lock().unwrap().last_error = Some(reason.to_string());
+        if self.set_connected(false) {
+            let _ = self
+                .events
+                .send(ConnectionEvent::Disconnected(reason.to_string()));
+        }
+    }
+
+    pub fn reconnecting(&self, attempt: u32, delay: Duration) {
+        let _ = self
+            .events
+            .send(ConnectionEvent::Reconnecting { attempt, delay });
+    }
+
+    /// Records a failed operation while the connection itself is still up
+    pub fn degraded(&self, reason: impl Display) {
+        let reason = reason.to_string();
+        let changed = {
+            let mut status = self.status.lock().unwrap();
+            status.last_error = Some(reason.clone());
+            !std::mem::replace(&mut status.degraded, true)
+        };
+        if changed && self.is_connected() {
+            let _ = self.events.send(ConnectionEvent::Degraded(reason));
+        }
+    }
+
+    /// Records a successful operation, clearing any degraded state
+    pub fn recovered(&self) {
+        let changed = std::mem::replace(&mut self.status.lock().unwrap().degraded, false);
+        if changed && self.is_connected() {
+            let _ = self.events.send(ConnectionEvent::Recovered);
+        }
+    }
+
+    /// Tracks the outcome of an operation on the broker
+    pub fn record<T>(&self, result: &Result<T>) {
+        match result {
+            Ok(_) => self.recovered(),
+            Err(e) => self.degraded(e),
+        }
+    }
+
+    /// Returns true if the state changed
+    fn set_connected(&self, connected: bool) -> bool {
+        self.connected.send_if_modified(|current| {
+            let changed = *current != connected;
+            *current = connected;
+            changed
+        })
+    }
+}
+
+/// Counts reconnection attempts, waiting between them based on the reconnect config
+#[derive(Clone, Debug)]
+pub struct Reconnector {
+    config: ReconnectConfig,
+    monitor: ConnectionMonitor,
+    attempt: u32,
+}
+
+impl Reconnector {
+    pub fn new(config: ReconnectConfig, monitor: ConnectionMonitor) -> Self {
+        Self {
+            config,
+            monitor,
+            attempt: 0,
+        }
+    }
+
+    pub fn connected(&mut self) {
+        self.attempt = 0;
+        self.monitor.connected();
+    }
+
+    /// Marks the connection as lost & waits before the next attempt.
+    /// Fails once the maximum number of attempts have been made.
+    pub async fn disconnected(&mut self, reason: impl Display) -> Result<()> {
+        self.monitor.disconnected(&reason);
+        self.attempt += 1;
+        if let Some(max_attempts) = self.config.max_attempts {
+            if self.attempt > max_attempts {
+                bail!("Gave up reconnecting after {max_attempts} attempts - {reason}");
+            }
+        }
+        let delay = self.config.backoff(self.attempt);
+        self.monitor.reconnecting(self.attempt, delay);
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
+}
+
+/// Resolves once the broker has accepted (or rejected) a publish
+pub type PublishResult = oneshot::Receiver<Result<()>>;
+
+/// Waits for the outcome of a publish
+pub async fn published(result: PublishResult) -> Result<()> {
+    result
+        .await
+        .map_err(|_| anyhow!("The message was dropped before it was published"))?
+}
+
+/// A message waiting to be published, along with whoever is waiting on the result
+#[derive(Debug)]
+pub struct PendingPublish {
+    pub subject: String,
+    pub message: OutputMessage,
+    completion: oneshot::Sender<Result<()>>,
+}
+
+impl PendingPublish {
+    pub fn new(subject: String, message: OutputMessage) -> (Self, PublishResult) {
+        let (completion, result) = oneshot::channel();
+        (
+            Self {
+                subject,
+                message,
+                completion,
+            },
+            result,
+        )
+    }
+
+    /// Reports the outcome back to the publisher
+    pub fn complete(self, result: Result<()>) {
+        let _ = self.completion.send(result);
+    }
+
+    /// Splits off the completion, for brokers that only confirm a publish after it is sent
+    pub fn into_parts(self) -> (String, OutputMessage, oneshot::Sender<Result<()>>) {
+        (self.subject, self.message, self.completion)
+    }
+}
+
+/// Holds publishes until a broker's connection is ready to send them
+#[derive(Debug)]
+pub struct PublishBuffer {
+    policy: PublishBufferPolicy,
+    capacity: usize,
+    queue: Mutex<VecDeque<PendingPublish>>,
+    notify: Notify,
+    monitor: ConnectionMonitor,
+    closed: AtomicBool,
+    /// Why the broker stopped publishing for good, if it gave up reconnecting
+    failure: Mutex<Option<String>>,
+    /// Publishes that haven't completed yet, whether they're still buffered or waiting on the server
+    in_flight: watch::Sender<usize>,
+}
+
+impl PublishBuffer {
+    pub fn new(config: &ReconnectConfig, monitor: ConnectionMonitor) -> Self {
+        Self {
+            policy: config.buffer_policy.unwrap_or_default(),
+            capacity: config.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            queue: Default::default(),
+            notify: Notify::new(),
+            monitor,
+            closed: AtomicBool::new(false),
+            failure: Default::default(),
+            in_flight: watch::channel(0).0,
+        }
+    }
+
+    pub fn monitor(&self) -> &ConnectionMonitor {
+        &self.monitor
+    }
+
+    /// Buffers a message, resolving once the broker has published it
+    pub async fn publish(&self, subject: String, message: OutputMessage) -> Result<()> {
+        if let Some(reason) = self.failure.lock().unwrap().as_ref() {
+            bail!("Can't publish to {subject} - {reason}");
+        }
+        if self.is_closed() {
+            bail!("Can't publish to {subject} - the broker is shutting down");
+        }
+        let _in_flight = InFlight::new(&self.in_flight);
+        let result = published(self.push(subject, message)?).await;
+        self.monitor.record(&result);
+        result
+    }
+
+    pub fn push(&self, subject: String, message: OutputMessage) -> Result<PublishResult> {
+        if self.policy == PublishBufferPolicy::Fail && !self.monitor.is_connected() {
+            bail!("Can't publish to {subject} - the broker is disconnected");
+        }
+        let (pending, result) = PendingPublish::new(subject, message);
+        {
+            let mut queue = self.queue.lock().unwrap();
+            if let Some(reason) = self.failure.lock().unwrap().as_ref() {
+                bail!("Can't publish to {} - {reason}", pending.subject);
+            }
+            if queue.len() >= self.capacity {
+                match self.policy {
+                    PublishBufferPolicy::DropOldest => {
+                        if let Some(dropped) = queue.pop_front() {
+                            warn!(
+                                subject = %dropped.subject,
+                                "Publish buffer full - dropped the oldest message"
+                            );
+                            dropped.complete(Err(anyhow!(
+                                "Dropped from the publish buffer to make room for newer messages"
+                            )));
+                        }
+                    }
+                    PublishBufferPolicy::Reject | PublishBufferPolicy::Fail => {
+                        bail!(
+                            "Can't publish to {} - the publish buffer is full",
+                            pending.subject
+                        )
+                    }
+                }
+            }
+            queue.push_back(pending);
+        }
+        self.notify.notify_one();
+        Ok(result)
+    }
+
+    /// Puts a message that failed to send back at the front of the buffer
+    pub fn retry(&self, pending: PendingPublish) {
+        self.queue.lock().unwrap().push_front(pending);
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next message to publish, once the broker is connected
+    pub async fn next(&self) -> PendingPublish {
+        loop {
+            self.monitor.wait_for_connection().await;
+            if let Some(next) = self.queue.lock().unwrap().pop_front() {
+                return next;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Stops taking new publishes, then waits for the broker to finish the buffered ones & disconnect
+    pub async fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.in_flight.send_modify(|_| {});
+        self.drained().await;
+        self.monitor.wait_for_disconnect().await;
+    }
+
+    /// Fails every buffered publish, along with any later ones, once the broker has stopped
+    /// for good - otherwise they'd wait for a connection that's never coming back
+    pub fn fail(&self, reason: impl Display) {
+        let reason = reason.to_string();
+        let pending: Vec<_> = {
+            let mut queue = self.queue.lock().unwrap();
+            *self.failure.lock().unwrap() = Some(reason.clone());
+            queue.drain(..).collect()
+        };
+        self.closed.store(true, Ordering::Release);
+        self.in_flight.send_modify(|_| {});
+        for pending in pending {
+            let subject = pending.subject.clone();
+            pending.complete(Err(anyhow!("Can't publish to {subject} - {reason}")));
+        }
+    }
+
+    /// Resolves once the buffer is closed and every publish has completed,
+    /// so the broker can close its connection
+    pub async fn drained(&self) {
+        let mut in_flight = self.in_flight.subscribe();
+        let _ = in_flight
+            .wait_for(|in_flight| *in_flight == 0 && self.is_closed())
+            .await;
+    }
+}
+
+/// Counts a publish as in flight until it's dropped
+struct InFlight<'a>(&'a watch::Sender<usize>);
+
+impl<'a> InFlight<'a> {
+    fn new(in_flight: &'a watch::Sender<usize>) -> Self {
+        in_flight.send_modify(|in_flight| *in_flight += 1);
+        Self(in_flight)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.send_modify(|in_flight| *in_flight -= 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use spin_message_types::OutputMessage;
+
+    use super::{published, ConnectionEvent, ConnectionMonitor, HealthState, PublishBuffer};
+    use crate::configs::{PublishBufferPolicy, ReconnectConfig};
+
+    fn buffer(policy: PublishBufferPolicy, size: usize) -> (PublishBuffer, ConnectionMonitor) {
+        let monitor = ConnectionMonitor::default();
+        let config = ReconnectConfig {
+            buffer_size: Some(size),
+            buffer_policy: Some(policy),
+            ..Default::default()
+        };
+        (PublishBuffer::new(&config, monitor.clone()), monitor)
+    }
+
+    #[test]
+    fn events_are_only_sent_when_the_state_changes() {
+        let monitor = ConnectionMonitor::default();
+        let mut events = monitor.subscribe();
+        monitor.connected();
+        monitor.connected();
+        monitor.disconnected("gone");
+        monitor.disconnected("still gone");
+
+        assert_eq!(events.try_recv().unwrap(), ConnectionEvent::Connected);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ConnectionEvent::Disconnected("gone".to_string())
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn health_follows_the_connection_and_failures() {
+        let monitor = ConnectionMonitor::default();
+        assert_eq!(monitor.health().state, HealthState::Disconnected);
+
+        monitor.connected();
+        assert_eq!(monitor.health().state, HealthState::Connected);
+
+        monitor.degraded("publish failed");
+        let health = monitor.health();
+        assert_eq!(health.state, HealthState::Degraded);
+        assert_eq!(health.last_error.as_deref(), Some("publish failed"));
+        assert!(health.is_ready());
+
+        monitor.recovered();
+        assert_eq!(monitor.health().state, HealthState::Connected);
+
+        monitor.disconnected("connection reset");
+        let health = monitor.health();
+        assert_eq!(health.state, HealthState::Disconnected);
+        assert_eq!(health.last_error.as_deref(), Some("connection reset"));
+        assert!(!health.is_ready());
+    }
+
+    #[tokio::test]
+    async fn buffered_messages_are_sent_once_connected() {
+        let (buffer, monitor) = buffer(PublishBufferPolicy::Reject, 2);
+        buffer
+            .push("a".to_string(), OutputMessage::default())
+            .unwrap();
+        buffer
+            .push("b".to_string(), OutputMessage::default())
+            .unwrap();
+        assert!(buffer
+            .push("c".to_string(), OutputMessage::default())
+            .is_err());
+
+        monitor.connected();
+        let a = buffer.next().await;
+        assert_eq!(a.subject, "a");
+        buffer.retry(a);
+        assert_eq!(buffer.next().await.subject, "a");
+        assert_eq!(buffer.next().await.subject, "b");
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_latest_messages() {
+        let (buffer, monitor) = buffer(PublishBufferPolicy::DropOldest, 2);
+        for subject in ["a", "b", "c"] {
+            buffer
+                .push(subject.to_string(), OutputMessage::default())
+                .unwrap();
+        }
+
+        monitor.connected();
+        assert_eq!(buffer.next().await.subject, "b");
+        assert_eq!(buffer.next().await.subject, "c");
+    }
+
+    #[tokio::test]
+    async fn dropped_messages_fail_their_publish() {
+        let (buffer, _) = buffer(PublishBufferPolicy::DropOldest, 1);
+        let first = buffer
+            .push("a".to_string(), OutputMessage::default())
+            .unwrap();
+        buffer
+            .push("b".to_string(), OutputMessage::default())
+            .unwrap();
+
+        assert!(published(first).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn publish_resolves_with_the_broker_result() {
+        let (buffer, monitor) = buffer(PublishBufferPolicy::Reject, 10);
+        monitor.connected();
+        let publisher = async {
+            let sent = buffer.publish("a".to_string(), OutputMessage::default());
+            let failed = buffer.publish("b".to_string(), OutputMessage::default());
+            tokio::join!(sent, failed)
+        };
+        let broker = async {
+            buffer.next().await.complete(Ok(()));
+            buffer
+                .next()
+                .await
+                .complete(Err(anyhow::anyhow!("rejected")));
+        };
+        let ((sent, failed), _) = tokio::join!(publisher, broker);
+
+        assert!(sent.is_ok());
+        assert!(failed.is_err());
+    }
+
+    #[test]
+    fn fail_rejects_publishes_while_disconnected() {
+        let (buffer, monitor) = buffer(PublishBufferPolicy::Fail, 10);
+        assert!(buffer
+            .push("a".to_string(), OutputMessage::default())
+            .is_err());
+        monitor.connected();
+        assert!(buffer
+            .push("a".to_string(), OutputMessage::default())
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn closing_waits_for_buffered_publishes() {
+        let (buffer, monitor) = buffer(PublishBufferPolicy::Reject, 10);
+        monitor.connected();
+        let publisher = buffer.publish("a".to_string(), OutputMessage::default());
+        let closer = async {
+            tokio::task::yield_now().await;
+            buffer.close().await;
+        };
+        let broker = async {
+            buffer.next().await.complete(Ok(()));
+            buffer.drained().await;
+            monitor.disconnected("Shut down");
+        };
+        let (sent, _, _) = tokio::join!(publisher, closer, broker);
+
+        assert!(sent.is_ok());
+        assert!(buffer
+            .publish("b".to_string(), OutputMessage::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn giving_up_fails_buffered_and_later_publishes() {
+        let (buffer, _) = buffer(PublishBufferPolicy::Reject, 10);
+        let buffered = buffer
+            .push("a".to_string(), OutputMessage::default())
+            .unwrap();
+
+        buffer.fail("Gave up reconnecting after 3 attempts");
+
+        let error = published(buffered).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Can't publish to a - Gave up reconnecting after 3 attempts"
+        );
+        assert!(buffer
+            .publish("b".to_string(), OutputMessage::default())
+            .await
+            .is_err());
+        buffer.drained().await;
+    }
+}
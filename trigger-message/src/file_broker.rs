@@ -0,0 +1,1045 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use spin_message_types::{InputMessage, OutputMessage};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+use wildmatch::WildMatch;
+
+use crate::{
+    broker::{
+        create_channel, default_message_response_subject, Acker, Acknowledgement, Delivery,
+        MessageBroker, QueueReceiver, Receiver, Wildcards,
+    },
+    configs::{StreamAckPolicy, StreamDeliverPolicy, StreamSubscription},
+    in_memory_broker::{InMemoryBroker, QueueGroup},
+};
+
+/// Every delivered message has its offset in the log in this header
+pub const OFFSET_HEADER: &str = "log-offset";
+
+const LOG_FILE: &str = "messages.log";
+const CONSUMERS_DIR: &str = "consumers";
+const DEFAULT_MAX_IN_FLIGHT: usize = 50;
+const CONSUMER_CHANNEL_CAPACITY: usize = 100;
+/// How often changed consumer state is written to disk, and the retention limits are applied
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FileBrokerConfig {
+    /// The directory the message log & consumer state are stored in
+    path: String,
+    /// Flush each message to disk before it's delivered. Defaults to false
+    sync: Option<bool>,
+    /// The most messages to keep in the log. Defaults to keeping everything
+    max_messages: Option<u64>,
+    /// How long to keep messages in the log, in seconds. Defaults to keeping everything
+    max_age: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct LogEntry {
+    offset: u64,
+    /// Milliseconds since the unix epoch
+    timestamp: u64,
+    message: OutputMessage,
+}
+
+impl LogEntry {
+    fn subject(&self) -> &str {
+        self.message.subject.as_deref().unwrap_or_default()
+    }
+}
+
+/// The log offset a message was delivered with
+fn offset(message: &InputMessage) -> Option<u64> {
+    let (_, offset) = message
+        .headers
+        .iter()
+        .rev()
+        .find(|(name, _)| name == OFFSET_HEADER)?;
+    std::str::from_utf8(offset).ok()?.parse().ok()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// An append only file of length prefixed messagepack entries. Offsets start at 1, and
+/// the position of each entry is kept in memory so they can be read back individually.
+/// Compacting the log drops the oldest entries, so it starts from the first offset left.
+struct MessageLog {
+    path: PathBuf,
+    file: File,
+    positions: Vec<u64>,
+    first: u64,
+    end: u64,
+    sync: bool,
+}
+
+impl MessageLog {
+    fn open(path: &Path, sync: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let len = file.metadata()?.len();
+
+        let mut reader = BufReader::new(&file);
+        let mut positions = vec![];
+        let mut first = 1;
+        let mut position = 0;
+        let mut prefix = [0; 4];
+        while position + 4 <= len {
+            reader.read_exact(&mut prefix)?;
+            let entry_len = u32::from_le_bytes(prefix) as u64;
+            if position + 4 + entry_len > len {
+                break;
+            }
+            if positions.is_empty() {
+                let mut body = vec![0; entry_len as usize];
+                reader.read_exact(&mut body)?;
+                first = rmp_serde::from_slice::<LogEntry>(&body)?.offset;
+            } else {
+                reader.seek_relative(entry_len as i64)?;
+            }
+            positions.push(position);
+            position += 4 + entry_len;
+        }
+        if position < len {
+            // The last entry was only partially written, most likely due to a crash
+            file.set_len(position)?;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            positions,
+            first,
+            end: position,
+            sync,
+        })
+    }
+
+    fn next_offset(&self) -> u64 {
+        self.first + self.positions.len() as u64
+    }
+
+    fn append(&mut self, message: OutputMessage) -> Result<LogEntry> {
+        let entry = LogEntry {
+            offset: self.next_offset(),
+            timestamp: now_millis(),
+            message,
+        };
+        let body = rmp_serde::to_vec(&entry)?;
+        let mut record = (body.len() as u32).to_le_bytes().to_vec();
+        record.extend(body);
+        self.file.write_all(&record)?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        self.positions.push(self.end);
+        self.end += record.len() as u64;
+        Ok(entry)
+    }
+
+    fn read(&mut self, offset: u64) -> Result<LogEntry> {
+        let Some(position) = offset
+            .checked_sub(self.first)
+            .and_then(|index| self.positions.get(index as usize))
+            .copied()
+        else {
+            bail!("No message at offset {offset}");
+        };
+        self.file.seek(SeekFrom::Start(position))?;
+        let mut len = [0; 4];
+        self.file.read_exact(&mut len)?;
+        let mut body = vec![0; u32::from_le_bytes(len) as usize];
+        self.file.read_exact(&mut body)?;
+        Ok(rmp_serde::from_slice(&body)?)
+    }
+
+    fn matching_offsets(&mut self, from: u64, matcher: &WildMatch) -> Result<Vec<u64>> {
+        let mut offsets = vec![];
+        for offset in from.max(self.first)..self.next_offset() {
+            if matcher.matches(self.read(offset)?.subject()) {
+                offsets.push(offset);
+            }
+        }
+        Ok(offsets)
+    }
+
+    fn start_offset(&mut self, policy: &StreamDeliverPolicy, matcher: &WildMatch) -> Result<u64> {
+        let offset = match policy {
+            StreamDeliverPolicy::All => self.first,
+            StreamDeliverPolicy::New => self.next_offset(),
+            StreamDeliverPolicy::BySequence(offset) => (*offset).max(self.first),
+            StreamDeliverPolicy::Last => self
+                .matching_offsets(self.first, matcher)?
+                .last()
+                .copied()
+                .unwrap_or(self.next_offset()),
+            StreamDeliverPolicy::ByTime(time) => {
+                let time = time::OffsetDateTime::parse(
+                    time,
+                    &time::format_description::well_known::Rfc3339,
+                )?;
+                let time = (time.unix_timestamp_nanos() / 1_000_000) as u64;
+                self.first_since(time)?
+            }
+        };
+        Ok(offset)
+    }
+
+    /// The first offset with a message published at or after the time
+    fn first_since(&mut self, time: u64) -> Result<u64> {
+        for offset in self.first..self.next_offset() {
+            if self.read(offset)?.timestamp >= time {
+                return Ok(offset);
+            }
+        }
+        Ok(self.next_offset())
+    }
+
+    /// The first offset that's within the retention limits. The latest message is always
+    /// kept, so the offsets carry on from it after a restart.
+    fn retained_from(
+        &mut self,
+        max_messages: Option<u64>,
+        max_age: Option<Duration>,
+    ) -> Result<u64> {
+        let mut from = self.first;
+        if let Some(max_messages) = max_messages {
+            from = from.max(self.next_offset().saturating_sub(max_messages));
+        }
+        if let Some(max_age) = max_age {
+            let cutoff = now_millis().saturating_sub(max_age.as_millis() as u64);
+            from = from.max(self.first_since(cutoff)?);
+        }
+        Ok(from
+            .min(self.next_offset().saturating_sub(1))
+            .max(self.first))
+    }
+
+    /// Rewrites the log without the entries before the offset
+    fn compact(&mut self, from: u64) -> Result<()> {
+        let Some(&start) = from
+            .checked_sub(self.first)
+            .and_then(|index| self.positions.get(index as usize))
+        else {
+            return Ok(());
+        };
+        let temp = self.path.with_extension("tmp");
+        let mut compacted = File::create(&temp)?;
+        self.file.seek(SeekFrom::Start(start))?;
+        std::io::copy(&mut (&self.file).take(self.end - start), &mut compacted)?;
+        compacted.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.positions = self.positions[(from - self.first) as usize..]
+            .iter()
+            .map(|position| position - start)
+            .collect();
+        self.first = from;
+        self.end -= start;
+        Ok(())
+    }
+}
+
+/// Everything before `next` has been handled, apart from the pending offsets.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ConsumerState {
+    next: u64,
+    pending: BTreeSet<u64>,
+}
+
+struct DurableConsumer {
+    group: QueueGroup,
+    state: ConsumerState,
+    state_path: PathBuf,
+    /// The state changed since it was last saved
+    dirty: bool,
+    backlog: VecDeque<u64>,
+    in_flight: usize,
+    max_in_flight: usize,
+    redeliver: bool,
+}
+
+impl DurableConsumer {
+    fn save(&mut self) -> Result<()> {
+        let temp = self.state_path.with_extension("tmp");
+        fs::write(&temp, rmp_serde::to_vec(&self.state)?)?;
+        fs::rename(temp, &self.state_path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+struct ConsumerOptions<'a> {
+    key: String,
+    topic: &'a str,
+    start: &'a StreamDeliverPolicy,
+    max_in_flight: usize,
+    redeliver: bool,
+}
+
+/// The log & the consumers. Everything in here does blocking file IO, so it's only used
+/// from the blocking thread pool, through `with_store`.
+struct FileStore {
+    name: String,
+    consumers_dir: PathBuf,
+    log: MessageLog,
+    consumers: HashMap<String, DurableConsumer>,
+    max_messages: Option<u64>,
+    max_age: Option<Duration>,
+}
+
+type SharedStore = Arc<Mutex<FileStore>>;
+
+fn lock(store: &SharedStore) -> Result<std::sync::MutexGuard<'_, FileStore>> {
+    store
+        .lock()
+        .map_err(|_| anyhow::Error::msg("File broker store is poisoned"))
+}
+
+async fn with_store<T, F>(store: &SharedStore, operation: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut FileStore, &SharedStore) -> Result<T> + Send + 'static,
+{
+    let store = store.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut guard = lock(&store)?;
+        operation(&mut guard, &store)
+    })
+    .await?
+}
+
+impl FileStore {
+    fn open(name: String, config: &FileBrokerConfig) -> Result<Self> {
+        let dir = PathBuf::from(&config.path);
+        let consumers_dir = dir.join(CONSUMERS_DIR);
+        fs::create_dir_all(&consumers_dir)?;
+        let log = MessageLog::open(&dir.join(LOG_FILE), config.sync.unwrap_or_default())?;
+        Ok(Self {
+            name,
+            consumers_dir,
+            log,
+            consumers: HashMap::new(),
+            max_messages: config.max_messages,
+            max_age: config.max_age.map(Duration::from_secs),
+        })
+    }
+
+    fn to_input_message(&self, entry: LogEntry) -> InputMessage {
+        let subject = entry.subject().to_string();
+        let mut headers = entry.message.headers;
+        headers.push((
+            OFFSET_HEADER.to_string(),
+            entry.offset.to_string().into_bytes(),
+        ));
+        InputMessage {
+            message: entry.message.message,
+            response_subject: entry
+                .message
+                .response_subject
+                .or_else(|| default_message_response_subject(&subject)),
+            subject,
+            broker: self.name.clone(),
+            headers,
+        }
+    }
+
+    fn publish(&mut self, message: OutputMessage, store: &SharedStore) -> Result<LogEntry> {
+        let entry = self.log.append(message)?;
+        let keys: Vec<String> = self
+            .consumers
+            .iter()
+            .filter(|(_, consumer)| consumer.group.matches(entry.subject()))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            // The saved state doesn't need to include this right away, since anything
+            // after it is picked up from the log on startup
+            if let Some(consumer) = self.consumers.get_mut(&key) {
+                consumer.state.pending.insert(entry.offset);
+                consumer.state.next = entry.offset + 1;
+                consumer.dirty = true;
+                consumer.backlog.push_back(entry.offset);
+            }
+            self.pump(&key, store)?;
+        }
+        Ok(entry)
+    }
+
+    fn save_consumers(&mut self) -> Result<()> {
+        for consumer in self.consumers.values_mut().filter(|c| c.dirty) {
+            consumer.save()?;
+        }
+        Ok(())
+    }
+
+    /// Saves the state of every consumer that changed, then compacts the log if at least
+    /// half of it is past the retention limits
+    fn flush(&mut self) -> Result<()> {
+        self.save_consumers()?;
+        if self.max_messages.is_none() && self.max_age.is_none() {
+            return Ok(());
+        }
+        let from = self.log.retained_from(self.max_messages, self.max_age)?;
+        let removed = from - self.log.first;
+        if removed > 0 && removed >= self.log.next_offset() - from {
+            info!(broker = %self.name, removed, "Compacting message log");
+            self.log.compact(from)?;
+        }
+        Ok(())
+    }
+
+    fn subscribe(
+        &mut self,
+        options: ConsumerOptions,
+        store: &SharedStore,
+    ) -> Result<QueueReceiver> {
+        let sender = create_channel(CONSUMER_CHANNEL_CAPACITY);
+        let receiver = sender.subscribe();
+        if let Some(consumer) = self.consumers.get_mut(&options.key) {
+            consumer.group.add_member(sender);
+            self.pump(&options.key, store)?;
+            return Ok(receiver);
+        }
+
+        let matcher = WildMatch::new(options.topic);
+        let file_name: String = options.key.bytes().map(|b| format!("{b:02x}")).collect();
+        let state_path = self.consumers_dir.join(file_name);
+        let mut state = if state_path.exists() {
+            rmp_serde::from_slice(&fs::read(&state_path)?)?
+        } else {
+            ConsumerState {
+                next: self.log.start_offset(options.start, &matcher)?,
+                pending: BTreeSet::new(),
+            }
+        };
+        // Anything published since the state was last saved is picked up from the log,
+        // and anything that was compacted away in the meantime is skipped
+        state
+            .pending
+            .extend(self.log.matching_offsets(state.next, &matcher)?);
+        state.pending.retain(|offset| *offset >= self.log.first);
+        state.next = state.next.max(self.log.next_offset());
+
+        let mut consumer = DurableConsumer {
+            group: QueueGroup::new(options.topic, sender),
+            backlog: state.pending.iter().copied().collect(),
+            state,
+            state_path,
+            dirty: false,
+            in_flight: 0,
+            max_in_flight: options.max_in_flight,
+            redeliver: options.redeliver,
+        };
+        consumer.save()?;
+        self.consumers.insert(options.key.clone(), consumer);
+        self.pump(&options.key, store)?;
+        Ok(receiver)
+    }
+
+    /// Delivers backlogged messages until the consumer has as many in flight as it's allowed
+    fn pump(&mut self, key: &str, store: &SharedStore) -> Result<()> {
+        loop {
+            let first = self.log.first;
+            let Some(consumer) = self.consumers.get_mut(key) else {
+                return Ok(());
+            };
+            if consumer.in_flight >= consumer.max_in_flight {
+                return Ok(());
+            }
+            let Some(offset) = consumer.backlog.pop_front() else {
+                return Ok(());
+            };
+            if offset < first {
+                // Compacted away before it was delivered
+                consumer.state.pending.remove(&offset);
+                consumer.dirty = true;
+                continue;
+            }
+            if self.deliver(key, offset, store)? {
+                if let Some(consumer) = self.consumers.get_mut(key) {
+                    consumer.in_flight += 1;
+                }
+            } else {
+                // Nobody is listening right now, so try again once a member subscribes
+                if let Some(consumer) = self.consumers.get_mut(key) {
+                    consumer.backlog.push_front(offset);
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    fn deliver(&mut self, key: &str, offset: u64, store: &SharedStore) -> Result<bool> {
+        let entry = self.log.read(offset)?;
+        let input = self.to_input_message(entry);
+        let Some(consumer) = self.consumers.get(key) else {
+            return Ok(false);
+        };
+        let Some(sender) = consumer.group.next_member() else {
+            return Ok(false);
+        };
+        let acker = Arc::new(FileAcker {
+            store: Arc::downgrade(store),
+            key: key.to_string(),
+            offset,
+        });
+        Ok(sender.send(Delivery::with_acker(input, acker)).is_ok())
+    }
+
+    fn complete(&mut self, key: &str, offset: u64, store: &SharedStore) -> Result<()> {
+        if let Some(consumer) = self.consumers.get_mut(key) {
+            consumer.state.pending.remove(&offset);
+            consumer.in_flight = consumer.in_flight.saturating_sub(1);
+            consumer.dirty = true;
+        }
+        self.pump(key, store)
+    }
+
+    fn redeliver(&mut self, key: &str, offset: u64, store: &SharedStore) -> Result<()> {
+        let redeliver = self
+            .consumers
+            .get(key)
+            .map(|consumer| consumer.redeliver)
+            .unwrap_or_default();
+        if !redeliver || offset < self.log.first {
+            return self.complete(key, offset, store);
+        }
+        if !self.deliver(key, offset, store)? {
+            if let Some(consumer) = self.consumers.get_mut(key) {
+                consumer.in_flight = consumer.in_flight.saturating_sub(1);
+                consumer.backlog.push_front(offset);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.save_consumers() {
+            error!(broker = %self.name, "Failed to save the file broker state - {e:?}");
+        }
+    }
+}
+
+/// Holds on to the store weakly, so deliveries that are never handled don't keep it open
+struct FileAcker {
+    store: Weak<Mutex<FileStore>>,
+    key: String,
+    offset: u64,
+}
+
+impl Debug for FileAcker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileAcker")
+            .field("key", &self.key)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Acker for FileAcker {
+    async fn acknowledge(&self, ack: Acknowledgement) -> Result<()> {
+        let store = self
+            .store
+            .upgrade()
+            .context("The file broker has been shut down")?;
+        let key = self.key.clone();
+        let offset = self.offset;
+        match ack {
+            Acknowledgement::Ack | Acknowledgement::Nack { requeue: false } => {
+                with_store(&store, move |s, store| s.complete(&key, offset, store)).await
+            }
+            Acknowledgement::Nack { requeue: true } => {
+                with_store(&store, move |s, store| s.redeliver(&key, offset, store)).await
+            }
+            Acknowledgement::RetryAfter(delay) => {
+                let store = Arc::downgrade(&store);
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let Some(store) = store.upgrade() else {
+                        return;
+                    };
+                    let result =
+                        with_store(&store, move |s, store| s.redeliver(&key, offset, store)).await;
+                    if let Err(e) = result {
+                        error!(offset, "Failed to redeliver message - {e:?}");
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A broker that persists every message to an append only log on disk. Topic subscriptions
+/// behave like the in memory broker, while queues & streams keep track of which messages
+/// were handled, so anything that wasn't is delivered again after a restart.
+#[derive(Clone)]
+pub struct FileBroker {
+    name: String,
+    local_broker: InMemoryBroker,
+    store: SharedStore,
+}
+
+impl FileBroker {
+    pub fn new(config: FileBrokerConfig, name: String) -> Result<Self> {
+        let store = Arc::new(Mutex::new(FileStore::open(name.clone(), &config)?));
+        tokio::spawn(FileBroker::keep_flushing(
+            name.clone(),
+            Arc::downgrade(&store),
+        ));
+        Ok(Self {
+            local_broker: InMemoryBroker::new(name.clone()),
+            name,
+            store,
+        })
+    }
+
+    /// Periodically saves the consumer state & applies the retention limits, until the broker is dropped
+    async fn keep_flushing(name: String, store: Weak<Mutex<FileStore>>) {
+        let start = tokio::time::Instant::now() + FLUSH_INTERVAL;
+        let mut interval = tokio::time::interval_at(start, FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(store) = store.upgrade() else {
+                return;
+            };
+            if let Err(e) = with_store(&store, |s, _| s.flush()).await {
+                error!(broker = %name, "Failed to save the file broker state - {e:?}");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MessageBroker for FileBroker {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Topic subscriptions go through the in memory broker, so `*` also matches across tokens
+    fn wildcards(&self) -> Wildcards {
+        Wildcards::Glob
+    }
+
+    async fn publish(&self, message: OutputMessage) -> Result<()> {
+        if message.subject.is_none() {
+            bail!("No Subject To Publish");
+        }
+        let entry = with_store(&self.store, |s, store| s.publish(message, store)).await?;
+        let mut message = entry.message;
+        message.headers.push((
+            OFFSET_HEADER.to_string(),
+            entry.offset.to_string().into_bytes(),
+        ));
+        self.local_broker.publish(message).await
+    }
+
+    async fn subscribe_to_topic(&self, subject: &str) -> Result<Receiver> {
+        self.local_broker.subscribe_to_topic(subject).await
+    }
+
+    async fn subscribe_to_topic_from(&self, subject: &str, after: u64) -> Result<Option<Receiver>> {
+        // Subscribing before reading the log means nothing published in between is missed.
+        // Those messages can turn up on both, so live ones the log already covered are skipped.
+        let mut live = self.local_broker.subscribe_to_topic(subject).await?;
+        let matcher = WildMatch::new(subject);
+        let (replayed, end) = with_store(&self.store, move |s, _| {
+            let mut replayed = vec![];
+            for offset in s.log.matching_offsets(after + 1, &matcher)? {
+                let entry = s.log.read(offset)?;
+                replayed.push(s.to_input_message(entry));
+            }
+            Ok((replayed, s.log.next_offset()))
+        })
+        .await?;
+
+        let sender = create_channel(replayed.len() + CONSUMER_CHANNEL_CAPACITY);
+        let receiver = sender.subscribe();
+        for input in replayed {
+            sender.send(input.into())?;
+        }
+        let name = self.name.clone();
+        tokio::spawn(async move {
+            loop {
+                match live.recv().await {
+                    Ok(delivery) if offset(&delivery.input).is_some_and(|offset| offset < end) => {}
+                    Ok(delivery) => {
+                        if sender.send(delivery).is_err() {
+                            // The subscriber went away
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(broker = name, skipped, "Replayed subscription fell behind");
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        Ok(Some(receiver))
+    }
+
+    fn sequence(&self, message: &InputMessage) -> Option<u64> {
+        offset(message)
+    }
+
+    async fn subscribe_to_queue(&self, topic: &str, group: &str) -> Result<QueueReceiver> {
+        let key = format!("queue::{topic}::{group}");
+        let topic = topic.to_string();
+        with_store(&self.store, move |s, store| {
+            let options = ConsumerOptions {
+                key,
+                topic: &topic,
+                start: &StreamDeliverPolicy::New,
+                max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+                redeliver: true,
+            };
+            s.subscribe(options, store)
+        })
+        .await
+    }
+
+    async fn subscribe_to_stream(
+        &self,
+        subscription: &StreamSubscription,
+    ) -> Result<QueueReceiver> {
+        let max_in_flight = subscription
+            .max_ack_pending
+            .map(|max| max.clamp(1, CONSUMER_CHANNEL_CAPACITY as i64) as usize)
+            .unwrap_or(DEFAULT_MAX_IN_FLIGHT);
+        let key = format!("stream::{}::{}", subscription.stream, subscription.consumer);
+        let topic = subscription.topic.clone().unwrap_or("*".to_string());
+        let start = subscription
+            .deliver_policy
+            .clone()
+            .unwrap_or(StreamDeliverPolicy::All);
+        let redeliver = !matches!(subscription.ack_policy, Some(StreamAckPolicy::None));
+        with_store(&self.store, move |s, store| {
+            let options = ConsumerOptions {
+                key,
+                topic: &topic,
+                start: &start,
+                max_in_flight,
+                redeliver,
+            };
+            s.subscribe(options, store)
+        })
+        .await
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        with_store(&self.store, |s, _| s.flush()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, time::Duration};
+
+    use spin_message_types::OutputMessage;
+
+    use crate::{
+        broker::{Acknowledgement, MessageBroker},
+        configs::{StreamDeliverPolicy, StreamSubscription},
+    };
+
+    use super::{FileBroker, FileBrokerConfig, OFFSET_HEADER};
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("file-broker-{}", ulid::Ulid::new())))
+        }
+
+        fn config(&self) -> FileBrokerConfig {
+            FileBrokerConfig {
+                path: self.0.to_string_lossy().to_string(),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn message(subject: &str, body: &str) -> OutputMessage {
+        OutputMessage {
+            subject: Some(subject.to_string()),
+            message: body.as_bytes().to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn a_published_message_gets_recieved_by_a_subscriber() {
+        let dir = TempDir::new();
+        let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+
+        let mut rx = broker.subscribe_to_topic("message.*").await.unwrap();
+        broker
+            .publish(message("message.test", "test"))
+            .await
+            .unwrap();
+        let result = rx.try_recv().unwrap();
+
+        assert_eq!(result.subject, "message.test");
+        assert_eq!(result.message, "test".as_bytes());
+        assert!(result
+            .headers
+            .contains(&(OFFSET_HEADER.to_string(), "1".as_bytes().to_owned())));
+    }
+
+    #[tokio::test]
+    async fn queue_messages_are_split_between_members() {
+        let dir = TempDir::new();
+        let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+
+        let mut rx_1 = broker
+            .subscribe_to_queue("message.*", "group")
+            .await
+            .unwrap();
+        let mut rx_2 = broker
+            .subscribe_to_queue("message.*", "group")
+            .await
+            .unwrap();
+        broker.publish(message("message.test", "1")).await.unwrap();
+        broker.publish(message("message.test", "2")).await.unwrap();
+
+        assert_eq!(rx_1.try_recv().unwrap().message, "1".as_bytes());
+        assert_eq!(rx_2.try_recv().unwrap().message, "2".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_queue_messages_are_redelivered_after_a_restart() {
+        let dir = TempDir::new();
+        {
+            let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+            let mut rx = broker
+                .subscribe_to_queue("message.*", "group")
+                .await
+                .unwrap();
+            broker.publish(message("message.test", "1")).await.unwrap();
+            broker.publish(message("message.test", "2")).await.unwrap();
+            let first = rx.try_recv().unwrap();
+            first.acknowledge(Acknowledgement::Ack).await.unwrap();
+        }
+
+        let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+        let mut rx = broker
+            .subscribe_to_queue("message.*", "group")
+            .await
+            .unwrap();
+        let result = rx.try_recv().unwrap();
+
+        assert_eq!(result.message, "2".as_bytes());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn messages_published_while_a_queue_is_down_are_delivered() {
+        let dir = TempDir::new();
+        {
+            let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+            broker
+                .subscribe_to_queue("message.*", "group")
+                .await
+                .unwrap();
+        }
+        {
+            let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+            broker.publish(message("message.test", "1")).await.unwrap();
+        }
+
+        let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+        let mut rx = broker
+            .subscribe_to_queue("message.*", "group")
+            .await
+            .unwrap();
+
+        assert_eq!(rx.try_recv().unwrap().message, "1".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn a_nacked_queue_message_gets_redelivered() {
+        let dir = TempDir::new();
+        let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+
+        let mut rx = broker
+            .subscribe_to_queue("message.*", "group")
+            .await
+            .unwrap();
+        broker.publish(message("message.test", "1")).await.unwrap();
+        let first = rx.try_recv().unwrap();
+        first
+            .acknowledge(Acknowledgement::Nack { requeue: true })
+            .await
+            .unwrap();
+
+        assert_eq!(rx.try_recv().unwrap().message, "1".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn a_message_retried_after_a_delay_gets_redelivered() {
+        let dir = TempDir::new();
+        let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+
+        let mut rx = broker
+            .subscribe_to_queue("message.*", "group")
+            .await
+            .unwrap();
+        broker.publish(message("message.test", "1")).await.unwrap();
+        let first = rx.try_recv().unwrap();
+        first
+            .acknowledge(Acknowledgement::RetryAfter(Duration::from_millis(10)))
+            .await
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+
+        let result = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.message, "1".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn streams_replay_from_an_offset() {
+        let dir = TempDir::new();
+        let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+        for body in ["1", "2", "3"] {
+            broker.publish(message("message.test", body)).await.unwrap();
+        }
+
+        let subscription = StreamSubscription {
+            stream: "log".to_string(),
+            consumer: "consumer".to_string(),
+            deliver_policy: Some(StreamDeliverPolicy::BySequence(2)),
+            ..Default::default()
+        };
+        let mut rx = broker.subscribe_to_stream(&subscription).await.unwrap();
+
+        assert_eq!(rx.try_recv().unwrap().message, "2".as_bytes());
+        assert_eq!(rx.try_recv().unwrap().message, "3".as_bytes());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn topics_resume_after_an_offset() {
+        let dir = TempDir::new();
+        let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+        for body in ["1", "2", "3"] {
+            broker.publish(message("message.test", body)).await.unwrap();
+        }
+        broker.publish(message("other.test", "4")).await.unwrap();
+
+        let mut rx = broker
+            .subscribe_to_topic_from("message.*", 1)
+            .await
+            .unwrap()
+            .unwrap();
+        let replayed = rx.try_recv().unwrap();
+        assert_eq!(replayed.message, "2".as_bytes());
+        assert_eq!(broker.sequence(&replayed), Some(2));
+        assert_eq!(rx.try_recv().unwrap().message, "3".as_bytes());
+
+        broker.publish(message("message.test", "5")).await.unwrap();
+        let live = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(live.message, "5".as_bytes());
+        assert_eq!(broker.sequence(&live), Some(5));
+    }
+
+    #[tokio::test]
+    async fn old_messages_are_compacted_away() {
+        let dir = TempDir::new();
+        let config = FileBrokerConfig {
+            max_messages: Some(2),
+            ..dir.config()
+        };
+        {
+            let broker = FileBroker::new(config.clone(), "test".to_string()).unwrap();
+            for body in ["1", "2", "3", "4", "5"] {
+                broker.publish(message("message.test", body)).await.unwrap();
+            }
+            broker.shutdown().await.unwrap();
+        }
+
+        let broker = FileBroker::new(config, "test".to_string()).unwrap();
+        broker.publish(message("message.test", "6")).await.unwrap();
+        let subscription = StreamSubscription {
+            stream: "log".to_string(),
+            consumer: "consumer".to_string(),
+            ..Default::default()
+        };
+        let mut rx = broker.subscribe_to_stream(&subscription).await.unwrap();
+
+        let first = rx.try_recv().unwrap();
+        assert_eq!(first.message, "4".as_bytes());
+        assert_eq!(broker.sequence(&first), Some(4));
+        assert_eq!(rx.try_recv().unwrap().message, "5".as_bytes());
+        assert_eq!(rx.try_recv().unwrap().message, "6".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn a_partially_written_entry_is_discarded() {
+        let dir = TempDir::new();
+        {
+            let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+            broker.publish(message("message.test", "1")).await.unwrap();
+        }
+        {
+            use std::io::Write;
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(dir.0.join(super::LOG_FILE))
+                .unwrap();
+            file.write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+        }
+
+        let broker = FileBroker::new(dir.config(), "test".to_string()).unwrap();
+        broker.publish(message("message.test", "2")).await.unwrap();
+
+        let subscription = StreamSubscription {
+            stream: "log".to_string(),
+            consumer: "consumer".to_string(),
+            ..Default::default()
+        };
+        let mut rx = broker.subscribe_to_stream(&subscription).await.unwrap();
+        assert_eq!(rx.try_recv().unwrap().message, "1".as_bytes());
+        assert_eq!(rx.try_recv().unwrap().message, "2".as_bytes());
+    }
+}
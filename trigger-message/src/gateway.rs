@@ -2,22 +2,39 @@ use axum::{
     body::{BoxBody, Bytes},
     extract::{
         ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
-        Path, State,
+        ConnectInfo, DefaultBodyLimit, Extension, MatchedPath, Path, State,
     },
-    http::{HeaderMap, Method, Response, StatusCode, Uri},
-    response::IntoResponse,
-    routing::{any, get, post},
-    Router,
+    http::{header::RETRY_AFTER, HeaderMap, Method, Request, Response, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::{any, get, post, MethodRouter},
+    Json, Router,
 };
-use futures::{SinkExt, StreamExt};
+use axum_server::tls_rustls::RustlsConfig;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{SinkExt, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
-use spin_message_types::{HttpRequest, OutputMessage};
+use spin_message_types::{HttpRequest, InputMessage, OutputMessage};
 
 use crate::{
-    broker::MessageBroker,
+    auth::{Authenticator, Identity},
+    broker::{http_headers_to_message_headers, Delivery, MessageBroker, Receiver},
     configs::{self, GatewayRequestResponseConfig},
+    limits::{GatewayLimits, RateLimiter},
+    metrics::{metrics, metrics_handler},
+    telemetry,
 };
 
 #[derive(Clone)]
@@ -26,61 +43,267 @@ struct GatewayState {
     websockets: Option<configs::WebsocketConfig>,
     request_response: Option<configs::GatewayRequestResponseConfig>,
     timeout: Option<u64>,
+    auth: Option<Authenticator>,
+    rate_limiter: Option<RateLimiter>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn spawn_gateway(
     port: u16,
     websockets: Option<configs::WebsocketConfig>,
     broker: Arc<dyn MessageBroker>,
     request_response: Option<GatewayRequestResponseConfig>,
     timeout: Option<u64>,
+    auth: Option<Authenticator>,
+    tls: Option<RustlsConfig>,
+    limits: GatewayLimits,
 ) {
+    if let Some(rate_limiter) = &limits.rate_limiter {
+        tokio::spawn(rate_limiter.clone().clean_up());
+    }
+    let state = Arc::new(GatewayState {
+        broker,
+        websockets,
+        request_response,
+        timeout,
+        auth,
+        rate_limiter: limits.rate_limiter,
+    });
+    let limit_body = |route: MethodRouter<Arc<GatewayState>>| match limits.max_body_size {
+        Some(max) => route.layer(DefaultBodyLimit::max(max)),
+        None => route,
+    };
+    // The health & metrics routes stay open, so probes & scrapers don't need credentials.
+    // IPs are rate limited before authenticating, and identities after.
     let app = Router::new()
-        .route("/publish/*subject", post(publish))
+        .route("/publish/*subject", limit_body(post(publish)))
         .route("/subscribe/*subject", get(subscribe))
-        .route("/request/*path", any(request_handler))
+        .route("/events/*subject", get(events))
+        .route("/request/*path", limit_body(any(request_handler)))
         .route("/ws", any(ws_handler))
-        .with_state(Arc::new(GatewayState {
-            broker,
-            websockets,
-            request_response,
-            timeout,
-        }));
+        .route_layer(middleware::from_fn_with_state(state.clone(), limit_keys))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .route_layer(middleware::from_fn_with_state(state.clone(), limit_ips))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), track_metrics))
+        .with_state(state.clone());
+    // Preflight requests don't carry credentials, so CORS wraps everything else
+    let app = match limits.cors {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    println!("Listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    let broker = state.broker.name();
+    match tls {
+        Some(tls) => {
+            info!(%addr, broker, "Gateway listening with TLS");
+            axum_server::bind_rustls(addr, tls)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        }
+        None => {
+            info!(%addr, broker, "Gateway listening");
+            axum::Server::bind(&addr)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        }
+    }
+}
+
+/// Records the number of requests to each route, and how long they took
+async fn track_metrics<B>(
+    State(state): State<Arc<GatewayState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response<BoxBody> {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let broker = state.broker.name();
+    metrics()
+        .gateway_requests
+        .with_label_values(&[broker, &route, response.status().as_str()])
+        .inc();
+    metrics()
+        .gateway_duration
+        .with_label_values(&[broker, &route])
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+/// Rejects requests without valid credentials, and passes on the caller's identity
+async fn authenticate<B>(
+    State(state): State<Arc<GatewayState>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response<BoxBody> {
+    let identity = match &state.auth {
+        Some(auth) => match auth.authenticate(request.headers(), request.uri().query()) {
+            Ok(identity) => identity,
+            Err(e) => {
+                debug!(
+                    broker = state.broker.name(),
+                    "Gateway authentication failed - {e}"
+                );
+                return (StatusCode::UNAUTHORIZED, "unauthorized").into_response();
+            }
+        },
+        None => Identity::anonymous(),
+    };
+    request.extensions_mut().insert(identity);
+    next.run(request).await
+}
+
+/// Rejects clients that have made too many requests from their IP
+async fn limit_ips<B>(
+    State(state): State<Arc<GatewayState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response<BoxBody> {
+    if let Some(Err(wait)) = state
+        .rate_limiter
+        .as_ref()
+        .map(|limiter| limiter.check_ip(addr.ip()))
+    {
+        debug!(broker = state.broker.name(), ip = %addr.ip(), "Rate limited");
+        return too_many_requests(wait);
+    }
+    next.run(request).await
+}
+
+/// Rejects identities that have made too many requests. Without auth there's only the anonymous identity,
+/// so nothing is limited here.
+async fn limit_keys<B>(
+    State(state): State<Arc<GatewayState>>,
+    Extension(identity): Extension<Identity>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response<BoxBody> {
+    if let (Some(limiter), Some(_)) = (&state.rate_limiter, &state.auth) {
+        if let Err(wait) = limiter.check_key(&identity.name) {
+            debug!(broker = state.broker.name(), identity = %identity.name, "Rate limited");
+            return too_many_requests(wait);
+        }
+    }
+    next.run(request).await
+}
+
+fn too_many_requests(wait: Duration) -> Response<BoxBody> {
+    let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, retry_after.to_string())],
+        "too many requests",
+    )
+        .into_response()
+}
+
+/// Waits for the next message on a websocket or event stream subscription,
+/// skipping any dropped because the client fell behind
+async fn next_delivery(broker: &str, client: &str, receiver: &mut Receiver) -> Option<Delivery> {
+    loop {
+        match receiver.recv().await {
+            Ok(delivery) => return Some(delivery),
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(
+                    broker,
+                    client, skipped, "Gateway subscription fell behind - messages were dropped"
+                );
+                metrics()
+                    .lagged_messages
+                    .with_label_values(&[broker, client])
+                    .inc_by(skipped);
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+fn axum_headers_to_message_headers(headers: &HeaderMap) -> Vec<(String, Vec<u8>)> {
+    http_headers_to_message_headers(headers.iter().map(|(n, v)| (n.as_str(), v.as_bytes())))
+}
+
+/// A span for an incoming request, continuing the caller's trace if it sent a `traceparent`
+fn server_span(route: &str, broker: &str, subject: &str, headers: &HeaderMap) -> Span {
+    let span = info_span!("gateway", otel.kind = "server", route, broker, subject);
+    telemetry::set_parent_from_http(&span, headers);
+    span
 }
 
 async fn publish(
     Path(subject): Path<String>,
     State(state): State<Arc<GatewayState>>,
+    Extension(identity): Extension<Identity>,
+    headers: HeaderMap,
     body: Bytes,
 ) -> impl IntoResponse {
     let broker = &state.broker;
-    match broker
-        .publish(OutputMessage {
-            subject: Some(subject),
-            message: body.to_vec(),
-            broker: None,
-            response_subject: None,
-        })
+    if !identity.can_publish(&subject) {
+        warn!(identity = %identity.name, %subject, "Not allowed to publish");
+        return (StatusCode::FORBIDDEN, "not allowed to publish to subject");
+    }
+    let timeout = Duration::from_millis(state.timeout.unwrap_or(2000));
+    let span = server_span("publish", broker.name(), &subject, &headers);
+    let published = broker.publish(OutputMessage {
+        subject: Some(subject),
+        message: body.to_vec(),
+        broker: None,
+        response_subject: None,
+        headers: axum_headers_to_message_headers(&headers),
+    });
+    // Publishing resolves once the broker accepts the message, which can take a while if it's reconnecting
+    match tokio::time::timeout(timeout, published)
+        .instrument(span)
         .await
     {
-        Ok(_) => (StatusCode::ACCEPTED, "published to subject"),
-        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "couldn't publish"),
+        Ok(Ok(_)) => (StatusCode::ACCEPTED, "published to subject"),
+        Ok(Err(e)) => {
+            error!(broker = broker.name(), "Gateway publish failed - {e:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "couldn't publish")
+        }
+        Err(_) => (
+            StatusCode::GATEWAY_TIMEOUT,
+            "timed out waiting for the broker to accept the message",
+        ),
     }
 }
 
+/// Liveness - the gateway is up, whatever state the broker is in
+async fn healthz(State(state): State<Arc<GatewayState>>) -> impl IntoResponse {
+    (StatusCode::OK, Json(state.broker.health()))
+}
+
+/// Readiness - fails while the broker is disconnected
+async fn readyz(State(state): State<Arc<GatewayState>>) -> impl IntoResponse {
+    let health = state.broker.health();
+    let status = if health.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(health))
+}
+
 async fn subscribe(
     Path(subject): Path<String>,
     State(state): State<Arc<GatewayState>>,
+    Extension(identity): Extension<Identity>,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
-    println!("Setting up upgrade");
+    if !identity.can_subscribe(&subject, state.broker.wildcards()) {
+        warn!(identity = %identity.name, %subject, "Not allowed to subscribe");
+        return (StatusCode::FORBIDDEN, "not allowed to subscribe to subject").into_response();
+    }
     let websockets = state.websockets.clone();
 
     if let Some(websockets) = websockets {
@@ -99,33 +322,29 @@ async fn handle_subscribe_websocket(
     broker: Arc<dyn MessageBroker>,
     websockets: configs::WebsocketConfig,
 ) {
-    println!("upgraded");
     if let Ok(mut result) = broker.subscribe_to_topic(&subject).await {
-        println!("subscribed to {subject}");
-        while let Ok(message) = result.recv().await {
-            println!("socket subscription message recieved");
+        debug!(broker = broker.name(), %subject, "Websocket subscribed");
+        while let Some(Delivery { input: message, .. }) =
+            next_delivery(broker.name(), "websocket", &mut result).await
+        {
             match websockets {
                 configs::WebsocketConfig::BinaryBody => {
                     let _ = socket.send(WsMessage::Binary(message.message)).await;
-                    println!("socket subscription message sent");
                 }
                 configs::WebsocketConfig::TextBody => {
                     if let Ok(body) = std::str::from_utf8(&message.message) {
                         let _ = socket.send(WsMessage::Text(body.to_string())).await;
-                        println!("socket subscription message sent");
                     }
                 }
                 configs::WebsocketConfig::Messagepack => {
                     let mut buf = Vec::new();
                     if let Ok(()) = message.serialize(&mut rmp_serde::Serializer::new(&mut buf)) {
                         let _ = socket.send(WsMessage::Binary(buf)).await;
-                        println!("socket subscription messagepack sent");
                     }
                 }
                 configs::WebsocketConfig::Json => {
                     if let Ok(json) = serde_json::to_string(&message) {
                         let _ = socket.send(WsMessage::Text(json)).await;
-                        println!("socket subscription message json sent");
                     }
                 }
             }
@@ -133,6 +352,95 @@ async fn handle_subscribe_websocket(
     }
 }
 
+const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
+/// Streams a subject as server sent events, for clients that can't use websockets.
+/// Reconnecting clients send a `Last-Event-ID`, which resumes after that message if the broker can replay.
+async fn events(
+    Path(subject): Path<String>,
+    State(state): State<Arc<GatewayState>>,
+    Extension(identity): Extension<Identity>,
+    headers: HeaderMap,
+) -> Response<BoxBody> {
+    if !identity.can_subscribe(&subject, state.broker.wildcards()) {
+        warn!(identity = %identity.name, %subject, "Not allowed to subscribe");
+        return (StatusCode::FORBIDDEN, "not allowed to subscribe to subject").into_response();
+    }
+    let Some(encoding) = state.websockets.clone() else {
+        return (StatusCode::BAD_REQUEST, "Event streams aren't supported").into_response();
+    };
+    let broker = state.broker.clone();
+    let last_event_id = headers
+        .get(LAST_EVENT_ID_HEADER)
+        .and_then(|id| id.to_str().ok()?.parse::<u64>().ok());
+
+    let replayed = match last_event_id {
+        Some(after) => broker.subscribe_to_topic_from(&subject, after).await,
+        None => Ok(None),
+    };
+    let subscribed = match replayed {
+        Ok(Some(receiver)) => {
+            debug!(broker = broker.name(), %subject, after = last_event_id, "Event stream resumed");
+            Ok(receiver)
+        }
+        Ok(None) => broker.subscribe_to_topic(&subject).await,
+        Err(e) => Err(e),
+    };
+    match subscribed {
+        Ok(receiver) => {
+            debug!(broker = broker.name(), %subject, "Event stream subscribed");
+            Sse::new(event_stream(broker, receiver, encoding))
+                .keep_alive(KeepAlive::default())
+                .into_response()
+        }
+        Err(e) => {
+            error!(broker = broker.name(), %subject, "Event stream subscription failed - {e:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "couldn't subscribe").into_response()
+        }
+    }
+}
+
+/// Each event's id is the message's sequence if the broker has one, so clients can resume from it.
+/// Otherwise it just counts the messages sent on this stream.
+fn event_stream(
+    broker: Arc<dyn MessageBroker>,
+    receiver: Receiver,
+    encoding: configs::WebsocketConfig,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    futures::stream::unfold((receiver, 0u64), move |(mut receiver, mut count)| {
+        let broker = broker.clone();
+        let encoding = encoding.clone();
+        async move {
+            let Delivery { input: message, .. } =
+                next_delivery(broker.name(), "events", &mut receiver).await?;
+            count += 1;
+            let id = broker.sequence(&message).unwrap_or(count);
+            let event = encode_event(&message, &encoding)
+                .map(|data| Event::default().id(id.to_string()).data(data));
+            Some((event, (receiver, count)))
+        }
+    })
+    .filter_map(|event| async move { event.map(Ok) })
+}
+
+/// Event data has to be text, so binary encodings are sent as base64
+fn encode_event(message: &InputMessage, encoding: &configs::WebsocketConfig) -> Option<String> {
+    match encoding {
+        configs::WebsocketConfig::BinaryBody => Some(STANDARD.encode(&message.message)),
+        configs::WebsocketConfig::TextBody => std::str::from_utf8(&message.message)
+            .ok()
+            .map(str::to_string),
+        configs::WebsocketConfig::Messagepack => {
+            let mut buf = Vec::new();
+            message
+                .serialize(&mut rmp_serde::Serializer::new(&mut buf))
+                .ok()?;
+            Some(STANDARD.encode(buf))
+        }
+        configs::WebsocketConfig::Json => serde_json::to_string(message).ok(),
+    }
+}
+
 fn axum_to_http(
     method: axum::http::Method,
     headers: axum::http::HeaderMap,
@@ -197,6 +505,7 @@ fn http_to_axum(
 async fn request_handler(
     Path(path): Path<String>,
     State(state): State<Arc<GatewayState>>,
+    Extension(identity): Extension<Identity>,
     uri: Uri,
     method: Method,
     headers: HeaderMap,
@@ -204,22 +513,38 @@ async fn request_handler(
 ) -> Response<BoxBody> {
     if let Some(serializer) = &state.request_response {
         let broker = &state.broker;
+        // Requests are published to `request.<id>.<method>.<path>`
+        let subject = broker.generate_request_subscription(&path, &Some(method.to_string()));
+        if !identity.can_publish(&subject) {
+            warn!(identity = %identity.name, %subject, "Not allowed to request");
+            return (StatusCode::FORBIDDEN, "not allowed to request path").into_response();
+        }
         let timeout = state.timeout.unwrap_or(2000);
         let timeout = Duration::from_millis(timeout);
 
+        let span = server_span("request", broker.name(), &path, &headers);
         let Ok(request) = axum_to_http(method, headers, uri, path, bytes) else {
             return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
         };
-        match tokio::time::timeout(timeout, broker.http_request(request, serializer)).await {
+        match tokio::time::timeout(timeout, broker.http_request(request, serializer))
+            .instrument(span)
+            .await
+        {
             Ok(Ok(result)) => {
-                println!("Got Parsed Response: {result:?}");
+                debug!(status = %result.status, "Got response");
                 match http_to_axum(result.status, result.headers, result.body) {
                     Ok(r) => r,
                     Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
                 }
             }
             Ok(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
-            _ => (StatusCode::GATEWAY_TIMEOUT, "response timed out").into_response(),
+            _ => {
+                metrics()
+                    .request_timeouts
+                    .with_label_values(&[broker.name(), "gateway"])
+                    .inc();
+                (StatusCode::GATEWAY_TIMEOUT, "response timed out").into_response()
+            }
         }
     } else {
         (StatusCode::BAD_REQUEST, "request-response is not supported").into_response()
@@ -228,14 +553,14 @@ async fn request_handler(
 
 async fn ws_handler(
     State(state): State<Arc<GatewayState>>,
+    Extension(identity): Extension<Identity>,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
-    println!("Setting up upgrade");
     let websockets = state.websockets.clone();
 
     if let Some(websockets) = websockets {
         ws.on_upgrade(move |socket| {
-            handle_bidirectional_websocket(socket, state.broker.clone(), websockets)
+            handle_bidirectional_websocket(socket, state.broker.clone(), websockets, identity)
         })
         .into_response()
     } else {
@@ -253,14 +578,14 @@ async fn handle_bidirectional_websocket(
     socket: WebSocket,
     broker: Arc<dyn MessageBroker>,
     websockets: configs::WebsocketConfig,
+    identity: Identity,
 ) {
-    println!("upgraded");
     let (mut sender, receiver) = socket.split();
 
     let broker = broker.clone();
     let (channel_send, mut channel_recv) = tokio::sync::mpsc::channel(10);
     tokio::spawn(async move {
-        receive_websocket_messages(receiver, &broker, channel_send, websockets).await
+        receive_websocket_messages(receiver, &broker, channel_send, websockets, &identity).await
     });
 
     while let Some(t) = channel_recv.recv().await {
@@ -282,21 +607,20 @@ async fn websocket_subscribe(
     };
 
     if let Ok(mut result) = broker.subscribe_to_topic(&subject).await {
-        println!("subscribed to {subject}");
-        while let Ok(message) = result.recv().await {
-            println!("socket subscription message recieved");
+        debug!(broker = broker.name(), %subject, "Websocket subscribed");
+        while let Some(Delivery { input: message, .. }) =
+            next_delivery(broker.name(), "websocket", &mut result).await
+        {
             match is_binary {
                 true => {
                     let mut buf = Vec::new();
                     if let Ok(()) = message.serialize(&mut rmp_serde::Serializer::new(&mut buf)) {
                         let _ = sender.send(WsMessage::Binary(buf)).await;
-                        println!("socket subscription messagepack sent");
                     }
                 }
                 false => {
                     if let Ok(json) = serde_json::to_string(&message) {
                         let _ = sender.send(WsMessage::Text(json)).await;
-                        println!("socket subscription message json sent");
                     }
                 }
             }
@@ -309,28 +633,47 @@ async fn receive_websocket_messages(
     broker: &Arc<dyn MessageBroker>,
     sender: tokio::sync::mpsc::Sender<WsMessage>,
     websocket: configs::WebsocketConfig,
+    identity: &Identity,
 ) {
     loop {
-        println!("waiting for message");
         match receiver.next().await {
             Some(Ok(msg)) => {
-                println!("parsing...");
                 let Some(parsed) = (match msg {
                     WsMessage::Text(text) => serde_json::from_str(&text).ok(),
                     WsMessage::Binary(binary) => rmp_serde::from_slice(&binary).ok(),
                     WsMessage::Close(reason) => {
-                        println!("Websocket closed {reason:?}");
+                        debug!(?reason, "Websocket closed");
                         break;
                     }
                     _ => {
                         continue;
                     }
                 }) else {
-                    println!("parse failed");
+                    warn!("Couldn't parse websocket message - closing the socket");
                     break;
                 };
 
                 match parsed {
+                    BidirectionalSocketMessage::Subscribe(subject)
+                        if !identity.can_subscribe(&subject, broker.wildcards()) =>
+                    {
+                        warn!(identity = %identity.name, %subject, "Not allowed to subscribe");
+                    }
+                    BidirectionalSocketMessage::Publish(message)
+                        if !identity
+                            .can_publish(message.subject.as_deref().unwrap_or_default())
+                            || message
+                                .response_subject
+                                .as_deref()
+                                .is_some_and(|subject| !identity.can_publish(subject)) =>
+                    {
+                        warn!(
+                            identity = %identity.name,
+                            subject = message.subject.as_deref(),
+                            response_subject = message.response_subject.as_deref(),
+                            "Not allowed to publish"
+                        );
+                    }
                     BidirectionalSocketMessage::Subscribe(subject) => {
                         let broker = broker.clone();
                         let websocket = websocket.clone();
@@ -339,22 +682,23 @@ async fn receive_websocket_messages(
                             websocket_subscribe(broker, subject, websocket, sender).await;
                         });
                     }
-                    BidirectionalSocketMessage::Publish(message) => {
-                        println!("publishing to broker {message:?}");
-                        let _ = broker.publish(message).await;
+                    BidirectionalSocketMessage::Publish(mut message) => {
+                        // Websocket clients can only publish to the gateway's own broker
+                        message.broker = None;
+                        debug!(subject = message.subject.as_deref(), payload = %telemetry::payload(&message.message), "Publishing from websocket");
+                        if let Err(e) = broker.publish(message).await {
+                            error!("Websocket publish failed - {e:?}");
+                        }
                     }
                 }
             }
             Some(Err(e)) => {
-                eprintln!("Error receiving socket {e:?}");
+                warn!("Error receiving from websocket - {e:?}");
                 break;
             }
             None => {
-                eprintln!("Received nothing...");
                 break;
             }
         };
-        println!("looping back");
     }
-    println!("exited");
 }
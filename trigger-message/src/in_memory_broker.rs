@@ -9,7 +9,10 @@ use dashmap::DashMap;
 use spin_message_types::{InputMessage, OutputMessage};
 use wildmatch::*;
 
-use crate::broker::{create_channel, MessageBroker, QueueReceiver, QueueSender, Receiver, Sender};
+use crate::broker::{
+    create_channel, Acker, Acknowledgement, Delivery, MessageBroker, QueueReceiver, QueueSender,
+    Receiver, Sender, Wildcards,
+};
 
 #[derive(Clone, Debug)]
 pub struct Subscription(WildMatch, Sender);
@@ -17,6 +20,30 @@ pub struct Subscription(WildMatch, Sender);
 #[derive(Clone, Debug)]
 pub struct QueueGroup(WildMatch, Vec<QueueSender>, Arc<AtomicUsize>);
 
+impl QueueGroup {
+    pub fn new(topic: &str, sender: QueueSender) -> Self {
+        Self(WildMatch::new(topic), vec![sender], Arc::new(0.into()))
+    }
+
+    pub fn matches(&self, subject: &str) -> bool {
+        self.0.matches(subject)
+    }
+
+    pub fn add_member(&mut self, sender: QueueSender) {
+        self.1.push(sender);
+    }
+
+    /// Picks the next member of the group, in round robin order
+    pub fn next_member(&self) -> Option<&QueueSender> {
+        let mut index = self.2.fetch_add(1, atomic::Ordering::SeqCst);
+        if index >= self.1.len() {
+            self.2.store(1, atomic::Ordering::SeqCst);
+            index = 0;
+        }
+        self.1.get(index)
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct InMemoryBroker {
     name: String,
@@ -32,15 +59,14 @@ impl InMemoryBroker {
             queue_subscriptions: Default::default(),
         }
     }
-}
-
-#[async_trait]
-impl MessageBroker for InMemoryBroker {
-    fn name(&self) -> &str {
-        &self.name
-    }
 
-    async fn publish(&self, message: OutputMessage) -> Result<()> {
+    /// Publishes a message, attaching the provided acker to every delivery.
+    /// Without an acker, queue deliveries can be requeued locally.
+    pub fn publish_with_acker(
+        &self,
+        message: OutputMessage,
+        acker: Option<Arc<dyn Acker>>,
+    ) -> Result<()> {
         let subject = &message
             .subject
             .as_deref()
@@ -50,6 +76,11 @@ impl MessageBroker for InMemoryBroker {
             subject: subject.to_string(),
             broker: self.name.clone(),
             response_subject: message.response_subject,
+            headers: message.headers,
+        };
+        let delivery = Delivery {
+            input: message.clone(),
+            acker: acker.clone(),
         };
         for r in self
             .topic_subscriptions
@@ -57,25 +88,81 @@ impl MessageBroker for InMemoryBroker {
             .filter(|r| r.key() == subject || r.0.matches(subject))
         {
             let value = r.value();
-            value.1.send(message.clone())?;
+            value.1.send(delivery.clone())?;
         }
         for r in self
             .queue_subscriptions
             .iter()
             .filter(|r| r.key() == subject || r.0.matches(subject))
         {
-            let value = r.value();
-            let mut index = value.2.fetch_add(1, atomic::Ordering::SeqCst);
-            if index >= value.1.len() {
-                value.2.store(1, atomic::Ordering::SeqCst);
-                index = 0;
-            }
-            if let Some(sender) = value.1.get(index) {
-                sender.send(message.clone())?;
+            if let Some(sender) = r.value().next_member() {
+                let delivery = match &acker {
+                    Some(_) => delivery.clone(),
+                    None => RequeueAcker::delivery(message.clone(), sender.clone()),
+                };
+                sender.send(delivery)?;
             }
         }
         Ok(())
     }
+}
+
+/// Redelivers nacked queue messages to the same queue member.
+#[derive(Debug)]
+struct RequeueAcker {
+    message: InputMessage,
+    sender: QueueSender,
+}
+
+impl RequeueAcker {
+    fn delivery(message: InputMessage, sender: QueueSender) -> Delivery {
+        let acker = Arc::new(RequeueAcker {
+            message: message.clone(),
+            sender,
+        });
+        Delivery::with_acker(message, acker)
+    }
+
+    fn requeue(&self) -> Result<()> {
+        let delivery = RequeueAcker::delivery(self.message.clone(), self.sender.clone());
+        self.sender.send(delivery)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Acker for RequeueAcker {
+    async fn acknowledge(&self, ack: Acknowledgement) -> Result<()> {
+        match ack {
+            Acknowledgement::Ack | Acknowledgement::Nack { requeue: false } => Ok(()),
+            Acknowledgement::Nack { requeue: true } => self.requeue(),
+            Acknowledgement::RetryAfter(delay) => {
+                let delivery = RequeueAcker::delivery(self.message.clone(), self.sender.clone());
+                let sender = self.sender.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = sender.send(delivery);
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MessageBroker for InMemoryBroker {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Subscriptions are matched with `WildMatch`, so `*` also matches across tokens
+    fn wildcards(&self) -> Wildcards {
+        Wildcards::Glob
+    }
+
+    async fn publish(&self, message: OutputMessage) -> Result<()> {
+        self.publish_with_acker(message, None)
+    }
 
     async fn subscribe_to_topic(&self, subject: &str) -> Result<Receiver> {
         if let Some(sender) = self.topic_subscriptions.get(subject) {
@@ -94,13 +181,12 @@ impl MessageBroker for InMemoryBroker {
         if let Some(mut group) = self.queue_subscriptions.get_mut(&subject) {
             let sender = create_channel(10);
 
-            group.1.push(sender.clone());
+            group.add_member(sender.clone());
 
             Ok(sender.subscribe())
         } else {
             let sender = create_channel(10);
-            let wildmatch = WildMatch::new(topic);
-            let group = QueueGroup(wildmatch, vec![sender.clone()], Arc::new(0.into()));
+            let group = QueueGroup::new(topic, sender.clone());
             self.queue_subscriptions.insert(subject.to_string(), group);
             Ok(sender.subscribe())
         }
@@ -109,7 +195,9 @@ impl MessageBroker for InMemoryBroker {
 
 #[cfg(test)]
 mod test {
-    use crate::broker::MessageBroker;
+    use std::time::Duration;
+
+    use crate::broker::{Acknowledgement, MessageBroker};
     use spin_message_types::OutputMessage;
 
     use super::InMemoryBroker;
@@ -121,6 +209,7 @@ mod test {
             message: "test".as_bytes().to_owned(),
             broker: None,
             response_subject: None,
+            headers: vec![],
         };
 
         let broker = InMemoryBroker::default();
@@ -134,6 +223,29 @@ mod test {
         assert_eq!(result.message, message.message);
     }
 
+    #[tokio::test]
+    async fn headers_are_passed_to_the_subscriber() {
+        let message = OutputMessage {
+            subject: Some("message.test".to_string()),
+            message: "test".as_bytes().to_owned(),
+            broker: None,
+            response_subject: None,
+            headers: vec![(
+                "content-type".to_string(),
+                "text/plain".as_bytes().to_owned(),
+            )],
+        };
+
+        let broker = InMemoryBroker::default();
+
+        let mut rx = broker.subscribe_to_topic("message.test").await.unwrap();
+
+        broker.publish(message.clone()).await.unwrap();
+        let result = rx.try_recv().unwrap();
+
+        assert_eq!(result.headers, message.headers);
+    }
+
     #[tokio::test]
     async fn a_published_message_doesnt_get_recieved_by_the_wrong_subscriber() {
         let message = OutputMessage {
@@ -141,6 +253,7 @@ mod test {
             message: "test".as_bytes().to_owned(),
             broker: None,
             response_subject: None,
+            headers: vec![],
         };
 
         let broker = InMemoryBroker::default();
@@ -159,12 +272,14 @@ mod test {
             message: "test".as_bytes().to_owned(),
             broker: None,
             response_subject: None,
+            headers: vec![],
         };
         let message_2 = OutputMessage {
             subject: Some("message.test".to_string()),
             message: "test 2".as_bytes().to_owned(),
             broker: None,
             response_subject: None,
+            headers: vec![],
         };
 
         let broker = InMemoryBroker::default();
@@ -192,6 +307,7 @@ mod test {
             message: "test".as_bytes().to_owned(),
             broker: None,
             response_subject: None,
+            headers: vec![],
         };
 
         let broker = InMemoryBroker::default();
@@ -212,6 +328,7 @@ mod test {
             message: "test".as_bytes().to_owned(),
             broker: None,
             response_subject: None,
+            headers: vec![],
         };
 
         let broker = InMemoryBroker::default();
@@ -230,6 +347,7 @@ mod test {
             message: "test".as_bytes().to_owned(),
             broker: None,
             response_subject: None,
+            headers: vec![],
         };
 
         let broker = InMemoryBroker::default();
@@ -253,6 +371,7 @@ mod test {
             message: "test".as_bytes().to_owned(),
             broker: None,
             response_subject: None,
+            headers: vec![],
         };
 
         let broker = InMemoryBroker::default();
@@ -281,6 +400,7 @@ mod test {
             message: "test".as_bytes().to_owned(),
             broker: None,
             response_subject: None,
+            headers: vec![],
         };
 
         let broker = InMemoryBroker::default();
@@ -315,4 +435,63 @@ mod test {
         assert_eq!(&result.subject, message.subject.as_ref().unwrap());
         assert_eq!(result.message, message.message);
     }
+
+    #[tokio::test]
+    async fn a_nacked_queue_message_gets_redelivered() {
+        let message = OutputMessage {
+            subject: Some("message.test".to_string()),
+            message: "test".as_bytes().to_owned(),
+            ..Default::default()
+        };
+
+        let broker = InMemoryBroker::default();
+
+        let mut rx = broker
+            .subscribe_to_queue("message.test", "group")
+            .await
+            .unwrap();
+
+        broker.publish(message.clone()).await.unwrap();
+        let result = rx.try_recv().expect("Should Successfully Recieve");
+        result
+            .acknowledge(Acknowledgement::Nack { requeue: true })
+            .await
+            .unwrap();
+
+        let result = rx.try_recv().expect("Should Be Redelivered");
+        assert_eq!(result.message, message.message);
+
+        result.acknowledge(Acknowledgement::Ack).await.unwrap();
+        let _ = rx.try_recv().expect_err("Should Be Empty");
+    }
+
+    #[tokio::test]
+    async fn a_queue_message_retried_after_a_delay_gets_redelivered() {
+        let message = OutputMessage {
+            subject: Some("message.test".to_string()),
+            message: "test".as_bytes().to_owned(),
+            ..Default::default()
+        };
+
+        let broker = InMemoryBroker::default();
+
+        let mut rx = broker
+            .subscribe_to_queue("message.test", "group")
+            .await
+            .unwrap();
+
+        broker.publish(message.clone()).await.unwrap();
+        let result = rx.try_recv().expect("Should Successfully Recieve");
+        result
+            .acknowledge(Acknowledgement::RetryAfter(Duration::from_millis(10)))
+            .await
+            .unwrap();
+        let _ = rx.try_recv().expect_err("Should Be Empty");
+
+        let result = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .expect("Should Be Redelivered")
+            .unwrap();
+        assert_eq!(result.message, message.message);
+    }
 }
@@ -0,0 +1,652 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Debug,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use rdkafka::{
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    message::{BorrowedMessage, Header, Headers, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord, Producer},
+    ClientConfig, Message, Offset, TopicPartitionList,
+};
+use serde::{Deserialize, Serialize};
+use spin_message_types::{InputMessage, OutputMessage};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    task::JoinSet,
+};
+use tracing::{error, info, trace, warn};
+
+use crate::{
+    broker::{
+        create_channel, default_message_response_subject, Acker, Acknowledgement, Delivery,
+        MessageBroker, QueueReceiver, Receiver, Sender,
+    },
+    connection::{published, BrokerHealth, ConnectionEvent, ConnectionMonitor, PendingPublish},
+};
+
+/// The header used as the kafka message key, both when publishing & receiving
+pub const KEY_HEADER: &str = "kafka-key";
+/// Publishing with this header sends the message to a specific partition
+pub const PARTITION_HEADER: &str = "kafka-partition";
+
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
+const SEEK_TIMEOUT: Duration = Duration::from_secs(5);
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct Subscription(Sender);
+
+#[derive(Clone, Debug)]
+pub struct KafkaBroker {
+    name: String,
+    map: Arc<DashMap<String, Subscription>>,
+    subscription_handler: mpsc::Sender<(String, Sender)>,
+    queue_handler: mpsc::Sender<(String, String, Sender)>,
+    publish_handler: mpsc::Sender<PendingPublish>,
+    shutdown_handler: mpsc::Sender<oneshot::Sender<()>>,
+    monitor: ConnectionMonitor,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KafkaSasl {
+    /// The SASL mechanism, such as "PLAIN", "SCRAM-SHA-256" or "SCRAM-SHA-512"
+    mechanism: String,
+    username: String,
+    password: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub enum KafkaOffsetReset {
+    #[default]
+    Earliest,
+    Latest,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct KafkaConnectionInfo {
+    brokers: Vec<String>,
+    client_id: Option<String>,
+    tls: Option<bool>,
+    sasl: Option<KafkaSasl>,
+    root_certificate: Option<String>,
+    client_certificate: Option<String>,
+    client_key: Option<String>,
+    /// Where a new consumer group starts reading from. Topic subscriptions always start from the latest message
+    offset_reset: Option<KafkaOffsetReset>,
+    /// Any additional librdkafka configuration
+    #[serde(default)]
+    options: HashMap<String, String>,
+}
+
+impl KafkaConnectionInfo {
+    fn client_config(&self) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", self.brokers.join(","));
+        if let Some(client_id) = &self.client_id {
+            config.set("client.id", client_id);
+        }
+        let tls = self.tls.unwrap_or_default();
+        let protocol = match (tls, &self.sasl) {
+            (true, Some(_)) => "SASL_SSL",
+            (false, Some(_)) => "SASL_PLAINTEXT",
+            (true, None) => "SSL",
+            (false, None) => "PLAINTEXT",
+        };
+        config.set("security.protocol", protocol);
+        if let Some(sasl) = &self.sasl {
+            config
+                .set("sasl.mechanisms", &sasl.mechanism)
+                .set("sasl.username", &sasl.username)
+                .set("sasl.password", &sasl.password);
+        }
+        if let Some(root) = &self.root_certificate {
+            config.set("ssl.ca.location", root);
+        }
+        if let Some(cert) = &self.client_certificate {
+            config.set("ssl.certificate.location", cert);
+        }
+        if let Some(key) = &self.client_key {
+            config.set("ssl.key.location", key);
+        }
+        for (key, value) in self.options.iter() {
+            config.set(key, value);
+        }
+        config
+    }
+
+    fn consumer(&self, group: &str, offset_reset: &KafkaOffsetReset) -> Result<StreamConsumer> {
+        let offset_reset = match offset_reset {
+            KafkaOffsetReset::Earliest => "earliest",
+            KafkaOffsetReset::Latest => "latest",
+        };
+        let consumer = self
+            .client_config()
+            .set("group.id", group)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", offset_reset)
+            .create()?;
+        Ok(consumer)
+    }
+}
+
+/// Kafka subscribes to patterns using regular expressions starting with `^`
+fn to_topic_pattern(subject: &str) -> String {
+    if !subject.contains(['*', '>']) {
+        return subject.to_string();
+    }
+    let pattern = subject
+        .split('.')
+        .map(|part| match part {
+            "*" => "[^.]+".to_string(),
+            ">" => ".+".to_string(),
+            part => regex_escape(part),
+        })
+        .collect::<Vec<_>>()
+        .join("\\.");
+    format!("^{pattern}$")
+}
+
+fn regex_escape(part: &str) -> String {
+    part.chars().fold(String::new(), |mut escaped, c| {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    })
+}
+
+fn to_kafka_headers(headers: &[(String, Vec<u8>)]) -> OwnedHeaders {
+    headers
+        .iter()
+        .filter(|(name, _)| name != KEY_HEADER && name != PARTITION_HEADER)
+        .fold(OwnedHeaders::new(), |headers, (name, value)| {
+            headers.insert(Header {
+                key: name,
+                value: Some(value),
+            })
+        })
+}
+
+fn find_header<'a>(headers: &'a [(String, Vec<u8>)], name: &str) -> Option<&'a [u8]> {
+    headers
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_slice())
+}
+
+fn to_input_message(name: &str, msg: &BorrowedMessage<'_>) -> InputMessage {
+    let mut headers: Vec<(String, Vec<u8>)> = msg
+        .headers()
+        .map(|headers| {
+            headers
+                .iter()
+                .map(|h| (h.key.to_string(), h.value.unwrap_or_default().to_vec()))
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Some(key) = msg.key() {
+        headers.push((KEY_HEADER.to_string(), key.to_vec()));
+    }
+    let subject = msg.topic().to_string();
+    InputMessage {
+        message: msg.payload().unwrap_or_default().to_vec(),
+        response_subject: default_message_response_subject(&subject),
+        subject,
+        broker: name.to_string(),
+        headers,
+    }
+}
+
+/// The messages of a partition that are still being handled. With more than one message
+/// in flight, an offset can only be committed once every message before it is done.
+#[derive(Debug, Default)]
+struct PartitionOffsets {
+    in_flight: BTreeSet<i64>,
+    next: i64,
+    committed: i64,
+}
+
+impl PartitionOffsets {
+    fn received(&mut self, offset: i64) {
+        self.in_flight.insert(offset);
+        self.next = self.next.max(offset + 1);
+    }
+
+    /// Marks a message as handled, returning the offset to commit if it moved forward
+    fn completed(&mut self, offset: i64) -> Option<i64> {
+        self.in_flight.remove(&offset);
+        let commit = self.in_flight.first().copied().unwrap_or(self.next);
+        if commit > self.committed {
+            self.committed = commit;
+            Some(commit)
+        } else {
+            None
+        }
+    }
+}
+
+type OffsetTracker = Arc<Mutex<HashMap<(String, i32), PartitionOffsets>>>;
+
+/// Commits the offsets of every handled message, waiting for the commit to finish
+fn commit_handled(consumer: &StreamConsumer, offsets: &OffsetTracker) -> Result<()> {
+    let mut list = TopicPartitionList::new();
+    for ((topic, partition), offsets) in offsets.lock().unwrap().iter() {
+        if offsets.committed > 0 {
+            list.add_partition_offset(topic, *partition, Offset::Offset(offsets.committed))?;
+        }
+    }
+    if list.count() > 0 {
+        consumer.commit(&list, CommitMode::Sync)?;
+    }
+    Ok(())
+}
+
+/// Commits offsets once every message up to them has been handled. Redelivery is done by
+/// seeking the partition back to the message, which also replays every message after it -
+/// including any that were already handled by other concurrent instances.
+struct KafkaAcker {
+    consumer: Arc<StreamConsumer>,
+    offsets: OffsetTracker,
+    topic: String,
+    partition: i32,
+    offset: i64,
+}
+
+impl Debug for KafkaAcker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KafkaAcker")
+            .field("topic", &self.topic)
+            .field("partition", &self.partition)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+impl KafkaAcker {
+    fn commit(&self) -> Result<()> {
+        let commit = self
+            .offsets
+            .lock()
+            .unwrap()
+            .entry((self.topic.clone(), self.partition))
+            .or_default()
+            .completed(self.offset);
+        let Some(commit) = commit else {
+            return Ok(());
+        };
+        let mut offsets = TopicPartitionList::new();
+        offsets.add_partition_offset(&self.topic, self.partition, Offset::Offset(commit))?;
+        self.consumer.commit(&offsets, CommitMode::Async)?;
+        Ok(())
+    }
+
+    /// Seeks the partition back to the message. Seeking blocks until the consumer has done it,
+    /// so it runs off the async runtime.
+    fn redeliver(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        let consumer = self.consumer.clone();
+        let topic = self.topic.clone();
+        let (partition, offset) = (self.partition, self.offset);
+        async move {
+            tokio::task::spawn_blocking(move || {
+                consumer.seek(&topic, partition, Offset::Offset(offset), SEEK_TIMEOUT)
+            })
+            .await??;
+            Ok(())
+        }
+    }
+}
+
+#[async_trait]
+impl Acker for KafkaAcker {
+    async fn acknowledge(&self, ack: Acknowledgement) -> Result<()> {
+        match ack {
+            Acknowledgement::Ack | Acknowledgement::Nack { requeue: false } => self.commit(),
+            Acknowledgement::Nack { requeue: true } => self.redeliver().await,
+            Acknowledgement::RetryAfter(delay) => {
+                let redeliver = self.redeliver();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    if let Err(e) = redeliver.await {
+                        warn!("Failed to redeliver Kafka message - {e:?}");
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
+impl KafkaBroker {
+    pub fn new(options: KafkaConnectionInfo, name: String) -> Self {
+        let (subscription_handler, sub_rx) = mpsc::channel(100);
+        let (publish_handler, pub_rx) = mpsc::channel(100);
+        let (queue_handler, queue_rx) = mpsc::channel(100);
+        let (shutdown_handler, shutdown_rx) = mpsc::channel(1);
+        let monitor = ConnectionMonitor::default();
+        let n = name.clone();
+        let m = monitor.clone();
+        tokio::spawn(async move {
+            if let Err(e) = KafkaBroker::setup_client(
+                n.clone(),
+                options,
+                sub_rx,
+                pub_rx,
+                queue_rx,
+                shutdown_rx,
+                m.clone(),
+            )
+            .await
+            {
+                error!(broker = %n, "Kafka Error: {e}");
+                m.disconnected(e);
+            }
+        });
+
+        Self {
+            name,
+            map: Default::default(),
+            subscription_handler,
+            publish_handler,
+            queue_handler,
+            shutdown_handler,
+            monitor,
+        }
+    }
+
+    async fn publish_on(
+        producer: &FutureProducer,
+        subject: &str,
+        message: &OutputMessage,
+    ) -> Result<()> {
+        let mut record = FutureRecord::to(subject)
+            .payload(&message.message)
+            .headers(to_kafka_headers(&message.headers));
+        if let Some(key) = find_header(&message.headers, KEY_HEADER) {
+            record = record.key(key);
+        }
+        if let Some(partition) = find_header(&message.headers, PARTITION_HEADER)
+            .and_then(|p| std::str::from_utf8(p).ok())
+            .and_then(|p| p.parse().ok())
+        {
+            record = record.partition(partition);
+        }
+        producer
+            .send(record, PUBLISH_TIMEOUT)
+            .await
+            .map_err(|(e, _)| e)?;
+        Ok(())
+    }
+
+    async fn consume(
+        name: &str,
+        consumer: Arc<StreamConsumer>,
+        subject: &str,
+        sender: Sender,
+        offsets: Option<OffsetTracker>,
+    ) -> Result<()> {
+        let pattern = to_topic_pattern(subject);
+        consumer.subscribe(&[pattern.as_str()])?;
+        loop {
+            let msg = match consumer.recv().await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!(broker = name, "Failed to receive Kafka Message - {e:?}");
+                    continue;
+                }
+            };
+            trace!(broker = name, subject = msg.topic(), "Received message");
+            let input = to_input_message(name, &msg);
+            let delivery = if let Some(offsets) = &offsets {
+                offsets
+                    .lock()
+                    .unwrap()
+                    .entry((msg.topic().to_string(), msg.partition()))
+                    .or_default()
+                    .received(msg.offset());
+                let acker = Arc::new(KafkaAcker {
+                    consumer: consumer.clone(),
+                    offsets: offsets.clone(),
+                    topic: msg.topic().to_string(),
+                    partition: msg.partition(),
+                    offset: msg.offset(),
+                });
+                Delivery::with_acker(input, acker)
+            } else {
+                input.into()
+            };
+            let _ = sender.send(delivery);
+        }
+    }
+
+    async fn setup_client(
+        name: String,
+        options: KafkaConnectionInfo,
+        mut sub_rx: mpsc::Receiver<(String, Sender)>,
+        mut pub_rx: mpsc::Receiver<PendingPublish>,
+        mut queue_rx: mpsc::Receiver<(String, String, Sender)>,
+        mut shutdown_rx: mpsc::Receiver<oneshot::Sender<()>>,
+        monitor: ConnectionMonitor,
+    ) -> Result<()> {
+        let producer: FutureProducer = options.client_config().create()?;
+        info!(broker = %name, "Connected to Kafka");
+        // librdkafka handles the connections itself, so failures only show up as failed publishes
+        monitor.connected();
+        let (close_publishing, mut closing) = oneshot::channel::<()>();
+        let publishing = tokio::spawn(async move {
+            let publish = |pending: PendingPublish| async {
+                let subject = &pending.subject;
+                // The delivery report only arrives once the brokers have acknowledged the message
+                let result = KafkaBroker::publish_on(&producer, subject, &pending.message).await;
+                match &result {
+                    Ok(_) => trace!(%subject, "Published"),
+                    Err(e) => error!(%subject, "Failed to publish - {e:?}"),
+                }
+                pending.complete(result);
+            };
+            loop {
+                tokio::select! {
+                    biased;
+                    Some(pending) = pub_rx.recv() => publish(pending).await,
+                    _ = &mut closing => break,
+                }
+            }
+            // Publishes that were already sent still go out, and later ones fail
+            pub_rx.close();
+            while let Some(pending) = pub_rx.recv().await {
+                publish(pending).await;
+            }
+            producer
+        });
+
+        // Consumers, along with the offsets to commit for queue consumers
+        let mut consumers: Vec<(Arc<StreamConsumer>, Option<OffsetTracker>)> = Vec::new();
+        let mut tasks = JoinSet::new();
+        let done = loop {
+            tokio::select! {
+                Some(done) = shutdown_rx.recv() => break done,
+                Some((subject, group, sender)) = queue_rx.recv() => {
+                    let consumer = match options
+                        .consumer(&group, &options.offset_reset.clone().unwrap_or_default())
+                    {
+                        Ok(consumer) => Arc::new(consumer),
+                        Err(e) => {
+                            error!(%subject, %group, "Couldn't create Kafka consumer - {e:?}");
+                            continue;
+                        }
+                    };
+                    let offsets = OffsetTracker::default();
+                    consumers.push((consumer.clone(), Some(offsets.clone())));
+                    let name = name.to_string();
+                    tasks.spawn(async move {
+                        info!(broker = %name, %subject, %group, "Subscribed to queue");
+                        if let Err(e) =
+                            KafkaBroker::consume(&name, consumer, &subject, sender, Some(offsets))
+                                .await
+                        {
+                            error!(broker = %name, %subject, %group, "Kafka Error: {e:?}");
+                        }
+                    });
+                }
+                Some((subject, sender)) = sub_rx.recv() => {
+                    // Every topic subscription gets its own group, so it receives every message
+                    let group = format!("{name}-{}", ulid::Ulid::new());
+                    let consumer = match options.consumer(&group, &KafkaOffsetReset::Latest) {
+                        Ok(consumer) => Arc::new(consumer),
+                        Err(e) => {
+                            error!(%subject, "Couldn't create Kafka consumer - {e:?}");
+                            continue;
+                        }
+                    };
+                    consumers.push((consumer.clone(), None));
+                    let name = name.to_string();
+                    tasks.spawn(async move {
+                        info!(broker = %name, %subject, "Subscribed");
+                        if let Err(e) =
+                            KafkaBroker::consume(&name, consumer, &subject, sender, None).await
+                        {
+                            error!(broker = %name, %subject, "Kafka Error: {e:?}");
+                        }
+                    });
+                }
+                Some(_) = tasks.join_next() => {}
+            }
+        };
+
+        let _ = close_publishing.send(());
+        let producer = publishing.await?;
+        tasks.shutdown().await;
+        // Flushing, committing & leaving the consumer groups all block on the brokers
+        let flush_name = name.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = producer.flush(FLUSH_TIMEOUT) {
+                warn!(broker = %flush_name, "Couldn't flush Kafka producer - {e:?}");
+            }
+            for (consumer, offsets) in consumers {
+                if let Some(offsets) = offsets {
+                    if let Err(e) = commit_handled(&consumer, &offsets) {
+                        warn!(broker = %flush_name, "Couldn't commit Kafka offsets - {e:?}");
+                    }
+                }
+                consumer.unsubscribe();
+            }
+        })
+        .await?;
+        info!(broker = %name, "Closed Kafka clients");
+        monitor.disconnected("Shut down");
+        let _ = done.send(());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageBroker for KafkaBroker {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn publish(&self, message: OutputMessage) -> Result<()> {
+        let subject = &message
+            .subject
+            .as_deref()
+            .ok_or(anyhow::Error::msg("No Subject To Publish"))?;
+        let (pending, result) = PendingPublish::new(subject.to_string(), message);
+        self.publish_handler.send(pending).await?;
+        let result = published(result).await;
+        self.monitor.record(&result);
+        result
+    }
+
+    fn connection_events(&self) -> Option<broadcast::Receiver<ConnectionEvent>> {
+        Some(self.monitor.subscribe())
+    }
+
+    fn health(&self) -> BrokerHealth {
+        self.monitor.health()
+    }
+
+    async fn subscribe_to_topic(&self, subject: &str) -> Result<Receiver> {
+        if let Some(sender) = self.map.get(subject) {
+            Ok(sender.0.subscribe())
+        } else {
+            let sender = create_channel(100);
+            self.map
+                .insert(subject.to_string(), Subscription(sender.clone()));
+            self.subscription_handler
+                .send((subject.to_string(), sender.clone()))
+                .await?;
+            Ok(sender.subscribe())
+        }
+    }
+
+    async fn subscribe_to_queue(&self, topic: &str, group: &str) -> Result<QueueReceiver> {
+        let sender = create_channel(100);
+        self.queue_handler
+            .send((topic.to_string(), group.to_string(), sender.clone()))
+            .await?;
+        Ok(sender.subscribe())
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        let (done, closed) = oneshot::channel();
+        self.shutdown_handler.send(done).await?;
+        closed.await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{to_topic_pattern, KafkaConnectionInfo, KafkaSasl, PartitionOffsets};
+
+    #[test]
+    fn plain_subjects_are_used_as_topic_names() {
+        assert_eq!(to_topic_pattern("hello.world"), "hello.world");
+    }
+
+    #[test]
+    fn wildcard_subjects_become_patterns() {
+        assert_eq!(to_topic_pattern("hello.*"), "^hello\\.[^.]+$");
+        assert_eq!(to_topic_pattern("hello.>"), "^hello\\..+$");
+        assert_eq!(to_topic_pattern("my-app.*"), "^my-app\\.[^.]+$");
+    }
+
+    #[test]
+    fn offsets_are_only_committed_once_earlier_messages_are_done() {
+        let mut offsets = PartitionOffsets::default();
+        offsets.received(0);
+        offsets.received(1);
+        offsets.received(2);
+
+        assert_eq!(offsets.completed(1), None);
+        assert_eq!(offsets.completed(0), Some(2));
+        assert_eq!(offsets.completed(2), Some(3));
+    }
+
+    #[test]
+    fn sasl_over_tls_uses_sasl_ssl() {
+        let options = KafkaConnectionInfo {
+            brokers: vec!["a:9092".to_string(), "b:9092".to_string()],
+            tls: Some(true),
+            sasl: Some(KafkaSasl {
+                mechanism: "PLAIN".to_string(),
+                username: "user".to_string(),
+                password: "password".to_string(),
+            }),
+            ..Default::default()
+        };
+
+        let config = options.client_config();
+
+        assert_eq!(config.get("bootstrap.servers"), Some("a:9092,b:9092"));
+        assert_eq!(config.get("security.protocol"), Some("SASL_SSL"));
+        assert_eq!(config.get("sasl.mechanisms"), Some("PLAIN"));
+    }
+}
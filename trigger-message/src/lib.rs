@@ -1,8 +1,22 @@
+pub mod amqp_broker;
+pub mod auth;
 pub mod broker;
 pub mod configs;
+pub mod connection;
+pub mod file_broker;
 pub mod gateway;
 pub mod in_memory_broker;
+pub mod kafka_broker;
+pub mod limits;
 pub mod message_trigger;
+pub mod metrics;
 pub mod mqtt_broker;
 pub mod nats_broker;
+pub mod ordering;
+pub mod postgres_broker;
 pub mod redis_broker;
+pub mod retry;
+pub mod shutdown;
+pub mod telemetry;
+pub mod tls;
+pub mod work_queue;
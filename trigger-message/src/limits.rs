@@ -0,0 +1,217 @@
+use std::{net::IpAddr, num::NonZeroU32, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
+use governor::{
+    clock::{Clock, DefaultClock},
+    DefaultKeyedRateLimiter, Quota,
+};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use crate::configs::{
+    GatewayBodyLimitConfig, GatewayCorsConfig, GatewayRateLimitConfig, RateLimit,
+};
+
+const DEFAULT_CORS_METHODS: [&str; 2] = ["GET", "POST"];
+const DEFAULT_CORS_HEADERS: [&str; 4] = [
+    "content-type",
+    "authorization",
+    "x-api-key",
+    "last-event-id",
+];
+/// How often the rate limits of clients that haven't been seen in a while are forgotten
+const RATE_LIMIT_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The CORS, body size & rate limit policies for a gateway
+#[derive(Clone, Default)]
+pub struct GatewayLimits {
+    pub cors: Option<CorsLayer>,
+    pub max_body_size: Option<usize>,
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+impl GatewayLimits {
+    pub fn new(
+        cors: Option<&GatewayCorsConfig>,
+        body_limit: Option<&GatewayBodyLimitConfig>,
+        rate_limit: Option<&GatewayRateLimitConfig>,
+    ) -> Result<Self> {
+        Ok(Self {
+            cors: cors.map(cors_layer).transpose()?,
+            max_body_size: body_limit.map(|limit| limit.max_bytes),
+            rate_limiter: rate_limit.map(RateLimiter::new).transpose()?,
+        })
+    }
+}
+
+fn cors_layer(config: &GatewayCorsConfig) -> Result<CorsLayer> {
+    if config.origins.is_empty() {
+        bail!("CORS needs at least one origin");
+    }
+    let origins = match cors_list::<HeaderValue>("origin", &config.origins, &[])? {
+        Some(origins) => AllowOrigin::list(origins),
+        None => AllowOrigin::any(),
+    };
+    let methods = match cors_list::<Method>("method", &config.methods, &DEFAULT_CORS_METHODS)? {
+        Some(methods) => AllowMethods::list(methods),
+        None => AllowMethods::any(),
+    };
+    let headers = match cors_list::<HeaderName>("header", &config.headers, &DEFAULT_CORS_HEADERS)? {
+        Some(headers) => AllowHeaders::list(headers),
+        None => AllowHeaders::any(),
+    };
+    let mut layer = CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_headers(headers);
+    if let Some(max_age) = config.max_age {
+        layer = layer.max_age(Duration::from_secs(max_age));
+    }
+    Ok(layer)
+}
+
+/// Parses a list of CORS values, falling back to the defaults if it's empty. `None` means "*", allowing anything.
+fn cors_list<T>(kind: &str, values: &[String], defaults: &[&str]) -> Result<Option<Vec<T>>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let values: Vec<&str> = if values.is_empty() {
+        defaults.to_vec()
+    } else {
+        values.iter().map(String::as_str).collect()
+    };
+    if values.contains(&"*") {
+        return Ok(None);
+    }
+    values
+        .into_iter()
+        .map(|value| {
+            value
+                .parse()
+                .with_context(|| format!("Invalid CORS {kind} {value}"))
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+/// Keeps track of how many requests each client IP & identity has made
+#[derive(Clone)]
+pub struct RateLimiter {
+    per_ip: Option<Arc<DefaultKeyedRateLimiter<IpAddr>>>,
+    per_key: Option<Arc<DefaultKeyedRateLimiter<String>>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &GatewayRateLimitConfig) -> Result<Self> {
+        Ok(Self {
+            per_ip: config.per_ip.as_ref().map(limiter).transpose()?,
+            per_key: config.per_key.as_ref().map(limiter).transpose()?,
+        })
+    }
+
+    /// Counts a request from an IP, returning how long it should wait if it's over the limit
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), Duration> {
+        match &self.per_ip {
+            Some(limiter) => check(limiter, &ip),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a request from an identity, returning how long it should wait if it's over the limit
+    pub fn check_key(&self, key: &str) -> Result<(), Duration> {
+        match &self.per_key {
+            Some(limiter) => check(limiter, &key.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /// Periodically forgets clients whose limits have fully replenished, so they don't build up
+    pub async fn clean_up(self) {
+        let mut interval = tokio::time::interval(RATE_LIMIT_CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Some(limiter) = &self.per_ip {
+                limiter.retain_recent();
+            }
+            if let Some(limiter) = &self.per_key {
+                limiter.retain_recent();
+            }
+        }
+    }
+}
+
+fn limiter<K>(limit: &RateLimit) -> Result<Arc<DefaultKeyedRateLimiter<K>>>
+where
+    K: Clone + Eq + std::hash::Hash,
+{
+    Ok(Arc::new(DefaultKeyedRateLimiter::keyed(quota(limit)?)))
+}
+
+fn quota(limit: &RateLimit) -> Result<Quota> {
+    let per_second =
+        NonZeroU32::new(limit.per_second).context("Rate limits need a per_second above 0")?;
+    let burst = match limit.burst {
+        Some(burst) => NonZeroU32::new(burst).context("Rate limit bursts need to be above 0")?,
+        None => per_second,
+    };
+    Ok(Quota::per_second(per_second).allow_burst(burst))
+}
+
+fn check<K>(limiter: &DefaultKeyedRateLimiter<K>, key: &K) -> Result<(), Duration>
+where
+    K: Clone + Eq + std::hash::Hash,
+{
+    limiter
+        .check_key(key)
+        .map_err(|not_until| not_until.wait_time_from(DefaultClock::default().now()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{GatewayLimits, RateLimiter};
+    use crate::configs::{GatewayCorsConfig, GatewayRateLimitConfig, RateLimit};
+
+    #[test]
+    fn clients_are_limited_separately() {
+        let limiter = RateLimiter::new(&GatewayRateLimitConfig {
+            per_ip: Some(RateLimit {
+                per_second: 1,
+                burst: Some(2),
+            }),
+            per_key: None,
+        })
+        .unwrap();
+        let first = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let second = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        assert!(limiter.check_ip(first).is_ok());
+        assert!(limiter.check_ip(first).is_ok());
+        assert!(limiter.check_ip(first).is_err());
+        assert!(limiter.check_ip(second).is_ok());
+        assert!(limiter.check_key("anyone").is_ok());
+    }
+
+    #[test]
+    fn invalid_limits_are_rejected() {
+        let cors = |origins: &[&str]| GatewayCorsConfig {
+            origins: origins.iter().map(|o| o.to_string()).collect(),
+            ..Default::default()
+        };
+        assert!(GatewayLimits::new(Some(&cors(&["https://example.com"])), None, None).is_ok());
+        assert!(GatewayLimits::new(Some(&cors(&["*"])), None, None).is_ok());
+        assert!(GatewayLimits::new(Some(&cors(&[])), None, None).is_err());
+        assert!(GatewayLimits::new(Some(&cors(&["bad\norigin"])), None, None).is_err());
+
+        let rate_limit = GatewayRateLimitConfig {
+            per_key: Some(RateLimit {
+                per_second: 0,
+                burst: None,
+            }),
+            ..Default::default()
+        };
+        assert!(GatewayLimits::new(None, None, Some(&rate_limit)).is_err());
+    }
+}
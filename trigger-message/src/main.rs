@@ -4,8 +4,6 @@ use trigger_message::message_trigger::*;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    println!("Setting up Message Trigger");
     let t = Command::parse();
-    println!("Parsed command");
     t.run().await
 }
@@ -1,15 +1,34 @@
-use crate::broker::MessageBroker;
+use crate::auth::Authenticator;
+use crate::broker::{Acknowledgement, Delivery, MessageBroker};
 use crate::configs::*;
-use anyhow::bail;
+use crate::connection::HealthState;
+use anyhow::{bail, Context};
 
 use crate::gateway::spawn_gateway;
+use crate::limits::GatewayLimits;
+use crate::metrics::{metrics, serve_metrics, subscription_pattern, MeteredBroker};
+use crate::ordering::{partition, KeyExtractor};
+use crate::retry;
+use crate::shutdown::{Shutdown, DEFAULT_DRAIN_TIMEOUT};
+use crate::telemetry::{self, TracedBroker};
+use crate::tls::rustls_config;
+use crate::work_queue::{WorkQueue, DEFAULT_QUEUE_SIZE};
 
 use serde::{Deserialize, Serialize};
 use spin_app::MetadataKey;
-use spin_message_types::export::{InternalMessage, Outcome};
+use spin_core::EngineBuilder;
+use spin_message_types::export::{
+    BrokerHost, InternalMessage, InternalOutputMessage, Outcome, SpinMessageTriggerHost,
+};
 use spin_trigger::EitherInstance;
 use spin_trigger::{cli::TriggerExecutorCommand, TriggerAppEngine, TriggerExecutor};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 use spin_message_types::{InputMessage, OutputMessage};
 
@@ -18,16 +37,124 @@ use spin_message_types::{InputMessage, OutputMessage};
 pub struct MessageMetadata {
     r#type: String,
     brokers: HashMap<String, BrokerConfig>,
+    #[serde(default)]
+    telemetry: Option<TelemetryConfig>,
+    /// How long running handlers get to finish when shutting down, in milliseconds
+    #[serde(default)]
+    drain_timeout: Option<u64>,
+    /// How long publishes from components wait for the broker to accept them, in milliseconds
+    #[serde(default)]
+    publish_timeout: Option<u64>,
+    /// Serves the trigger's metrics on this port, separately from any gateway
+    #[serde(default)]
+    metrics_port: Option<u16>,
 }
 
+pub type Brokers = Arc<HashMap<String, Arc<dyn MessageBroker>>>;
+
 pub struct MessageTrigger {
     engine: TriggerAppEngine<Self>,
-    brokers: HashMap<String, Arc<dyn MessageBroker>>,
+    brokers: Brokers,
     components: Vec<MessageTriggerConfig>,
+    /// The ordering key of each component, in the same order as `components`
+    ordering: Vec<Option<KeyExtractor>>,
+    shutdown: Shutdown,
+    publish_timeout: Duration,
 }
 
 pub type Command = TriggerExecutorCommand<MessageTrigger>;
-pub type RuntimeData = ();
+
+const DEFAULT_REQUEST_TIMEOUT: u64 = 2000;
+const DEFAULT_PUBLISH_TIMEOUT: u64 = 2000;
+
+/// Per-instance data backing the `broker` host interface, giving components
+/// access to the trigger's named brokers while they run.
+#[derive(Default)]
+pub struct RuntimeData {
+    brokers: Brokers,
+    default_broker: Option<String>,
+    publish_timeout: Duration,
+}
+
+impl RuntimeData {
+    fn get_broker(&self, broker: Option<&str>) -> anyhow::Result<&Arc<dyn MessageBroker>> {
+        let Some(broker) = broker.or(self.default_broker.as_deref()) else {
+            bail!("No broker set");
+        };
+        match self.brokers.get(broker) {
+            Some(broker) => Ok(broker),
+            None => bail!("No such broker - {broker}"),
+        }
+    }
+
+    async fn publish_message(&self, message: OutputMessage) -> anyhow::Result<()> {
+        let broker = self.get_broker(message.broker.as_deref())?;
+        // Publishes wait while the broker reconnects, so the guest isn't blocked forever if it doesn't
+        match tokio::time::timeout(self.publish_timeout, broker.publish(message)).await {
+            Ok(result) => result,
+            Err(_) => bail!(
+                "Timed out waiting for {} to accept the message",
+                broker.name()
+            ),
+        }
+    }
+
+    async fn request_message(
+        &self,
+        message: OutputMessage,
+        timeout: Option<u64>,
+    ) -> anyhow::Result<InputMessage> {
+        let broker = self.get_broker(message.broker.as_deref())?;
+        let timeout = Duration::from_millis(timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT));
+        match tokio::time::timeout(timeout, broker.request(message)).await {
+            Ok(result) => result,
+            Err(_) => {
+                metrics()
+                    .request_timeouts
+                    .with_label_values(&[broker.name(), "component"])
+                    .inc();
+                bail!("Request timed out")
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BrokerHost for RuntimeData {
+    async fn publish(
+        &mut self,
+        message: InternalOutputMessage,
+    ) -> anyhow::Result<Result<(), String>> {
+        Ok(self
+            .publish_message(message.into())
+            .await
+            .map_err(|e| e.to_string()))
+    }
+
+    async fn publish_all(
+        &mut self,
+        messages: Vec<InternalOutputMessage>,
+    ) -> anyhow::Result<Result<(), String>> {
+        for message in messages.into_iter() {
+            if let Err(e) = self.publish_message(message.into()).await {
+                return Ok(Err(e.to_string()));
+            }
+        }
+        Ok(Ok(()))
+    }
+
+    async fn request(
+        &mut self,
+        message: InternalOutputMessage,
+        timeout: Option<u64>,
+    ) -> anyhow::Result<Result<InternalMessage, String>> {
+        Ok(self
+            .request_message(message.into(), timeout)
+            .await
+            .map(|v| v.into())
+            .map_err(|e| e.to_string()))
+    }
+}
 
 const TRIGGER_METADATA_KEY: MetadataKey<MessageMetadata> = MetadataKey::new("trigger");
 
@@ -41,16 +168,37 @@ impl TriggerExecutor for MessageTrigger {
 
     type RunConfig = spin_trigger::cli::NoArgs;
 
+    fn configure_engine(builder: &mut EngineBuilder<Self::RuntimeData>) -> anyhow::Result<()> {
+        builder.link_import(|linker, get| SpinMessageTriggerHost::add_to_linker(linker, get))
+    }
+
     async fn new(engine: TriggerAppEngine<Self>) -> anyhow::Result<Self> {
-        println!("Getting metadata - let's see what it is...");
         let metadata = engine.app().require_metadata(TRIGGER_METADATA_KEY)?;
-        println!("Getting Trigger Configs");
-        let components = engine
+        telemetry::init(metadata.telemetry.as_ref())?;
+        if let Some(port) = metadata.metrics_port {
+            tokio::spawn(serve_metrics(port));
+        }
+        let components: Vec<MessageTriggerConfig> = engine
             .trigger_configs()
             .map(|(_, config)| config.clone())
             .collect();
-        println!("Setting Up Brokers");
-        let brokers = metadata
+        let mut ordering = Vec::with_capacity(components.len());
+        for config in &components {
+            if config.overflow == Some(OverflowPolicy::DeadLetter) && config.dead_letter.is_none() {
+                bail!(
+                    "{} uses the DeadLetter overflow policy, but has no dead letter subject",
+                    config.component
+                );
+            }
+            let keys = config
+                .ordering
+                .as_ref()
+                .map(KeyExtractor::new)
+                .transpose()
+                .with_context(|| format!("{} has an invalid ordering key", config.component))?;
+            ordering.push(keys);
+        }
+        let brokers: HashMap<_, _> = metadata
             .brokers
             .iter()
             .map(
@@ -61,85 +209,327 @@ impl TriggerExecutor for MessageTrigger {
                         gateway,
                     },
                 )| {
-                    println!(
-                        "Setting up {key} - with broker {broker_type:?} and gateway {gateway:?}"
-                    );
+                    info!(broker = %key, ?broker_type, ?gateway, "Setting up broker");
                     let key = key.clone();
                     let broker: Arc<dyn MessageBroker> = match broker_type {
                         BrokerTypeConfig::InMemoryBroker => {
                             Arc::new(crate::in_memory_broker::InMemoryBroker::new(key.clone()))
                         }
+                        BrokerTypeConfig::File(options) => Arc::new(
+                            crate::file_broker::FileBroker::new(options.clone(), key.clone())?,
+                        ),
                         BrokerTypeConfig::Redis(address) => Arc::new(
                             crate::redis_broker::RedisBroker::new(address.clone(), key.clone()),
                         ),
+                        BrokerTypeConfig::RedisStreams(options) => {
+                            Arc::new(crate::redis_broker::RedisBroker::new_with_streams(
+                                options.clone(),
+                                key.clone(),
+                            ))
+                        }
                         BrokerTypeConfig::Nats(options) => Arc::new(
                             crate::nats_broker::NatsBroker::new(options.clone(), key.clone()),
                         ),
                         BrokerTypeConfig::Mqtt(options) => Arc::new(
                             crate::mqtt_broker::MqttBroker::new(options.clone(), key.clone()),
                         ),
+                        BrokerTypeConfig::Amqp(options) => Arc::new(
+                            crate::amqp_broker::AmqpBroker::new(options.clone(), key.clone()),
+                        ),
+                        BrokerTypeConfig::Kafka(options) => Arc::new(
+                            crate::kafka_broker::KafkaBroker::new(options.clone(), key.clone()),
+                        ),
+                        BrokerTypeConfig::Postgres(options) => {
+                            Arc::new(crate::postgres_broker::PostgresBroker::new(
+                                options.clone(),
+                                key.clone(),
+                            ))
+                        }
                     };
+                    let broker = TracedBroker::wrap(MeteredBroker::wrap(broker));
                     if let GatewayConfig::Http {
                         port,
                         websockets,
                         request_response,
                         timeout,
+                        auth,
+                        tls,
+                        cors,
+                        body_limit,
+                        rate_limit,
                     } = gateway
                     {
+                        let auth = auth.as_ref().map(Authenticator::new).transpose()?;
+                        let tls = tls.as_ref().map(rustls_config).transpose()?;
+                        let limits = GatewayLimits::new(
+                            cors.as_ref(),
+                            body_limit.as_ref(),
+                            rate_limit.as_ref(),
+                        )?;
                         tokio::spawn(spawn_gateway(
                             *port,
                             websockets.clone(),
                             broker.clone(),
                             request_response.clone(),
                             *timeout,
+                            auth,
+                            tls,
+                            limits,
                         ));
                     }
-                    println!("Broker and gateway for key {key} complete");
-                    (key, broker)
+                    Ok((key, broker))
                 },
             )
-            .collect();
+            .collect::<anyhow::Result<_>>()?;
+        let drain_timeout = metadata.drain_timeout.unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+        let publish_timeout = metadata.publish_timeout.unwrap_or(DEFAULT_PUBLISH_TIMEOUT);
         Ok(Self {
             engine,
             components,
-            brokers,
+            ordering,
+            brokers: Arc::new(brokers),
+            shutdown: Shutdown::new(Duration::from_millis(drain_timeout)),
+            publish_timeout: Duration::from_millis(publish_timeout),
         })
     }
 
     async fn run(self, _config: Self::RunConfig) -> anyhow::Result<()> {
-        println!("Running message trigger");
-        tokio::spawn(async move {
-            tokio::signal::ctrl_c().await.unwrap();
-            std::process::exit(0);
-        });
-
-        tokio_scoped::scope(|scope| {
-            // For each component, run its own timer loop
-            for config in &self.components {
-                scope.spawn(async {
-                    let config = config.clone();
-                    let rx = if let Some(broker) = self.brokers.get(&config.broker) {
-                        broker.subscribe(&config.subscription).await.ok()
-                    } else {
-                        None
-                    };
+        info!("Running message trigger");
+        self.shutdown.listen_for_signals();
 
-                    if let Some(mut rx) = rx {
-                        while let Ok(message) = rx.recv().await {
-                            println!("Got message {message:?}");
-                            if let Err(e) = self.handle_message(&config, message).await {
-                                eprintln!("Error handling message: {e:?}");
+        for (name, broker) in self.brokers.iter() {
+            let Some(mut events) = broker.connection_events() else {
+                continue;
+            };
+            let name = name.clone();
+            let broker = broker.clone();
+            tokio::spawn(async move {
+                let mut state = broker.health().state;
+                info!(broker = %name, %state, "Broker health");
+                loop {
+                    match events.recv().await {
+                        Ok(event) => {
+                            let current = broker.health().state;
+                            if current == state {
+                                info!(broker = %name, %event, "Broker connection event");
+                            } else if current == HealthState::Connected {
+                                info!(broker = %name, from = %state, %event, "Broker recovered");
+                            } else {
+                                warn!(broker = %name, from = %state, to = %current, %event, "Broker health changed");
                             }
+                            state = current;
                         }
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
                     }
-                });
+                }
+            });
+        }
+
+        let queues: Vec<Vec<WorkQueue>> = self
+            .components
+            .iter()
+            .map(|config| {
+                let instances = config.max_concurrency.unwrap_or(1).max(1);
+                let queue_size = config.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE);
+                let policy = config.overflow.unwrap_or_default();
+                if config.ordering.is_some() {
+                    // Each instance gets its own share of the queue, and messages with the same key always go to the same one
+                    (0..instances)
+                        .map(|_| WorkQueue::new(queue_size.div_ceil(instances), policy))
+                        .collect()
+                } else {
+                    vec![WorkQueue::new(queue_size, policy)]
+                }
+            })
+            .collect();
+
+        tokio_scoped::scope(|scope| {
+            // Each component has a loop receiving messages into its queues,
+            // and a worker for each message it can handle at once
+            for ((config, queues), keys) in self.components.iter().zip(&queues).zip(&self.ordering)
+            {
+                scope.spawn(self.receive(config, queues, keys.as_ref()));
+                let instances = config.max_concurrency.unwrap_or(1).max(1);
+                for queue in queues {
+                    for _ in 0..instances / queues.len() {
+                        scope.spawn(self.work(config, queue));
+                    }
+                }
             }
         });
+
+        // Every component has stopped, so anything they published can be flushed
+        let timeout = self.shutdown.drain_timeout();
+        futures::future::join_all(self.brokers.iter().map(|(name, broker)| async move {
+            match tokio::time::timeout(timeout, broker.shutdown()).await {
+                Ok(Ok(())) => info!(broker = %name, "Broker shut down"),
+                Ok(Err(e)) => error!(broker = %name, "Error shutting down broker - {e:?}"),
+                Err(_) => warn!(broker = %name, "Timed out shutting down broker"),
+            }
+        }))
+        .await;
+        telemetry::shutdown();
         Ok(())
     }
 }
 
 impl MessageTrigger {
+    async fn receive(
+        &self,
+        config: &MessageTriggerConfig,
+        queues: &[WorkQueue],
+        keys: Option<&KeyExtractor>,
+    ) {
+        let rx = match self.brokers.get(&config.broker) {
+            Some(broker) => broker.subscribe(&config.subscription).await,
+            None => Err(anyhow::anyhow!("No such broker")),
+        };
+        let mut rx = match rx {
+            Ok(rx) => rx,
+            Err(e) => {
+                error!(
+                    component = %config.component,
+                    broker = %config.broker,
+                    "Couldn't subscribe - {e:?}"
+                );
+                queues.iter().for_each(WorkQueue::close);
+                return;
+            }
+        };
+
+        let subscription = subscription_pattern(&config.subscription);
+        loop {
+            let received = tokio::select! {
+                biased;
+                _ = self.shutdown.started() => break,
+                received = rx.recv() => received,
+            };
+            let delivery = match received {
+                Ok(delivery) => delivery,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        component = %config.component,
+                        broker = %config.broker,
+                        skipped,
+                        "Component fell behind - messages were dropped"
+                    );
+                    metrics()
+                        .lagged_messages
+                        .with_label_values(&[&config.broker, &config.component])
+                        .inc_by(skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            metrics()
+                .messages_received
+                .with_label_values(&[&config.broker, &subscription, &config.component])
+                .inc();
+            let queue = match keys {
+                Some(keys) => &queues[partition(keys.key(&delivery).as_deref(), queues.len())],
+                None => &queues[0],
+            };
+            if let Some(overflowed) = queue.push(delivery).await {
+                self.overflowed(config, queue.policy(), overflowed).await;
+            }
+        }
+        queues.iter().for_each(WorkQueue::close);
+    }
+
+    /// Deals with a message that didn't fit in the component's queue
+    async fn overflowed(
+        &self,
+        config: &MessageTriggerConfig,
+        policy: OverflowPolicy,
+        delivery: Delivery,
+    ) {
+        warn!(
+            component = %config.component,
+            subject = %delivery.input.subject,
+            ?policy,
+            "Component queue is full"
+        );
+        metrics()
+            .overflowed_messages
+            .with_label_values(&[&config.component, &format!("{policy:?}")])
+            .inc();
+        let ack = match (policy, &config.dead_letter) {
+            (OverflowPolicy::DeadLetter, Some(dead_letter)) => {
+                let error = anyhow::anyhow!("The component's queue was full");
+                match self
+                    .send_to_dead_letter(config, dead_letter, delivery.input.clone(), &error, 0)
+                    .await
+                {
+                    Ok(()) => Acknowledgement::Ack,
+                    Err(e) => {
+                        error!(
+                            component = %config.component,
+                            dead_letter = %dead_letter.subject,
+                            "Error sending message to dead letter: {e:?}"
+                        );
+                        Acknowledgement::Nack { requeue: true }
+                    }
+                }
+            }
+            _ => Acknowledgement::Nack { requeue: false },
+        };
+        self.settle(config, &delivery, ack).await;
+    }
+
+    async fn work(&self, config: &MessageTriggerConfig, queue: &WorkQueue) {
+        while let Some(delivery) = queue.next().await {
+            // Messages that were queued but not started yet go back to the broker
+            if self.shutdown.is_started() {
+                self.settle(config, &delivery, Acknowledgement::Nack { requeue: true })
+                    .await;
+                continue;
+            }
+            debug!(
+                component = %config.component,
+                broker = %config.broker,
+                subject = %delivery.input.subject,
+                request_id = telemetry::request_id(&delivery.input.subject),
+                payload = %telemetry::payload(&delivery.input.message),
+                "Got message"
+            );
+            let ack = tokio::select! {
+                ack = self.handle_with_retries(config, delivery.input.clone()) => ack,
+                _ = self.shutdown.drain_expired() => {
+                    warn!(
+                        component = %config.component,
+                        broker = %config.broker,
+                        subject = %delivery.input.subject,
+                        "Drain timeout ran out - abandoning message"
+                    );
+                    Acknowledgement::Nack { requeue: true }
+                }
+            };
+            self.settle(config, &delivery, ack).await;
+        }
+    }
+
+    /// Settles a delivery with its broker, giving up once the drain timeout runs out
+    async fn settle(
+        &self,
+        config: &MessageTriggerConfig,
+        delivery: &Delivery,
+        ack: Acknowledgement,
+    ) {
+        let result = tokio::select! {
+            result = delivery.acknowledge(ack) => result,
+            _ = self.shutdown.drain_expired() => Err(anyhow::anyhow!("the drain timeout ran out")),
+        };
+        if let Err(e) = result {
+            error!(
+                component = %config.component,
+                broker = %config.broker,
+                subject = %delivery.input.subject,
+                "Error acknowledging message: {e:?}"
+            );
+        }
+    }
+
     async fn send_with_broker(
         &self,
         broker: &str,
@@ -163,25 +553,110 @@ impl MessageTrigger {
     ) -> anyhow::Result<()> {
         for msg in msgs.into_iter() {
             if let Err(e) = self.send_with_broker(broker, subject, msg).await {
-                eprintln!("Error sending message: {e:?}");
+                error!(broker, subject, "Error sending message: {e:?}");
             }
         }
         Ok(())
     }
 
-    async fn handle_message(
+    /// Handles the message, retrying failures based on the component's retry policy.
+    /// Once retries run out, the message is sent to the dead letter subject if one is set.
+    async fn handle_with_retries(
         &self,
         config: &MessageTriggerConfig,
         message: InputMessage,
+    ) -> Acknowledgement {
+        let retry = config.retry.clone().unwrap_or_default();
+        let dead_letter = config.dead_letter.as_ref().map(|dead_letter| {
+            let message = message.clone();
+            move |error: anyhow::Error, attempts: u32| async move {
+                let result = self
+                    .send_to_dead_letter(config, dead_letter, message, &error, attempts)
+                    .await;
+                if let Err(e) = &result {
+                    error!(
+                        component = %config.component,
+                        dead_letter = %dead_letter.subject,
+                        "Error sending message to dead letter: {e:?}"
+                    );
+                }
+                result
+            }
+        });
+        let max_attempts = retry.max_attempts;
+        let handle = |attempt: u32| {
+            let message = message.clone();
+            async move {
+                let subject = message.subject.clone();
+                let result = self.handle_message(config, message).await;
+                if let Err(e) = &result {
+                    warn!(
+                        component = %config.component,
+                        %subject,
+                        attempt,
+                        max_attempts,
+                        "Error handling message: {e:?}"
+                    );
+                }
+                result
+            }
+        };
+        retry::with_retries(&retry, handle, dead_letter).await
+    }
+
+    async fn send_to_dead_letter(
+        &self,
+        config: &MessageTriggerConfig,
+        dead_letter: &DeadLetterConfig,
+        message: InputMessage,
+        error: &anyhow::Error,
+        attempts: u32,
     ) -> anyhow::Result<()> {
-        let (instance, mut store) = self.engine.prepare_instance(&config.component).await?;
+        let broker = dead_letter.broker.as_deref().unwrap_or(&config.broker);
+        self.send_with_broker(
+            broker,
+            &dead_letter.subject,
+            retry::dead_letter_message(&config.component, message, error, attempts),
+        )
+        .await
+    }
+
+    async fn handle_message(
+        &self,
+        config: &MessageTriggerConfig,
+        message: InputMessage,
+    ) -> anyhow::Result<Acknowledgement> {
+        let span = info_span!(
+            "handle_message",
+            otel.kind = "consumer",
+            component = %config.component,
+            broker = %config.broker,
+            subject = %message.subject,
+        );
+        telemetry::set_parent_from_message(&span, &message.headers);
+        self.call_component(config, message).instrument(span).await
+    }
+
+    async fn call_component(
+        &self,
+        config: &MessageTriggerConfig,
+        message: InputMessage,
+    ) -> anyhow::Result<Acknowledgement> {
+        let started = Instant::now();
+        let (instance, mut store) = self
+            .engine
+            .prepare_instance(&config.component)
+            .instrument(info_span!("prepare_instance", component = %config.component))
+            .await?;
         let EitherInstance::Component(instance) = instance else {
             unreachable!()
         };
-        println!("Setup instance");
-        let instance =
-            spin_message_types::export::SpinMessageTriggerGuest::new(&mut store, &instance)?;
-        println!("engine ready");
+        *store.data_mut() = RuntimeData {
+            brokers: self.brokers.clone(),
+            default_broker: Some(config.broker.clone()),
+            publish_timeout: self.publish_timeout,
+        };
+        let instance = SpinMessageTriggerHost::new(&mut store, &instance)?;
 
         let original_subject = &message.subject;
 
@@ -190,16 +665,25 @@ impl MessageTrigger {
             message: message.message,
             broker: config.broker.clone(),
             response_subject: message.response_subject,
+            headers: message.headers,
         };
 
-        println!("ready for wasm");
-
         let result = instance
             .guest()
             .call_handle_message(&mut store, &message)
-            .await?;
+            .await;
+        let outcome = match &result {
+            Ok(Outcome::Publish(_)) => "publish",
+            Ok(Outcome::Error(_)) => "error",
+            Ok(Outcome::Ack) => "ack",
+            Ok(Outcome::Nack(_)) => "nack",
+            Ok(Outcome::RetryAfter(_)) => "retry_after",
+            Err(_) => "failed",
+        };
+        metrics().handled(&config.component, outcome, started.elapsed());
+        let result = result?;
 
-        println!("Got result {result:?}");
+        debug!(outcome, "Component handled message");
 
         let default_result_target = match &config.subscription {
             SubscriptionType::Topic { topic: _, result } => result.as_ref(),
@@ -208,10 +692,11 @@ impl MessageTrigger {
                 group: _,
                 result,
             } => result.as_ref(),
+            SubscriptionType::Stream(StreamSubscription { result, .. }) => result.as_ref(),
             _ => None,
         };
 
-        match (result, default_result_target) {
+        let ack = match (result, default_result_target) {
             (
                 Outcome::Publish(msgs),
                 Some(MessageResultType {
@@ -224,7 +709,8 @@ impl MessageTrigger {
                     default_subject,
                     msgs.into_iter().map(|v| v.into()).collect(),
                 )
-                .await?
+                .await?;
+                Acknowledgement::Ack
             }
             (Outcome::Publish(msgs), None) => {
                 self.send_all_with_broker(
@@ -232,10 +718,18 @@ impl MessageTrigger {
                     original_subject,
                     msgs.into_iter().map(|v| v.into()).collect(),
                 )
-                .await?
+                .await?;
+                Acknowledgement::Ack
             }
-            _ => {}
-        }
-        Ok(())
+            (Outcome::Error(e), _) => {
+                bail!("Component {} returned an error: {e}", config.component);
+            }
+            (Outcome::Ack, _) => Acknowledgement::Ack,
+            (Outcome::Nack(requeue), _) => Acknowledgement::Nack { requeue },
+            (Outcome::RetryAfter(delay), _) => {
+                Acknowledgement::RetryAfter(Duration::from_millis(delay))
+            }
+        };
+        Ok(ack)
     }
 }
@@ -0,0 +1,356 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use prometheus::{
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+use spin_message_types::{InputMessage, OutputMessage};
+use tokio::sync::broadcast;
+use tracing::{error, info};
+
+use crate::{
+    broker::{MessageBroker, QueueReceiver, Receiver, Wildcards},
+    configs::{StreamSubscription, SubscriptionType},
+    connection::{BrokerHealth, ConnectionEvent},
+};
+
+const NAMESPACE: &str = "spin_message";
+
+/// The metrics collected by the trigger & gateways
+pub struct Metrics {
+    registry: Registry,
+    pub messages_received: IntCounterVec,
+    pub messages_published: IntCounterVec,
+    pub subscriptions: IntCounterVec,
+    pub requests: IntCounterVec,
+    pub request_timeouts: IntCounterVec,
+    pub lagged_messages: IntCounterVec,
+    pub overflowed_messages: IntCounterVec,
+    pub messages_handled: IntCounterVec,
+    pub handler_duration: HistogramVec,
+    pub gateway_requests: IntCounterVec,
+    pub gateway_duration: HistogramVec,
+}
+
+fn register<T: Collector + Clone + 'static>(registry: &Registry, metric: T) -> Result<T> {
+    registry.register(Box::new(metric.clone()))?;
+    Ok(metric)
+}
+
+fn counter(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> Result<IntCounterVec> {
+    let opts = Opts::new(name, help).namespace(NAMESPACE);
+    register(registry, IntCounterVec::new(opts, labels)?)
+}
+
+fn histogram(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> Result<HistogramVec> {
+    let opts = HistogramOpts::new(name, help).namespace(NAMESPACE);
+    register(registry, HistogramVec::new(opts, labels)?)
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new();
+        Ok(Self {
+            messages_received: counter(
+                &registry,
+                "messages_received_total",
+                "Messages delivered to components, by the subscription they matched",
+                &["broker", "subscription", "component"],
+            )?,
+            messages_published: counter(
+                &registry,
+                "messages_published_total",
+                "Messages published to brokers",
+                &["broker", "subject", "outcome"],
+            )?,
+            subscriptions: counter(
+                &registry,
+                "subscriptions_total",
+                "Subscriptions made on brokers",
+                &["broker", "kind", "outcome"],
+            )?,
+            requests: counter(
+                &registry,
+                "requests_total",
+                "Request/response calls made through brokers",
+                &["broker", "outcome"],
+            )?,
+            request_timeouts: counter(
+                &registry,
+                "request_timeouts_total",
+                "Requests that timed out waiting for a response",
+                &["broker", "source"],
+            )?,
+            lagged_messages: counter(
+                &registry,
+                "lagged_messages_total",
+                "Messages dropped because a subscriber fell too far behind",
+                &["broker", "subscriber"],
+            )?,
+            overflowed_messages: counter(
+                &registry,
+                "overflowed_messages_total",
+                "Messages that didn't fit in a component's queue, by the overflow policy",
+                &["component", "policy"],
+            )?,
+            messages_handled: counter(
+                &registry,
+                "messages_handled_total",
+                "Messages handled by components, by the outcome",
+                &["component", "outcome"],
+            )?,
+            handler_duration: histogram(
+                &registry,
+                "handler_duration_seconds",
+                "How long components took to handle a message",
+                &["component", "outcome"],
+            )?,
+            gateway_requests: counter(
+                &registry,
+                "gateway_requests_total",
+                "Requests received by the HTTP gateways",
+                &["broker", "route", "status"],
+            )?,
+            gateway_duration: histogram(
+                &registry,
+                "gateway_request_duration_seconds",
+                "How long the HTTP gateways took to respond",
+                &["broker", "route"],
+            )?,
+            registry,
+        })
+    }
+
+    /// Records a component handling a message
+    pub fn handled(&self, component: &str, outcome: &str, duration: Duration) {
+        self.messages_handled
+            .with_label_values(&[component, outcome])
+            .inc();
+        self.handler_duration
+            .with_label_values(&[component, outcome])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Renders every metric in the Prometheus text format
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("Metric definitions should be valid"))
+}
+
+pub fn outcome<T>(result: &Result<T>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    }
+}
+
+/// Collapses the parts of a subject that are ids - such as ulids, uuids or numbers -
+/// so publishing to many similar subjects doesn't create a metric for each one
+pub fn subject_pattern(subject: &str) -> String {
+    subject
+        .split('.')
+        .map(|part| if is_id(part) { "*" } else { part })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn is_id(part: &str) -> bool {
+    let is_number = !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+    let is_ulid = part.len() == 26
+        && part
+            .chars()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase());
+    let is_uuid = part.len() == 36 && part.chars().all(|c| c.is_ascii_hexdigit() || c == '-');
+    is_number || is_ulid || is_uuid
+}
+
+/// The subject pattern a subscription listens on, used to label received messages
+pub fn subscription_pattern(subscription: &SubscriptionType) -> String {
+    match subscription {
+        SubscriptionType::Topic { topic, .. } | SubscriptionType::Queue { topic, .. } => {
+            topic.clone()
+        }
+        SubscriptionType::Request { path, method } => {
+            format!("{} {path}", method.as_deref().unwrap_or("*"))
+        }
+        SubscriptionType::Stream(StreamSubscription { stream, topic, .. }) => {
+            topic.clone().unwrap_or_else(|| stream.clone())
+        }
+        SubscriptionType::None => String::new(),
+    }
+}
+
+pub async fn metrics_handler() -> impl IntoResponse {
+    match metrics().render() {
+        Ok(body) => (
+            StatusCode::OK,
+            [(
+                header::CONTENT_TYPE,
+                TextEncoder::new().format_type().to_string(),
+            )],
+            body,
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Failed to render metrics - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Serves the metrics on their own port
+pub async fn serve_metrics(port: u16) {
+    let app = Router::new().route("/metrics", get(metrics_handler));
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    info!(%addr, "Serving metrics");
+    if let Err(e) = axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .await
+    {
+        error!(%addr, "Metrics server failed - {e:?}");
+    }
+}
+
+/// Wraps a broker, recording metrics for everything published or subscribed through it
+pub struct MeteredBroker(Arc<dyn MessageBroker>);
+
+impl MeteredBroker {
+    pub fn wrap(broker: Arc<dyn MessageBroker>) -> Arc<dyn MessageBroker> {
+        Arc::new(Self(broker))
+    }
+
+    fn subscribed<T>(&self, kind: &str, result: Result<T>) -> Result<T> {
+        metrics()
+            .subscriptions
+            .with_label_values(&[self.name(), kind, outcome(&result)])
+            .inc();
+        result
+    }
+}
+
+#[async_trait]
+impl MessageBroker for MeteredBroker {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    async fn publish(&self, message: OutputMessage) -> Result<()> {
+        let subject = message
+            .subject
+            .as_deref()
+            .map(subject_pattern)
+            .unwrap_or_default();
+        let result = self.0.publish(message).await;
+        metrics()
+            .messages_published
+            .with_label_values(&[self.name(), &subject, outcome(&result)])
+            .inc();
+        result
+    }
+
+    async fn subscribe_to_topic(&self, subject: &str) -> Result<Receiver> {
+        let result = self.0.subscribe_to_topic(subject).await;
+        self.subscribed("topic", result)
+    }
+
+    async fn subscribe_to_topic_from(&self, subject: &str, after: u64) -> Result<Option<Receiver>> {
+        self.0.subscribe_to_topic_from(subject, after).await
+    }
+
+    fn sequence(&self, message: &InputMessage) -> Option<u64> {
+        self.0.sequence(message)
+    }
+
+    fn wildcards(&self) -> Wildcards {
+        self.0.wildcards()
+    }
+
+    async fn subscribe_to_queue(&self, topic: &str, group: &str) -> Result<QueueReceiver> {
+        let result = self.0.subscribe_to_queue(topic, group).await;
+        self.subscribed("queue", result)
+    }
+
+    async fn subscribe_to_stream(
+        &self,
+        subscription: &StreamSubscription,
+    ) -> Result<QueueReceiver> {
+        let result = self.0.subscribe_to_stream(subscription).await;
+        self.subscribed("stream", result)
+    }
+
+    fn connection_events(&self) -> Option<broadcast::Receiver<ConnectionEvent>> {
+        self.0.connection_events()
+    }
+
+    fn health(&self) -> BrokerHealth {
+        self.0.health()
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.0.shutdown().await
+    }
+
+    async fn request(&self, request: OutputMessage) -> Result<InputMessage> {
+        let result = self.0.request(request).await;
+        metrics()
+            .requests
+            .with_label_values(&[self.name(), outcome(&result)])
+            .inc();
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{metrics, subject_pattern};
+
+    #[test]
+    fn ids_are_collapsed_in_subject_patterns() {
+        assert_eq!(subject_pattern("orders.created"), "orders.created");
+        assert_eq!(
+            subject_pattern("request.01HGW2N7EHJ8Y5T0E4Z9KXJ6QV.GET.users.42"),
+            "request.*.GET.users.*"
+        );
+        assert_eq!(
+            subject_pattern("jobs.6f1c1c2e-8a8e-4c55-9d4c-3f0a2b1c9e7d.done"),
+            "jobs.*.done"
+        );
+    }
+
+    #[test]
+    fn metrics_are_rendered_as_text() {
+        metrics()
+            .messages_published
+            .with_label_values(&["test", "greetings", "ok"])
+            .inc();
+        let rendered = metrics().render().unwrap();
+        let line = rendered
+            .lines()
+            .find(|line| {
+                line.starts_with("spin_message_messages_published_total{")
+                    && line.contains(r#"subject="greetings""#)
+            })
+            .unwrap();
+        assert!(line.contains(r#"broker="test""#));
+        assert!(line.ends_with(" 1"));
+    }
+}
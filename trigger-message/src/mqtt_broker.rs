@@ -1,18 +1,42 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
 
-use rumqttc::{AsyncClient, ClientError, EventLoop, MqttOptions};
+use dashmap::DashMap;
+use rumqttc::{
+    v5::{
+        self,
+        mqttbytes::v5::{ConnectProperties, PubAckReason, PubRecReason, PublishProperties},
+    },
+    AsyncClient, EventLoop, MqttOptions, Outgoing, Publish, Transport,
+};
 use serde::{Deserialize, Serialize};
 use spin_message_types::OutputMessage;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{error, info, trace, warn};
 
 use crate::{
-    broker::{MessageBroker, QueueReceiver, Receiver, Sender},
+    broker::{Acker, Acknowledgement, MessageBroker, QueueReceiver, Receiver, Sender},
+    configs::ReconnectConfig,
+    connection::{BrokerHealth, ConnectionEvent, ConnectionMonitor, PublishBuffer, Reconnector},
     in_memory_broker::InMemoryBroker,
 };
 
+/// Publishing with this header overrides the QoS level (0, 1 or 2) for that message
+pub const QOS_HEADER: &str = "mqtt-qos";
+/// Publishing with this header set to `true` publishes a retained message
+pub const RETAIN_HEADER: &str = "mqtt-retain";
+/// In v5 mode, holds the correlation data of received messages. Publishing with it sets the correlation data.
+pub const CORRELATION_DATA_HEADER: &str = "mqtt-correlation-data";
+
 #[derive(Clone, Debug)]
 pub struct Subscription(Sender);
 
@@ -22,7 +46,7 @@ pub struct MqttBroker {
     local_broker: Arc<InMemoryBroker>,
     subscription_handler: mpsc::Sender<String>,
     queue_handler: mpsc::Sender<(String, String)>,
-    publish_handler: mpsc::Sender<(String, OutputMessage)>,
+    publish_buffer: Arc<PublishBuffer>,
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -31,31 +55,618 @@ pub struct MqttCredentials {
     password: String,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MqttProtocol {
+    #[default]
+    V4,
+    V5,
+}
+
+/// How messages are carried in MQTT payloads
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MqttPayloadMode {
+    /// The whole message, including its subject & headers, is encoded with messagepack
+    #[default]
+    Envelope,
+    /// The payload is the message body, and the subject comes from the MQTT topic
+    Raw,
+}
+
+impl MqttPayloadMode {
+    fn encode(&self, message: &OutputMessage) -> Result<Vec<u8>> {
+        match self {
+            MqttPayloadMode::Envelope => Ok(rmp_serde::to_vec(message)?),
+            MqttPayloadMode::Raw => Ok(message.message.clone()),
+        }
+    }
+
+    fn decode(&self, topic: &str, payload: Vec<u8>) -> Result<OutputMessage> {
+        match self {
+            MqttPayloadMode::Envelope => Ok(rmp_serde::from_slice(&payload)?),
+            MqttPayloadMode::Raw => Ok(OutputMessage {
+                message: payload,
+                subject: Some(topic.replace('/', ".")),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MqttLastWill {
+    subject: String,
+    message: String,
+    qos: Option<u8>,
+    retain: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MqttTls {
+    ca: Option<String>,
+    client_certificate: Option<String>,
+    client_key: Option<String>,
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct MqttConnectionInfo {
     address: String,
     id: Option<String>,
     keep_alive: Option<f32>,
     credentials: Option<MqttCredentials>,
+    manual_acks: Option<bool>,
+    protocol: Option<MqttProtocol>,
+    payload: Option<MqttPayloadMode>,
+    qos: Option<u8>,
+    retain: Option<bool>,
+    subscription_qos: Option<HashMap<String, u8>>,
+    last_will: Option<MqttLastWill>,
+    clean_session: Option<bool>,
+    session_expiry: Option<u32>,
+    tls: Option<MqttTls>,
+    reconnect: Option<ReconnectConfig>,
+    /// Only complete QoS 1 & 2 publishes once the server acknowledges them
+    publisher_confirms: Option<bool>,
+}
+
+fn to_mqtt_topic(subject: &str) -> String {
+    subject.replace('.', "/").replace('*', "+")
+}
+
+fn v4_qos(level: u8) -> Result<rumqttc::QoS> {
+    rumqttc::qos(level).map_err(|_| anyhow!("Invalid MQTT QoS {level}"))
+}
+
+fn v5_qos(level: u8) -> Result<v5::mqttbytes::QoS> {
+    v5::mqttbytes::qos(level).ok_or_else(|| anyhow!("Invalid MQTT QoS {level}"))
+}
+
+impl MqttTls {
+    fn transport(&self) -> Result<Transport> {
+        let client_auth = match (&self.client_certificate, &self.client_key) {
+            (Some(cert), Some(key)) => Some((std::fs::read(cert)?, std::fs::read(key)?)),
+            (None, None) => None,
+            _ => bail!("MQTT client certificates need both a certificate and a key"),
+        };
+        match (&self.ca, client_auth) {
+            (Some(ca), client_auth) => Ok(Transport::tls(std::fs::read(ca)?, client_auth, None)),
+            (None, None) => Ok(Transport::tls_with_config(Default::default())),
+            (None, Some(_)) => bail!("MQTT client certificates need a ca to be set"),
+        }
+    }
 }
 
 impl MqttConnectionInfo {
-    pub async fn connect(&self) -> Result<(AsyncClient, EventLoop)> {
+    fn protocol(&self) -> MqttProtocol {
+        self.protocol.unwrap_or_default()
+    }
+
+    fn payload_mode(&self) -> MqttPayloadMode {
+        self.payload.unwrap_or_default()
+    }
+
+    fn last_will_payload(&self, will: &MqttLastWill) -> Result<Vec<u8>> {
+        self.payload_mode().encode(&OutputMessage {
+            message: will.message.as_bytes().to_vec(),
+            subject: Some(will.subject.clone()),
+            ..Default::default()
+        })
+    }
+
+    pub(crate) async fn connect(&self) -> Result<(MqttClient, MqttEventLoop)> {
         let id = match &self.id {
             Some(id) => id.clone(),
             None => ulid::Ulid::new().to_string(),
         };
         let url = format!("{}?client_id={id}", self.address);
-        let mut options = MqttOptions::parse_url(url)?;
-        options.set_keep_alive(Duration::from_secs_f32(self.keep_alive.unwrap_or(5.)));
+        let keep_alive = Duration::from_secs_f32(self.keep_alive.unwrap_or(5.));
+        let transport = self.tls.as_ref().map(MqttTls::transport).transpose()?;
+
+        match self.protocol() {
+            MqttProtocol::V4 => {
+                let mut options = MqttOptions::parse_url(url)?;
+                options.set_keep_alive(keep_alive);
+                if let Some(MqttCredentials { username, password }) = &self.credentials {
+                    options.set_credentials(username, password);
+                }
+                if let Some(transport) = transport {
+                    options.set_transport(transport);
+                }
+                if let Some(clean_session) = self.clean_session {
+                    options.set_clean_session(clean_session);
+                }
+                if self.session_expiry.is_some() {
+                    warn!("MQTT session expiry is only supported with the V5 protocol");
+                }
+                if let Some(will) = &self.last_will {
+                    options.set_last_will(rumqttc::LastWill::new(
+                        to_mqtt_topic(&will.subject),
+                        self.last_will_payload(will)?,
+                        v4_qos(will.qos.unwrap_or(1))?,
+                        will.retain.unwrap_or(false),
+                    ));
+                }
+                options.set_manual_acks(self.manual_acks.unwrap_or(false));
+
+                let (client, eventloop) = AsyncClient::new(options, 100);
+                Ok((
+                    MqttClient::V4(client),
+                    MqttEventLoop::V4(Box::new(eventloop)),
+                ))
+            }
+            MqttProtocol::V5 => {
+                let mut options = v5::MqttOptions::parse_url(url)?;
+                options.set_keep_alive(keep_alive);
+                if let Some(MqttCredentials { username, password }) = &self.credentials {
+                    options.set_credentials(username, password);
+                }
+                if let Some(transport) = transport {
+                    options.set_transport(transport);
+                }
+                if let Some(clean_session) = self.clean_session {
+                    options.set_clean_start(clean_session);
+                }
+                if let Some(session_expiry) = self.session_expiry {
+                    let mut properties = ConnectProperties::new();
+                    properties.session_expiry_interval = Some(session_expiry);
+                    options.set_connect_properties(properties);
+                }
+                if let Some(will) = &self.last_will {
+                    options.set_last_will(v5::mqttbytes::v5::LastWill::new(
+                        to_mqtt_topic(&will.subject),
+                        self.last_will_payload(will)?,
+                        v5_qos(will.qos.unwrap_or(1))?,
+                        will.retain.unwrap_or(false),
+                        None,
+                    ));
+                }
+                options.set_manual_acks(self.manual_acks.unwrap_or(false));
+
+                let (client, eventloop) = v5::AsyncClient::new(options, 100);
+                Ok((
+                    MqttClient::V5(client),
+                    MqttEventLoop::V5(Box::new(eventloop)),
+                ))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum MqttClient {
+    V4(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+#[derive(Debug)]
+enum ReceivedPublish {
+    V4(Publish),
+    V5(v5::mqttbytes::v5::Publish),
+}
+
+impl MqttClient {
+    async fn publish(
+        &self,
+        topic: String,
+        qos: u8,
+        retain: bool,
+        payload: Vec<u8>,
+        properties: PublishProperties,
+    ) -> Result<()> {
+        match self {
+            MqttClient::V4(client) => client.publish(topic, v4_qos(qos)?, retain, payload).await?,
+            MqttClient::V5(client) => {
+                client
+                    .publish_with_properties(topic, v5_qos(qos)?, retain, payload, properties)
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: String, qos: u8) -> Result<()> {
+        match self {
+            MqttClient::V4(client) => client.subscribe(topic, v4_qos(qos)?).await?,
+            MqttClient::V5(client) => client.subscribe(topic, v5_qos(qos)?).await?,
+        }
+        Ok(())
+    }
+
+    async fn ack(&self, publish: &ReceivedPublish) -> Result<()> {
+        match (self, publish) {
+            (MqttClient::V4(client), ReceivedPublish::V4(publish)) => client.ack(publish).await?,
+            (MqttClient::V5(client), ReceivedPublish::V5(publish)) => client.ack(publish).await?,
+            _ => bail!("MQTT protocol mismatch"),
+        }
+        Ok(())
+    }
+
+    fn try_ack(&self, publish: &ReceivedPublish) -> Result<()> {
+        match (self, publish) {
+            (MqttClient::V4(client), ReceivedPublish::V4(publish)) => client.try_ack(publish)?,
+            (MqttClient::V5(client), ReceivedPublish::V5(publish)) => client.try_ack(publish)?,
+            _ => bail!("MQTT protocol mismatch"),
+        }
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        match self {
+            MqttClient::V4(client) => client.disconnect().await?,
+            MqttClient::V5(client) => client.disconnect().await?,
+        }
+        Ok(())
+    }
+}
+
+pub(crate) enum MqttEventLoop {
+    V4(Box<EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+enum MqttIncoming {
+    Connected {
+        session_present: bool,
+    },
+    Publish(Box<ReceivedMessage>),
+    /// A publish was written to the connection - QoS 0 publishes have a packet id of 0
+    Sent(u16),
+    /// The server acknowledged a QoS 1 or 2 publish
+    Acked {
+        pkid: u16,
+        result: Result<()>,
+    },
+    /// A disconnect was sent, so the server won't publish the last will
+    Disconnected,
+}
+
+/// Matches publishes up with the acknowledgements the server sends back for them
+#[derive(Debug, Default)]
+struct PublishConfirms {
+    /// Publishes handed to the client, in the order it will send them
+    unsent: VecDeque<oneshot::Sender<Result<()>>>,
+    /// Publishes waiting on the server, by packet id
+    awaiting: HashMap<u16, oneshot::Sender<Result<()>>>,
+}
+
+impl PublishConfirms {
+    fn queued(&mut self, completion: oneshot::Sender<Result<()>>) {
+        self.unsent.push_back(completion);
+    }
+
+    /// Takes back the latest publish, when the client couldn't accept it
+    fn not_queued(&mut self) -> Option<oneshot::Sender<Result<()>>> {
+        self.unsent.pop_back()
+    }
+
+    fn sent(&mut self, pkid: u16) {
+        // Publishes that weren't acked are sent again with the same id after reconnecting
+        if self.awaiting.contains_key(&pkid) {
+            return;
+        }
+        let Some(completion) = self.unsent.pop_front() else {
+            return;
+        };
+        if pkid == 0 {
+            let _ = completion.send(Ok(()));
+        } else {
+            self.awaiting.insert(pkid, completion);
+        }
+    }
+
+    fn acked(&mut self, pkid: u16, result: Result<()>) {
+        if let Some(completion) = self.awaiting.remove(&pkid) {
+            let _ = completion.send(result);
+        }
+    }
+}
+
+struct ReceivedMessage {
+    topic: String,
+    payload: Vec<u8>,
+    properties: Option<PublishProperties>,
+    publish: ReceivedPublish,
+}
+
+impl MqttEventLoop {
+    /// Polls the event loop until the next connection acknowledgement or incoming publish.
+    /// Polling again after an error reconnects.
+    async fn next_incoming(&mut self) -> Result<MqttIncoming> {
+        loop {
+            match self {
+                MqttEventLoop::V4(event_loop) => {
+                    let notification = event_loop.poll().await?;
+                    match notification {
+                        rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(ack)) => {
+                            return Ok(MqttIncoming::Connected {
+                                session_present: ack.session_present,
+                            });
+                        }
+                        rumqttc::Event::Incoming(rumqttc::Packet::Publish(msg)) => {
+                            return Ok(MqttIncoming::Publish(Box::new(ReceivedMessage {
+                                topic: msg.topic.clone(),
+                                payload: msg.payload.to_vec(),
+                                properties: None,
+                                publish: ReceivedPublish::V4(msg),
+                            })));
+                        }
+                        rumqttc::Event::Outgoing(Outgoing::Publish(pkid)) => {
+                            return Ok(MqttIncoming::Sent(pkid));
+                        }
+                        rumqttc::Event::Outgoing(Outgoing::Disconnect) => {
+                            return Ok(MqttIncoming::Disconnected);
+                        }
+                        rumqttc::Event::Incoming(rumqttc::Packet::PubAck(ack)) => {
+                            return Ok(MqttIncoming::Acked {
+                                pkid: ack.pkid,
+                                result: Ok(()),
+                            });
+                        }
+                        rumqttc::Event::Incoming(rumqttc::Packet::PubComp(comp)) => {
+                            return Ok(MqttIncoming::Acked {
+                                pkid: comp.pkid,
+                                result: Ok(()),
+                            });
+                        }
+                        event => trace!(?event, "MQTT event"),
+                    }
+                }
+                MqttEventLoop::V5(event_loop) => {
+                    let notification = event_loop.poll().await?;
+                    match notification {
+                        v5::Event::Incoming(v5::mqttbytes::v5::Packet::ConnAck(ack)) => {
+                            return Ok(MqttIncoming::Connected {
+                                session_present: ack.session_present,
+                            });
+                        }
+                        v5::Event::Incoming(v5::mqttbytes::v5::Packet::Publish(msg)) => {
+                            return Ok(MqttIncoming::Publish(Box::new(ReceivedMessage {
+                                topic: String::from_utf8_lossy(&msg.topic).to_string(),
+                                payload: msg.payload.to_vec(),
+                                properties: msg.properties.clone(),
+                                publish: ReceivedPublish::V5(msg),
+                            })));
+                        }
+                        v5::Event::Outgoing(Outgoing::Publish(pkid)) => {
+                            return Ok(MqttIncoming::Sent(pkid));
+                        }
+                        v5::Event::Outgoing(Outgoing::Disconnect) => {
+                            return Ok(MqttIncoming::Disconnected);
+                        }
+                        v5::Event::Incoming(v5::mqttbytes::v5::Packet::PubAck(ack)) => {
+                            let result = match ack.reason {
+                                PubAckReason::Success | PubAckReason::NoMatchingSubscribers => {
+                                    Ok(())
+                                }
+                                reason => Err(anyhow!("MQTT server rejected publish - {reason:?}")),
+                            };
+                            return Ok(MqttIncoming::Acked {
+                                pkid: ack.pkid,
+                                result,
+                            });
+                        }
+                        // A QoS 2 publish only gets completed after being received successfully
+                        v5::Event::Incoming(v5::mqttbytes::v5::Packet::PubRec(rec))
+                            if !matches!(
+                                rec.reason,
+                                PubRecReason::Success | PubRecReason::NoMatchingSubscribers
+                            ) =>
+                        {
+                            return Ok(MqttIncoming::Acked {
+                                pkid: rec.pkid,
+                                result: Err(anyhow!(
+                                    "MQTT server rejected publish - {:?}",
+                                    rec.reason
+                                )),
+                            });
+                        }
+                        v5::Event::Incoming(v5::mqttbytes::v5::Packet::PubComp(comp)) => {
+                            return Ok(MqttIncoming::Acked {
+                                pkid: comp.pkid,
+                                result: Ok(()),
+                            });
+                        }
+                        event => trace!(?event, "MQTT event"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Removes the QoS & retain overrides from the message headers, falling back to the defaults
+fn take_publish_options(
+    message: &mut OutputMessage,
+    default_qos: u8,
+    default_retain: bool,
+) -> Result<(u8, bool)> {
+    let mut qos = default_qos;
+    let mut retain = default_retain;
+    let mut headers = Vec::with_capacity(message.headers.len());
+    for (name, value) in message.headers.drain(..) {
+        if name.eq_ignore_ascii_case(QOS_HEADER) {
+            qos = String::from_utf8_lossy(&value).trim().parse()?;
+        } else if name.eq_ignore_ascii_case(RETAIN_HEADER) {
+            retain = String::from_utf8_lossy(&value)
+                .trim()
+                .eq_ignore_ascii_case("true");
+        } else {
+            headers.push((name, value));
+        }
+    }
+    message.headers = headers;
+    Ok((qos, retain))
+}
+
+/// Builds the v5 properties for an outgoing message - headers become user properties,
+/// and the response subject becomes the response topic.
+fn publish_properties(
+    message: &mut OutputMessage,
+    correlation_data: Option<Vec<u8>>,
+) -> PublishProperties {
+    let header_correlation = message
+        .headers
+        .iter()
+        .position(|(name, _)| name.eq_ignore_ascii_case(CORRELATION_DATA_HEADER))
+        .map(|i| message.headers.remove(i).1);
+    PublishProperties {
+        response_topic: message.response_subject.as_deref().map(to_mqtt_topic),
+        correlation_data: header_correlation.or(correlation_data).map(Into::into),
+        user_properties: message
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), String::from_utf8_lossy(value).to_string()))
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// How long the correlation data of a received v5 request is kept, waiting for a response
+const CORRELATION_TTL: Duration = Duration::from_secs(60);
+/// The most requests whose correlation data is kept at once
+const MAX_CORRELATIONS: usize = 10_000;
+
+/// Correlation data of received v5 requests, keyed by their response topic, so it can be
+/// echoed in the response. Requests that never get a response expire after a while.
+#[derive(Default)]
+struct Correlations(DashMap<String, (Vec<u8>, Instant)>);
+
+impl Correlations {
+    fn insert(&self, response_topic: String, data: Vec<u8>) {
+        if self.0.len() >= MAX_CORRELATIONS {
+            self.0
+                .retain(|_, (_, received)| received.elapsed() < CORRELATION_TTL);
+            if self.0.len() >= MAX_CORRELATIONS {
+                // The data is still in the message headers, for components that pass them on
+                warn!(%response_topic, "Too many pending MQTT requests to keep correlation data");
+                return;
+            }
+        }
+        self.0.insert(response_topic, (data, Instant::now()));
+    }
+
+    fn take(&self, response_topic: &str) -> Option<Vec<u8>> {
+        let (_, (data, received)) = self.0.remove(response_topic)?;
+        (received.elapsed() < CORRELATION_TTL).then_some(data)
+    }
+
+    /// Keeps the correlation data of a received request, keyed by the topic its response is published to
+    fn received(&self, properties: &PublishProperties) {
+        if let (Some(topic), Some(data)) =
+            (&properties.response_topic, &properties.correlation_data)
+        {
+            self.insert(to_mqtt_topic(&topic.replace('/', ".")), data.to_vec());
+        }
+    }
+
+    /// The v5 properties for a message published to the given topic, with the correlation data
+    /// of the request it responds to
+    fn publish_properties(&self, topic: &str, message: &mut OutputMessage) -> PublishProperties {
+        publish_properties(message, self.take(topic))
+    }
+}
+
+/// Merges the v5 properties of a received message into it
+fn apply_properties(message: &mut OutputMessage, properties: PublishProperties) {
+    if message.response_subject.is_none() {
+        message.response_subject = properties
+            .response_topic
+            .map(|topic| topic.replace('/', "."));
+    }
+    for (name, value) in properties.user_properties {
+        if !message.headers.iter().any(|(n, _)| n == &name) {
+            message.headers.push((name, value.into_bytes()));
+        }
+    }
+    if let Some(correlation_data) = properties.correlation_data {
+        message.headers.push((
+            CORRELATION_DATA_HEADER.to_string(),
+            correlation_data.to_vec(),
+        ));
+    }
+}
+
+/// Sends the PUBACK for a received message once it's been handled. MQTT servers only redeliver
+/// on a new session, so requeued messages are acked too, and redelivered through the local broker instead.
+#[derive(Debug)]
+struct MqttAcker {
+    client: MqttClient,
+    publish: ReceivedPublish,
+    settled: AtomicBool,
+    local_broker: Arc<InMemoryBroker>,
+    message: OutputMessage,
+}
 
-        if let Some(MqttCredentials { username, password }) = &self.credentials {
-            options.set_credentials(username, password);
+impl MqttAcker {
+    fn new(
+        client: MqttClient,
+        publish: ReceivedPublish,
+        local_broker: Arc<InMemoryBroker>,
+        message: OutputMessage,
+    ) -> Self {
+        Self {
+            client,
+            publish,
+            settled: AtomicBool::new(false),
+            local_broker,
+            message,
         }
+    }
+
+    fn redeliver(&self, delay: Duration) {
+        let local_broker = self.local_broker.clone();
+        let message = self.message.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Err(e) = local_broker.publish_with_acker(message, None) {
+                warn!("Failed to redeliver MQTT message - {e:?}");
+            }
+        });
+    }
+}
 
-        let (client, eventloop) = AsyncClient::new(options, 100);
+#[async_trait]
+impl Acker for MqttAcker {
+    async fn acknowledge(&self, ack: Acknowledgement) -> Result<()> {
+        if self.settled.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        // Leaving the message unacked would hold up the inflight window until the session ends
+        self.client.ack(&self.publish).await?;
+        match ack {
+            Acknowledgement::Ack | Acknowledgement::Nack { requeue: false } => {}
+            Acknowledgement::Nack { requeue: true } => self.redeliver(Duration::ZERO),
+            Acknowledgement::RetryAfter(delay) => self.redeliver(delay),
+        }
+        Ok(())
+    }
+}
 
-        Ok((client, eventloop))
+impl Drop for MqttAcker {
+    fn drop(&mut self) {
+        // Messages nobody settled still need to be acked, or they'd hold up the inflight window
+        if !self.settled.swap(true, Ordering::SeqCst) {
+            let _ = self.client.try_ack(&self.publish);
+        }
     }
 }
 
@@ -63,15 +674,27 @@ impl MqttBroker {
     pub fn new(options: MqttConnectionInfo, name: String) -> Self {
         let local_broker = Arc::new(InMemoryBroker::default());
         let (subscription_handler, sub_rx) = mpsc::channel(100);
-        let (publish_handler, pub_rx) = mpsc::channel(100);
         let (queue_handler, queue_rx) = mpsc::channel(100);
+        let publish_buffer = Arc::new(PublishBuffer::new(
+            &options.reconnect.clone().unwrap_or_default(),
+            ConnectionMonitor::default(),
+        ));
         let n = name.clone();
         let broker = local_broker.clone();
+        let buffer = publish_buffer.clone();
         tokio::spawn(async move {
-            if let Err(e) =
-                MqttBroker::setup_client(n, options, sub_rx, pub_rx, queue_rx, broker).await
+            if let Err(e) = MqttBroker::setup_client(
+                n.clone(),
+                options,
+                sub_rx,
+                buffer.clone(),
+                queue_rx,
+                broker,
+            )
+            .await
             {
-                eprintln!("Mqtt Error: {e}");
+                error!(broker = %n, "Mqtt Error: {e}");
+                buffer.fail(e);
             }
         });
 
@@ -79,7 +702,7 @@ impl MqttBroker {
             name,
             local_broker,
             subscription_handler,
-            publish_handler,
+            publish_buffer,
             queue_handler,
         }
     }
@@ -88,26 +711,107 @@ impl MqttBroker {
         name: String,
         options: MqttConnectionInfo,
         mut sub_rx: mpsc::Receiver<String>,
-        mut pub_rx: mpsc::Receiver<(String, OutputMessage)>,
+        publish_buffer: Arc<PublishBuffer>,
         mut queue_rx: mpsc::Receiver<(String, String)>,
         local_broker: Arc<InMemoryBroker>,
     ) -> Result<()> {
         let (client, mut event_loop) = options.connect().await?;
-        println!("Connected to MQTT for {name}");
+        let monitor = publish_buffer.monitor().clone();
+        let mut reconnector = Reconnector::new(
+            options.reconnect.clone().unwrap_or_default(),
+            monitor.clone(),
+        );
+        let manual_acks = options.manual_acks.unwrap_or(false);
+        let v5 = options.protocol() == MqttProtocol::V5;
+        let payload_mode = options.payload_mode();
+        let default_qos = options.qos.unwrap_or(1);
+        let default_retain = options.retain.unwrap_or(false);
+        let subscription_qos: Arc<HashMap<String, u8>> = Arc::new(
+            options
+                .subscription_qos
+                .iter()
+                .flatten()
+                .map(|(subject, qos)| (to_mqtt_topic(subject), *qos))
+                .collect(),
+        );
+        let correlations: Arc<Correlations> = Default::default();
+        // Every topic filter subscribed to, with its QoS, so they can be restored after reconnecting
+        let subscriptions: Arc<DashMap<String, u8>> = Default::default();
+        let confirms: Option<Arc<Mutex<PublishConfirms>>> = options
+            .publisher_confirms
+            .unwrap_or_default()
+            .then(Default::default);
+        info!(broker = %name, "Created MQTT client");
         {
+            // Disconnect requests are queued behind any publishes the client hasn't sent yet
             let client = client.clone();
+            let publish_buffer = publish_buffer.clone();
+            let name = name.clone();
             tokio::spawn(async move {
-                while let Some((subject, message)) = pub_rx.recv().await {
-                    let Ok(body) = rmp_serde::to_vec(&message) else {
-                        continue;
+                publish_buffer.drained().await;
+                if let Err(e) = client.disconnect().await {
+                    warn!(broker = %name, "Couldn't disconnect from MQTT - {e:?}");
+                }
+            });
+        }
+        {
+            let client = client.clone();
+            let correlations = correlations.clone();
+            let confirms = confirms.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (subject, mut message, completion) =
+                        publish_buffer.next().await.into_parts();
+                    let (qos, retain) =
+                        match take_publish_options(&mut message, default_qos, default_retain) {
+                            Ok(options) => options,
+                            Err(e) => {
+                                error!(%subject, "Invalid MQTT publish options - {e:?}");
+                                let _ = completion.send(Err(e));
+                                continue;
+                            }
+                        };
+                    let properties = if v5 {
+                        correlations.publish_properties(&subject, &mut message)
+                    } else {
+                        PublishProperties::default()
+                    };
+                    let body = match payload_mode.encode(&message) {
+                        Ok(body) => body,
+                        Err(e) => {
+                            let _ = completion.send(Err(e));
+                            continue;
+                        }
+                    };
+                    // With confirms on, the event loop completes the publish once it's acknowledged
+                    let completion = match &confirms {
+                        Some(confirms) => {
+                            confirms.lock().unwrap().queued(completion);
+                            None
+                        }
+                        None => Some(completion),
                     };
-                    println!("Publishing on MQTT to {subject}");
-                    let result: Result<(), ClientError> = client
-                        .publish(subject.clone(), rumqttc::QoS::AtLeastOnce, false, body)
-                        .await;
-                    match result {
-                        Ok(_) => println!("Published on MQTT to {subject}"),
-                        Err(e) => eprintln!("Failed to publish on MQTT - {e:?}"),
+                    match client
+                        .publish(subject.clone(), qos, retain, body, properties)
+                        .await
+                    {
+                        Ok(_) => {
+                            trace!(%subject, "Published");
+                            if let Some(completion) = completion {
+                                let _ = completion.send(Ok(()));
+                            }
+                        }
+                        Err(e) => {
+                            error!(%subject, "Failed to publish - {e:?}");
+                            let completion = completion.or_else(|| {
+                                confirms
+                                    .as_ref()
+                                    .and_then(|confirms| confirms.lock().unwrap().not_queued())
+                            });
+                            if let Some(completion) = completion {
+                                let _ = completion.send(Err(e));
+                            }
+                        }
                     };
                 }
             });
@@ -115,54 +819,116 @@ impl MqttBroker {
 
         {
             let client = client.clone();
-            let _name = name.to_string();
+            let subscription_qos = subscription_qos.clone();
+            let subscriptions = subscriptions.clone();
             tokio::spawn(async move {
                 while let Some((subject, group)) = queue_rx.recv().await {
-                    println!("MQTT Queue Subscribe to {subject} {group}");
-                    let _ = client
-                        .subscribe(
-                            format!("$share/{group}/{subject}"),
-                            rumqttc::QoS::AtLeastOnce,
-                        )
-                        .await;
+                    info!(%subject, %group, "Subscribing to queue");
+                    let qos = subscription_qos
+                        .get(&subject)
+                        .copied()
+                        .unwrap_or(default_qos);
+                    let filter = format!("$share/{group}/{subject}");
+                    subscriptions.insert(filter.clone(), qos);
+                    if let Err(e) = client.subscribe(filter, qos).await {
+                        error!("Failed to subscribe - {e:?}");
+                    }
                 }
             });
         }
         {
             let client = client.clone();
-            let _name = name.to_string();
+            let subscriptions = subscriptions.clone();
             tokio::spawn(async move {
                 while let Some(subject) = sub_rx.recv().await {
-                    println!("MQTT Subscribe to {subject}");
-                    let _ = client
-                        .subscribe(subject.to_string(), rumqttc::QoS::AtLeastOnce)
-                        .await;
+                    info!(%subject, "Subscribing");
+                    let qos = subscription_qos
+                        .get(&subject)
+                        .copied()
+                        .unwrap_or(default_qos);
+                    subscriptions.insert(subject.clone(), qos);
+                    if let Err(e) = client.subscribe(subject, qos).await {
+                        error!("Failed to subscribe - {e:?}");
+                    }
                 }
             });
         }
 
+        let mut reconnected = false;
         loop {
-            match event_loop.poll().await {
-                Ok(notification) => {
-                    println!("MQTT Event {notification:?}");
-                    if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(msg)) = notification {
-                        let payload = msg.payload;
-                        let Ok(message) = rmp_serde::from_slice(&payload) else {
-                            continue;
-                        };
-                        let _ = local_broker.publish(message).await;
+            match event_loop.next_incoming().await {
+                Ok(MqttIncoming::Connected { session_present }) => {
+                    info!(broker = %name, "Connected to MQTT");
+                    reconnector.connected();
+                    // Subscriptions made before connecting are sent once the connection is up,
+                    // but after a reconnect they're gone unless the server kept the session
+                    if reconnected && !session_present {
+                        let client = client.clone();
+                        let subscriptions: Vec<(String, u8)> = subscriptions
+                            .iter()
+                            .map(|entry| (entry.key().clone(), *entry.value()))
+                            .collect();
+                        tokio::spawn(async move {
+                            for (filter, qos) in subscriptions {
+                                info!(%filter, "Resubscribing");
+                                if let Err(e) = client.subscribe(filter, qos).await {
+                                    error!("Failed to resubscribe - {e:?}");
+                                }
+                            }
+                        });
+                    }
+                }
+                Ok(MqttIncoming::Publish(received)) => {
+                    let ReceivedMessage {
+                        topic,
+                        payload,
+                        properties,
+                        publish,
+                    } = *received;
+                    let Ok(mut message) = payload_mode.decode(&topic, payload) else {
+                        if manual_acks {
+                            let _ = client.ack(&publish).await;
+                        }
+                        continue;
+                    };
+                    if let Some(properties) = properties {
+                        correlations.received(&properties);
+                        apply_properties(&mut message, properties);
+                    }
+                    let acker: Option<Arc<dyn Acker>> = if manual_acks {
+                        Some(Arc::new(MqttAcker::new(
+                            client.clone(),
+                            publish,
+                            local_broker.clone(),
+                            message.clone(),
+                        )))
+                    } else {
+                        None
                     };
+                    let _ = local_broker.publish_with_acker(message, acker);
+                }
+                Ok(MqttIncoming::Sent(pkid)) => {
+                    if let Some(confirms) = &confirms {
+                        confirms.lock().unwrap().sent(pkid);
+                    }
+                }
+                Ok(MqttIncoming::Acked { pkid, result }) => {
+                    if let Some(confirms) = &confirms {
+                        confirms.lock().unwrap().acked(pkid, result);
+                    }
+                }
+                Ok(MqttIncoming::Disconnected) => {
+                    info!(broker = %name, "Closed MQTT connection");
+                    monitor.disconnected("Shut down");
+                    return Ok(());
                 }
                 Err(e) => {
-                    eprintln!("Disconnected from event loop, {e:?}");
-                    break;
+                    warn!(broker = %name, "Disconnected from event loop - {e:?}");
+                    reconnected = true;
+                    reconnector.disconnected(e).await?;
                 }
             }
         }
-
-        println!("exiting mqtt broker");
-
-        Ok(())
     }
 }
 
@@ -177,23 +943,195 @@ impl MessageBroker for MqttBroker {
             .subject
             .as_deref()
             .ok_or(anyhow::Error::msg("No Subject To Publish"))?;
-        self.publish_handler
-            .send((subject.replace('.', "/").replace('*', "+"), message.clone()))
-            .await?;
-        Ok(())
+        self.publish_buffer
+            .publish(to_mqtt_topic(subject), message.clone())
+            .await
     }
 
     async fn subscribe_to_topic(&self, subject: &str) -> Result<Receiver> {
         self.subscription_handler
-            .send(subject.replace('.', "/").replace('*', "+"))
+            .send(to_mqtt_topic(subject))
             .await?;
         self.local_broker.subscribe_to_topic(subject).await
     }
 
     async fn subscribe_to_queue(&self, topic: &str, group: &str) -> Result<QueueReceiver> {
         self.queue_handler
-            .send((topic.replace('.', "/").replace('*', "+"), group.to_string()))
+            .send((to_mqtt_topic(topic), group.to_string()))
             .await?;
         self.local_broker.subscribe_to_queue(topic, group).await
     }
+
+    fn connection_events(&self) -> Option<broadcast::Receiver<ConnectionEvent>> {
+        Some(self.publish_buffer.monitor().subscribe())
+    }
+
+    fn health(&self) -> BrokerHealth {
+        self.publish_buffer.monitor().health()
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.publish_buffer.close().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn publish_options_are_taken_from_headers() {
+        let mut message = OutputMessage {
+            headers: vec![
+                (QOS_HEADER.to_string(), b"2".to_vec()),
+                (RETAIN_HEADER.to_string(), b"true".to_vec()),
+                ("content-type".to_string(), b"text/plain".to_vec()),
+            ],
+            ..Default::default()
+        };
+        let (qos, retain) = take_publish_options(&mut message, 1, false).unwrap();
+        assert_eq!(qos, 2);
+        assert!(retain);
+        assert_eq!(
+            message.headers,
+            vec![("content-type".to_string(), b"text/plain".to_vec())]
+        );
+
+        let mut message = OutputMessage::default();
+        assert_eq!(
+            take_publish_options(&mut message, 0, true).unwrap(),
+            (0, true)
+        );
+    }
+
+    #[test]
+    fn confirms_complete_once_acked() {
+        let mut confirms = PublishConfirms::default();
+        let (qos0, mut qos0_result) = oneshot::channel();
+        let (qos1, mut qos1_result) = oneshot::channel();
+        let (next, mut next_result) = oneshot::channel();
+        confirms.queued(qos0);
+        confirms.queued(qos1);
+        confirms.queued(next);
+
+        confirms.sent(0);
+        assert!(qos0_result.try_recv().unwrap().is_ok());
+
+        confirms.sent(1);
+        // Resending after a reconnect shouldn't be mistaken for the next publish
+        confirms.sent(1);
+        assert!(qos1_result.try_recv().is_err());
+        confirms.acked(1, Ok(()));
+        assert!(qos1_result.try_recv().unwrap().is_ok());
+
+        confirms.sent(2);
+        confirms.acked(2, Err(anyhow!("not authorized")));
+        assert!(next_result.try_recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn raw_payloads_use_the_incoming_topic() {
+        let message = MqttPayloadMode::Raw
+            .decode("sensors/room1/temperature", b"21.5".to_vec())
+            .unwrap();
+        assert_eq!(
+            message.subject.as_deref(),
+            Some("sensors.room1.temperature")
+        );
+        assert_eq!(message.message, b"21.5");
+
+        let encoded = MqttPayloadMode::Raw.encode(&message).unwrap();
+        assert_eq!(encoded, b"21.5");
+    }
+
+    #[test]
+    fn envelope_payloads_keep_the_message() {
+        let message = OutputMessage {
+            message: b"hello".to_vec(),
+            subject: Some("greetings.en".to_string()),
+            headers: vec![("trace".to_string(), b"123".to_vec())],
+            ..Default::default()
+        };
+        let encoded = MqttPayloadMode::Envelope.encode(&message).unwrap();
+        let decoded = MqttPayloadMode::Envelope
+            .decode("something/else", encoded)
+            .unwrap();
+        assert_eq!(decoded.subject.as_deref(), Some("greetings.en"));
+        assert_eq!(decoded.message, b"hello");
+        assert_eq!(decoded.headers, message.headers);
+        assert!(MqttPayloadMode::Envelope
+            .decode("greetings/en", b"not an envelope".to_vec())
+            .is_err());
+    }
+
+    #[test]
+    fn correlation_data_is_only_kept_for_a_while() {
+        let correlations = Correlations::default();
+        correlations.insert("response.abc".to_string(), b"abc".to_vec());
+        assert_eq!(correlations.take("response.abc"), Some(b"abc".to_vec()));
+        assert_eq!(correlations.take("response.abc"), None);
+
+        correlations.0.insert(
+            "response.old".to_string(),
+            (b"old".to_vec(), Instant::now() - CORRELATION_TTL),
+        );
+        assert_eq!(correlations.take("response.old"), None);
+
+        for i in 0..MAX_CORRELATIONS + 1 {
+            correlations.insert(format!("response.{i}"), vec![]);
+        }
+        assert_eq!(correlations.0.len(), MAX_CORRELATIONS);
+    }
+
+    #[test]
+    fn responses_are_published_with_the_request_correlation_data() {
+        let correlations = Correlations::default();
+        let properties = PublishProperties {
+            response_topic: Some("response/abc".to_string()),
+            correlation_data: Some(b"abc".to_vec().into()),
+            ..Default::default()
+        };
+        correlations.received(&properties);
+        let mut request = OutputMessage::default();
+        apply_properties(&mut request, properties);
+
+        // The component responds on the response subject, without passing the headers on
+        let mut response = OutputMessage {
+            subject: request.response_subject.clone(),
+            ..Default::default()
+        };
+        let topic = to_mqtt_topic(response.subject.as_deref().unwrap());
+        let properties = correlations.publish_properties(&topic, &mut response);
+        assert_eq!(properties.response_topic, None);
+        assert_eq!(properties.correlation_data.as_deref(), Some(&b"abc"[..]));
+        assert!(correlations.0.is_empty());
+    }
+
+    #[test]
+    fn v5_properties_round_trip() {
+        let mut outgoing = OutputMessage {
+            response_subject: Some("response.abc".to_string()),
+            headers: vec![("trace".to_string(), b"123".to_vec())],
+            ..Default::default()
+        };
+        let properties = publish_properties(&mut outgoing, Some(b"abc".to_vec()));
+        assert_eq!(properties.response_topic.as_deref(), Some("response/abc"));
+        assert_eq!(properties.correlation_data.as_deref(), Some(&b"abc"[..]));
+        assert_eq!(
+            properties.user_properties,
+            vec![("trace".to_string(), "123".to_string())]
+        );
+
+        let mut incoming = OutputMessage::default();
+        apply_properties(&mut incoming, properties);
+        assert_eq!(incoming.response_subject.as_deref(), Some("response.abc"));
+        assert_eq!(
+            incoming.headers,
+            vec![
+                ("trace".to_string(), b"123".to_vec()),
+                (CORRELATION_DATA_HEADER.to_string(), b"abc".to_vec())
+            ]
+        );
+    }
 }
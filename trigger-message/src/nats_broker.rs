@@ -1,15 +1,34 @@
 use std::{path::Path, sync::Arc, time::Duration};
 
 use anyhow::{bail, Result};
-use async_nats::{ConnectOptions, ServerAddr};
+use async_nats::{
+    jetstream::{
+        self,
+        consumer::{pull, AckPolicy, DeliverPolicy},
+        AckKind,
+    },
+    ConnectOptions, ServerAddr,
+};
 use async_trait::async_trait;
 use dashmap::DashMap;
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use spin_message_types::{InputMessage, OutputMessage};
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    task::JoinSet,
+};
+use tracing::{error, info, trace, warn};
 
-use crate::broker::{create_channel, MessageBroker, QueueReceiver, Receiver, Sender};
+use crate::{
+    broker::{
+        create_channel, default_message_response_subject, Acker, Acknowledgement, Delivery,
+        MessageBroker, QueueReceiver, Receiver, Sender,
+    },
+    configs::{ReconnectConfig, StreamAckPolicy, StreamDeliverPolicy, StreamSubscription},
+    connection::{BrokerHealth, ConnectionEvent, ConnectionMonitor, PublishBuffer},
+    telemetry,
+};
 
 #[derive(Clone, Debug)]
 pub struct Subscription(Sender);
@@ -20,7 +39,9 @@ pub struct NatsBroker {
     map: Arc<DashMap<String, Subscription>>,
     subscription_handler: mpsc::Sender<(String, Sender)>,
     queue_handler: mpsc::Sender<(String, String, Sender)>,
-    publish_handler: mpsc::Sender<(String, OutputMessage)>,
+    stream_handler: mpsc::Sender<(StreamSubscription, Sender)>,
+    publish_buffer: Arc<PublishBuffer>,
+    monitor: ConnectionMonitor,
     request_handler: mpsc::Sender<(String, OutputMessage, oneshot::Sender<InputMessage>)>,
 }
 
@@ -49,10 +70,34 @@ pub struct NatsConnectionInfo {
     root_certificate: Option<String>,
     client_certificate: Option<ClientCertInfo>,
     client_name: Option<String>,
+    jetstream: Option<JetStreamOptions>,
+    reconnect: Option<ReconnectConfig>,
+    /// Publish through JetStream, only completing once a stream has stored the message
+    publisher_confirms: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct JetStreamOptions {
+    domain: Option<String>,
+    api_prefix: Option<String>,
+    #[serde(default)]
+    streams: Vec<JetStreamStream>,
+}
+
+/// A stream that gets created on startup if it doesn't exist yet
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct JetStreamStream {
+    name: String,
+    subjects: Vec<String>,
+    max_messages: Option<i64>,
+    /// The maximum age of messages in the stream, in milliseconds
+    max_age: Option<u64>,
 }
 
 impl NatsConnectionInfo {
-    pub async fn connect(&self) -> Result<async_nats::Client> {
+    /// Connects to the servers, failing the publish buffer if the client gives up reconnecting
+    pub async fn connect(&self, publish_buffer: Arc<PublishBuffer>) -> Result<async_nats::Client> {
+        let monitor = publish_buffer.monitor().clone();
         let mut options = if let Some(auth) = &self.auth {
             match auth {
                 NatsAuth::Token(token) => ConnectOptions::with_token(token.clone()),
@@ -103,6 +148,44 @@ impl NatsConnectionInfo {
             options = options.add_client_certificate(certificate, key);
         }
 
+        // The client reconnects & restores subscriptions by itself, so it just needs to report it.
+        // async-nats 0.33 never reads its own `max_reconnects`, so giving up is done here instead -
+        // once out of attempts, the client waits forever before the next one.
+        let reconnect = self.reconnect.clone().unwrap_or_default();
+        let reconnecting = monitor.clone();
+        options = options
+            .retry_on_initial_connect()
+            .reconnect_delay_callback(move |attempts| {
+                let attempts = attempts as u32;
+                if attempts <= 1 {
+                    return Duration::ZERO;
+                }
+                let attempt = attempts - 1;
+                if let Some(max_attempts) = reconnect.max_attempts {
+                    if attempt > max_attempts {
+                        error!("Gave up reconnecting to NATS after {max_attempts} attempts");
+                        reconnecting.disconnected("gave up reconnecting");
+                        publish_buffer.fail(format!(
+                            "Gave up reconnecting after {max_attempts} attempts"
+                        ));
+                        return Duration::MAX;
+                    }
+                }
+                let delay = reconnect.backoff(attempt);
+                reconnecting.reconnecting(attempt, delay);
+                delay
+            })
+            .event_callback(move |event| {
+                let monitor = monitor.clone();
+                async move {
+                    match event {
+                        async_nats::Event::Connected => monitor.connected(),
+                        async_nats::Event::Disconnected => monitor.disconnected("connection lost"),
+                        event => info!(%event, "NATS event"),
+                    }
+                }
+            });
+
         let addresses: Vec<ServerAddr> = self
             .addresses
             .iter()
@@ -116,20 +199,137 @@ impl NatsConnectionInfo {
             .collect();
         Ok(options.connect(addresses).await?)
     }
+
+    pub async fn jetstream(&self, client: async_nats::Client) -> Result<jetstream::Context> {
+        let Some(options) = &self.jetstream else {
+            return Ok(jetstream::new(client));
+        };
+        let context = match (&options.domain, &options.api_prefix) {
+            (Some(domain), _) => jetstream::with_domain(client, domain),
+            (None, Some(prefix)) => jetstream::with_prefix(client, prefix),
+            (None, None) => jetstream::new(client),
+        };
+        for stream in options.streams.iter() {
+            context
+                .get_or_create_stream(jetstream::stream::Config {
+                    name: stream.name.clone(),
+                    subjects: stream.subjects.clone(),
+                    max_messages: stream.max_messages.unwrap_or(-1),
+                    max_age: Duration::from_millis(stream.max_age.unwrap_or_default()),
+                    ..Default::default()
+                })
+                .await?;
+        }
+        Ok(context)
+    }
+}
+
+fn stream_deliver_policy(policy: &Option<StreamDeliverPolicy>) -> Result<DeliverPolicy> {
+    let policy = match policy.as_ref().unwrap_or(&StreamDeliverPolicy::All) {
+        StreamDeliverPolicy::All => DeliverPolicy::All,
+        StreamDeliverPolicy::Last => DeliverPolicy::Last,
+        StreamDeliverPolicy::New => DeliverPolicy::New,
+        StreamDeliverPolicy::BySequence(start_sequence) => DeliverPolicy::ByStartSequence {
+            start_sequence: *start_sequence,
+        },
+        StreamDeliverPolicy::ByTime(time) => DeliverPolicy::ByStartTime {
+            start_time: time::OffsetDateTime::parse(
+                time,
+                &time::format_description::well_known::Rfc3339,
+            )?,
+        },
+    };
+    Ok(policy)
+}
+
+fn stream_ack_policy(policy: &Option<StreamAckPolicy>) -> AckPolicy {
+    match policy.as_ref().unwrap_or(&StreamAckPolicy::Explicit) {
+        StreamAckPolicy::Explicit => AckPolicy::Explicit,
+        StreamAckPolicy::All => AckPolicy::All,
+        StreamAckPolicy::None => AckPolicy::None,
+    }
+}
+
+struct JetStreamAcker(jetstream::message::Acker);
+
+impl std::fmt::Debug for JetStreamAcker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("JetStreamAcker")
+    }
+}
+
+#[async_trait]
+impl Acker for JetStreamAcker {
+    async fn acknowledge(&self, ack: Acknowledgement) -> Result<()> {
+        let kind = match ack {
+            Acknowledgement::Ack => AckKind::Ack,
+            Acknowledgement::Nack { requeue: true } => AckKind::Nak(None),
+            Acknowledgement::Nack { requeue: false } => AckKind::Term,
+            Acknowledgement::RetryAfter(delay) => AckKind::Nak(Some(delay)),
+        };
+        self.0.ack_with(kind).await.map_err(|e| anyhow::anyhow!(e))
+    }
+}
+
+fn to_nats_headers(headers: &[(String, Vec<u8>)]) -> async_nats::HeaderMap {
+    let mut map = async_nats::HeaderMap::new();
+    for (name, value) in headers.iter() {
+        map.append(name.as_str(), String::from_utf8_lossy(value).as_ref());
+    }
+    map
+}
+
+fn from_nats_headers(headers: Option<&async_nats::HeaderMap>) -> Vec<(String, Vec<u8>)> {
+    let Some(headers) = headers else {
+        return vec![];
+    };
+    headers
+        .iter()
+        .flat_map(|(name, values)| {
+            values
+                .iter()
+                .map(|value| (name.to_string(), value.as_str().as_bytes().to_vec()))
+        })
+        .collect()
+}
+
+fn to_input_message(name: &str, msg: async_nats::Message) -> InputMessage {
+    InputMessage {
+        message: msg.payload.to_vec(),
+        subject: msg.subject.to_string(),
+        broker: name.to_string(),
+        response_subject: msg.reply.map(|v| v.to_string()),
+        headers: from_nats_headers(msg.headers.as_ref()),
+    }
 }
 
 impl NatsBroker {
     pub fn new(options: NatsConnectionInfo, name: String) -> Self {
         let (subscription_handler, sub_rx) = mpsc::channel(100);
-        let (publish_handler, pub_rx) = mpsc::channel(100);
         let (request_handler, req_rx) = mpsc::channel(100);
         let (queue_handler, queue_rx) = mpsc::channel(100);
+        let (stream_handler, stream_rx) = mpsc::channel(100);
+        let monitor = ConnectionMonitor::default();
+        let publish_buffer = Arc::new(PublishBuffer::new(
+            &options.reconnect.clone().unwrap_or_default(),
+            monitor.clone(),
+        ));
         let n = name.clone();
+        let buffer = publish_buffer.clone();
         tokio::spawn(async move {
-            if let Err(e) =
-                NatsBroker::setup_client(n, options, sub_rx, pub_rx, req_rx, queue_rx).await
+            if let Err(e) = NatsBroker::setup_client(
+                n.clone(),
+                options,
+                sub_rx,
+                buffer.clone(),
+                req_rx,
+                queue_rx,
+                stream_rx,
+            )
+            .await
             {
-                eprintln!("Nats Error: {e}");
+                error!(broker = %n, "Nats Error: {e}");
+                buffer.fail(e);
             }
         });
 
@@ -137,32 +337,175 @@ impl NatsBroker {
             name,
             map: Default::default(),
             subscription_handler,
-            publish_handler,
+            publish_buffer,
+            monitor,
             request_handler,
             queue_handler,
+            stream_handler,
         }
     }
 
+    /// Keeps consuming a stream, setting the consumer up again with the reconnect backoff
+    /// if JetStream isn't ready yet, or the consumer goes away
+    async fn keep_consuming_stream(
+        name: &str,
+        jetstream: &jetstream::Context,
+        subscription: &StreamSubscription,
+        sender: Sender,
+        reconnect: &ReconnectConfig,
+    ) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            let result =
+                Self::consume_stream(name, jetstream, subscription, &sender, &mut attempt).await;
+            if sender.receiver_count() == 0 {
+                return Ok(());
+            }
+            let reason = match result {
+                Ok(()) => "the consumer's messages ended".to_string(),
+                Err(e) => e.to_string(),
+            };
+            attempt += 1;
+            if let Some(max_attempts) = reconnect.max_attempts {
+                if attempt > max_attempts {
+                    bail!("Gave up consuming the stream after {max_attempts} attempts - {reason}");
+                }
+            }
+            let delay = reconnect.backoff(attempt);
+            warn!(
+                broker = name,
+                stream = %subscription.stream,
+                consumer = %subscription.consumer,
+                attempt,
+                ?delay,
+                "Retrying JetStream consumer - {reason}"
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn consume_stream(
+        name: &str,
+        jetstream: &jetstream::Context,
+        subscription: &StreamSubscription,
+        sender: &Sender,
+        attempt: &mut u32,
+    ) -> Result<()> {
+        let stream = jetstream.get_stream(&subscription.stream).await?;
+        let ack_policy = stream_ack_policy(&subscription.ack_policy);
+        let consumer = stream
+            .get_or_create_consumer(
+                &subscription.consumer,
+                pull::Config {
+                    durable_name: Some(subscription.consumer.clone()),
+                    deliver_policy: stream_deliver_policy(&subscription.deliver_policy)?,
+                    ack_policy,
+                    max_ack_pending: subscription.max_ack_pending.unwrap_or_default(),
+                    filter_subject: subscription.topic.clone().unwrap_or_default(),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let mut messages = consumer.messages().await?;
+        *attempt = 0;
+        info!(
+            broker = name,
+            stream = %subscription.stream,
+            consumer = %subscription.consumer,
+            "Consuming JetStream"
+        );
+        while let Some(msg) = messages.next().await {
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!(broker = name, "Failed to receive JetStream Message - {e:?}");
+                    continue;
+                }
+            };
+            let (msg, acker) = msg.split();
+            trace!(broker = name, subject = %msg.subject, "Received JetStream message");
+            // The reply subject is used for acknowledgements, so it isn't a response subject
+            let mut input = to_input_message(name, msg);
+            input.response_subject = default_message_response_subject(&input.subject);
+            let delivery = match ack_policy {
+                AckPolicy::None => input.into(),
+                _ => Delivery::with_acker(input, Arc::new(JetStreamAcker(acker))),
+            };
+            let _ = sender.send(delivery);
+        }
+        Ok(())
+    }
+
     async fn setup_client(
         name: String,
         options: NatsConnectionInfo,
         mut sub_rx: mpsc::Receiver<(String, Sender)>,
-        mut pub_rx: mpsc::Receiver<(String, OutputMessage)>,
+        publish_buffer: Arc<PublishBuffer>,
         mut req_rx: mpsc::Receiver<(String, OutputMessage, oneshot::Sender<InputMessage>)>,
         mut queue_rx: mpsc::Receiver<(String, String, Sender)>,
+        mut stream_rx: mpsc::Receiver<(StreamSubscription, Sender)>,
     ) -> Result<()> {
-        let client = options.connect().await?;
-        println!("Connected to NATS for {name}");
+        let client = options.connect(publish_buffer.clone()).await?;
+        info!(broker = %name, "Created NATS client");
+        let jetstream = match options.jetstream(client.clone()).await {
+            Ok(jetstream) => jetstream,
+            Err(e) => {
+                error!(broker = %name, "Failed to set up JetStream - {e:?}");
+                jetstream::new(client.clone())
+            }
+        };
+        // Every task holding the client, so stopping them all unsubscribes & closes the connection
+        let mut tasks = JoinSet::new();
         {
             let client = client.clone();
-            tokio::spawn(async move {
-                while let Some((subject, message)) = pub_rx.recv().await {
+            let jetstream = jetstream.clone();
+            let publish_buffer = publish_buffer.clone();
+            let confirm = options.publisher_confirms.unwrap_or_default();
+            tasks.spawn(async move {
+                loop {
+                    let (subject, message, completion) = publish_buffer.next().await.into_parts();
+                    let headers = to_nats_headers(&message.headers);
                     let body = message.message;
-                    println!("Publishing on NATS to {subject}");
-                    let result = client.publish(subject.clone(), body.into());
-                    match result.await {
-                        Ok(_) => println!("Published on NATS to {subject}"),
-                        Err(e) => eprintln!("Failed to publish on NATS - {e:?}"),
+                    if confirm {
+                        let result = jetstream
+                            .publish_with_headers(subject.clone(), headers, body.into())
+                            .await;
+                        match result {
+                            // Waiting on the ack separately lets the next publish go out meanwhile
+                            Ok(ack) => {
+                                tokio::spawn(async move {
+                                    let result = match ack.await {
+                                        Ok(ack) => {
+                                            trace!(
+                                                %subject,
+                                                stream = %ack.stream,
+                                                sequence = ack.sequence,
+                                                "Published to JetStream"
+                                            );
+                                            Ok(())
+                                        }
+                                        Err(e) => {
+                                            error!(%subject, "JetStream rejected publish - {e:?}");
+                                            Err(e.into())
+                                        }
+                                    };
+                                    let _ = completion.send(result);
+                                });
+                            }
+                            Err(e) => {
+                                error!(%subject, "Failed to publish - {e:?}");
+                                let _ = completion.send(Err(e.into()));
+                            }
+                        }
+                    } else {
+                        let result = client
+                            .publish_with_headers(subject.clone(), headers, body.into())
+                            .await;
+                        match &result {
+                            Ok(_) => trace!(%subject, "Published"),
+                            Err(e) => error!(%subject, "Failed to publish - {e:?}"),
+                        }
+                        let _ = completion.send(result.map_err(|e| e.into()));
                     }
                 }
             });
@@ -170,82 +513,97 @@ impl NatsBroker {
         {
             let client = client.clone();
             let name = name.to_string();
-            tokio::spawn(async move {
+            tasks.spawn(async move {
                 while let Some((subject, message, response)) = req_rx.recv().await {
+                    let headers = to_nats_headers(&message.headers);
                     let body = message.message;
-                    println!("Requesting on NATS to {subject}");
-                    let request = async_nats::Request::new().payload(body.into());
+                    trace!(%subject, request_id = telemetry::request_id(&subject), "Requesting");
+                    let request = async_nats::Request::new()
+                        .payload(body.into())
+                        .headers(headers);
                     let result = client.send_request(subject.clone(), request).await;
                     match result {
                         Ok(msg) => {
-                            let subject = msg.subject.clone();
-                            println!("Received NATS Response on {subject}");
-                            let body = msg.payload.to_vec();
-
-                            let _ = response.send(InputMessage {
-                                message: body,
-                                subject: subject.to_string(),
-                                broker: name.clone(),
-                                response_subject: msg.reply.map(|v| v.to_string()),
-                            });
+                            trace!(subject = %msg.subject, "Received response");
+                            let _ = response.send(to_input_message(&name, msg));
                         }
                         Err(e) => {
-                            eprintln!("Request/Response Failed - {e:?}");
+                            warn!(%subject, "Request/Response Failed - {e:?}");
                         }
                     }
                 }
             });
         }
-        {
-            let client = client.clone();
-            let name = name.to_string();
-            tokio::spawn(async move {
-                while let Some((subject, group, sender)) = queue_rx.recv().await {
+
+        let reconnect = options.reconnect.clone().unwrap_or_default();
+        loop {
+            tokio::select! {
+                _ = publish_buffer.drained() => break,
+                Some((subject, sender)) = sub_rx.recv() => {
                     let client = client.clone();
                     let name = name.to_string();
-                    tokio::spawn(async move {
+                    tasks.spawn(async move {
+                        if let Ok(mut pubsub) = client.subscribe(subject.clone()).await {
+                            info!(broker = %name, %subject, "Subscribed");
+                            while let Some(msg) = pubsub.next().await {
+                                trace!(subject = %msg.subject, "Received message");
+                                let _ = sender.send(to_input_message(&name, msg).into());
+                            }
+                        }
+                    });
+                }
+                Some((subject, group, sender)) = queue_rx.recv() => {
+                    let client = client.clone();
+                    let name = name.to_string();
+                    tasks.spawn(async move {
                         if let Ok(mut pubsub) =
                             client.queue_subscribe(subject.clone(), group.clone()).await
                         {
-                            println!("Queue subscribed to async_nats: {subject} - {group}");
+                            info!(broker = %name, %subject, %group, "Subscribed to queue");
                             while let Some(msg) = pubsub.next().await {
-                                let subject = msg.subject.clone();
-                                println!("Received Queued NATS Message on {subject} - {group}");
-                                let body = msg.payload.to_vec();
-                                let _ = sender.send(InputMessage {
-                                    message: body,
-                                    subject: subject.to_string(),
-                                    broker: name.clone(),
-                                    response_subject: msg.reply.map(|v| v.to_string()),
-                                });
+                                trace!(subject = %msg.subject, %group, "Received queued message");
+                                let _ = sender.send(to_input_message(&name, msg).into());
                             }
                         }
                     });
                 }
-            });
-        }
-
-        while let Some((subject, sender)) = sub_rx.recv().await {
-            let client = client.clone();
-            let name = name.to_string();
-            tokio::spawn(async move {
-                if let Ok(mut pubsub) = client.subscribe(subject.clone()).await {
-                    println!("Subscribed to async_nats: {subject}");
-                    while let Some(msg) = pubsub.next().await {
-                        let subject = msg.subject.clone();
-                        println!("Received NATS Message on {subject}");
-                        let body = msg.payload.to_vec();
-                        let _ = sender.send(InputMessage {
-                            message: body,
-                            subject: subject.to_string(),
-                            broker: name.clone(),
-                            response_subject: msg.reply.map(|v| v.to_string()),
-                        });
-                    }
+                Some((subscription, sender)) = stream_rx.recv() => {
+                    let jetstream = jetstream.clone();
+                    let name = name.to_string();
+                    let reconnect = reconnect.clone();
+                    tasks.spawn(async move {
+                        if let Err(e) = NatsBroker::keep_consuming_stream(
+                            &name,
+                            &jetstream,
+                            &subscription,
+                            sender,
+                            &reconnect,
+                        )
+                        .await
+                        {
+                            error!(
+                                stream = %subscription.stream,
+                                consumer = %subscription.consumer,
+                                "JetStream Error: {e:?}"
+                            );
+                        }
+                    });
                 }
-            });
+                Some(_) = tasks.join_next() => {}
+            }
         }
 
+        // Publishes only reach the client's write buffer, so it needs flushing before shutting down
+        if let Err(e) = client.flush().await {
+            warn!(broker = %name, "Couldn't flush NATS client - {e:?}");
+        }
+        info!(broker = %name, "Flushed NATS publishes");
+        // Dropping the subscribers unsubscribes them, and dropping the last client closes the connection
+        tasks.shutdown().await;
+        drop(jetstream);
+        drop(client);
+        info!(broker = %name, "Closed NATS connection");
+        publish_buffer.monitor().disconnected("Shut down");
         Ok(())
     }
 }
@@ -261,10 +619,9 @@ impl MessageBroker for NatsBroker {
             .subject
             .as_deref()
             .ok_or(anyhow::Error::msg("No Subject To Publish"))?;
-        self.publish_handler
-            .send((subject.to_string(), message))
-            .await?;
-        Ok(())
+        self.publish_buffer
+            .publish(subject.to_string(), message)
+            .await
     }
 
     async fn subscribe_to_topic(&self, subject: &str) -> Result<Receiver> {
@@ -289,6 +646,30 @@ impl MessageBroker for NatsBroker {
         Ok(sender.subscribe())
     }
 
+    async fn subscribe_to_stream(
+        &self,
+        subscription: &StreamSubscription,
+    ) -> Result<QueueReceiver> {
+        let sender = create_channel(100);
+        self.stream_handler
+            .send((subscription.clone(), sender.clone()))
+            .await?;
+        Ok(sender.subscribe())
+    }
+
+    fn connection_events(&self) -> Option<broadcast::Receiver<ConnectionEvent>> {
+        Some(self.monitor.subscribe())
+    }
+
+    fn health(&self) -> BrokerHealth {
+        self.monitor.health()
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.publish_buffer.close().await;
+        Ok(())
+    }
+
     async fn request(&self, request: OutputMessage) -> Result<InputMessage> {
         let Some(subject) = request.subject.clone() else {
             bail!("No subject set");
@@ -0,0 +1,192 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+use spin_message_types::InputMessage;
+
+use crate::configs::OrderingKey;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PathSegment {
+    Field(String),
+    Index(usize),
+}
+
+/// Finds the ordering key of each message, based on the component's ordering config
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyExtractor {
+    SubjectSegment(usize),
+    Header(String),
+    JsonPath(Vec<PathSegment>),
+}
+
+impl KeyExtractor {
+    pub fn new(config: &OrderingKey) -> Result<Self> {
+        Ok(match config {
+            OrderingKey::SubjectSegment(segment) => KeyExtractor::SubjectSegment(*segment),
+            OrderingKey::Header(name) => KeyExtractor::Header(name.clone()),
+            OrderingKey::JsonPath(path) => KeyExtractor::JsonPath(parse_path(path)?),
+        })
+    }
+
+    /// The message's key, or `None` if it doesn't have the subject segment, header or json field
+    pub fn key(&self, message: &InputMessage) -> Option<Vec<u8>> {
+        match self {
+            KeyExtractor::SubjectSegment(segment) => message
+                .subject
+                .split('.')
+                .nth(*segment)
+                .map(|segment| segment.as_bytes().to_vec()),
+            KeyExtractor::Header(name) => message
+                .headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone()),
+            KeyExtractor::JsonPath(path) => {
+                let payload: Value = serde_json::from_slice(&message.message).ok()?;
+                let value = path
+                    .iter()
+                    .try_fold(&payload, |value, segment| match segment {
+                        PathSegment::Field(field) => value.get(field),
+                        PathSegment::Index(index) => value.get(index),
+                    })?;
+                match value {
+                    Value::Null => None,
+                    Value::String(value) => Some(value.as_bytes().to_vec()),
+                    value => Some(value.to_string().into_bytes()),
+                }
+            }
+        }
+    }
+}
+
+/// Picks the partition for a key, so the same key always ends up in the same one.
+/// Messages without a key share a partition.
+pub fn partition(key: Option<&[u8]>, partitions: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % partitions.max(1) as u64) as usize
+}
+
+/// Parses the subset of JSONPath used for keys - fields & array indices, like `$.items[0].sku` or `$['order-id']`
+fn parse_path(path: &str) -> Result<Vec<PathSegment>> {
+    let Some(mut rest) = path.strip_prefix('$') else {
+        bail!("JSON paths need to start with $ - {path}");
+    };
+    let mut segments = vec![];
+    while !rest.is_empty() {
+        if let Some(field) = rest.strip_prefix('.') {
+            let end = field.find(['.', '[']).unwrap_or(field.len());
+            if end == 0 {
+                bail!("Empty field in JSON path - {path}");
+            }
+            segments.push(PathSegment::Field(field[..end].to_string()));
+            rest = &field[end..];
+        } else if let Some(bracketed) = rest.strip_prefix('[') {
+            let Some(end) = bracketed.find(']') else {
+                bail!("Unclosed bracket in JSON path - {path}");
+            };
+            let inner = &bracketed[..end];
+            let quoted = inner
+                .strip_prefix('\'')
+                .and_then(|inner| inner.strip_suffix('\''))
+                .or_else(|| {
+                    inner
+                        .strip_prefix('"')
+                        .and_then(|inner| inner.strip_suffix('"'))
+                });
+            segments.push(match quoted {
+                Some(field) => PathSegment::Field(field.to_string()),
+                None => match inner.parse() {
+                    Ok(index) => PathSegment::Index(index),
+                    Err(_) => bail!("Invalid index {inner} in JSON path - {path}"),
+                },
+            });
+            rest = &bracketed[end + 1..];
+        } else {
+            bail!("Unexpected {rest} in JSON path - {path}");
+        }
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod test {
+    use spin_message_types::InputMessage;
+
+    use super::{parse_path, partition, KeyExtractor, PathSegment};
+    use crate::configs::OrderingKey;
+
+    fn message(subject: &str, body: &str) -> InputMessage {
+        InputMessage {
+            subject: subject.to_string(),
+            message: body.as_bytes().to_vec(),
+            headers: vec![("Entity-Id".to_string(), b"7".to_vec())],
+            ..Default::default()
+        }
+    }
+
+    fn key(config: OrderingKey, message: &InputMessage) -> Option<String> {
+        KeyExtractor::new(&config)
+            .unwrap()
+            .key(message)
+            .map(|key| String::from_utf8(key).unwrap())
+    }
+
+    #[test]
+    fn keys_come_from_the_subject_headers_or_payload() {
+        let message = message(
+            "orders.42.created",
+            r#"{"order": {"id": 42, "items": [{"sku": "abc"}]}}"#,
+        );
+
+        assert_eq!(key(OrderingKey::SubjectSegment(1), &message).unwrap(), "42");
+        assert_eq!(key(OrderingKey::SubjectSegment(5), &message), None);
+        assert_eq!(
+            key(OrderingKey::Header("entity-id".to_string()), &message).unwrap(),
+            "7"
+        );
+        assert_eq!(
+            key(OrderingKey::JsonPath("$.order.id".to_string()), &message).unwrap(),
+            "42"
+        );
+        assert_eq!(
+            key(
+                OrderingKey::JsonPath("$.order.items[0]['sku']".to_string()),
+                &message
+            )
+            .unwrap(),
+            "abc"
+        );
+        assert_eq!(
+            key(OrderingKey::JsonPath("$.customer".to_string()), &message),
+            None
+        );
+    }
+
+    #[test]
+    fn json_paths_are_parsed() {
+        assert_eq!(
+            parse_path("$.items[2][\"a.b\"]").unwrap(),
+            vec![
+                PathSegment::Field("items".to_string()),
+                PathSegment::Index(2),
+                PathSegment::Field("a.b".to_string())
+            ]
+        );
+        assert!(parse_path("items").is_err());
+        assert!(parse_path("$.items[x]").is_err());
+        assert!(parse_path("$..items").is_err());
+    }
+
+    #[test]
+    fn the_same_key_always_gets_the_same_partition() {
+        let first = partition(Some(b"42"), 4);
+        assert!(first < 4);
+        assert_eq!(partition(Some(b"42"), 4), first);
+        assert_eq!(partition(None, 1), 0);
+    }
+}
@@ -1,18 +1,33 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use async_trait::async_trait;
 use dashmap::DashMap;
 use futures::StreamExt;
 use rsmq_async::{Rsmq, RsmqConnection};
+use serde::{Deserialize, Serialize};
 use spin_message_types::{InputMessage, OutputMessage};
-use tokio::sync::mpsc;
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinSet,
+};
+use tracing::{error, info, trace, warn};
 
-use crate::broker::{
-    create_channel, default_message_response_subject, MessageBroker, QueueReceiver, Receiver,
-    Sender,
+use crate::{
+    broker::{
+        create_channel, default_message_response_subject, Acker, Acknowledgement, Delivery,
+        MessageBroker, QueueReceiver, Receiver, Sender, Wildcards,
+    },
+    configs::ReconnectConfig,
+    connection::{BrokerHealth, ConnectionEvent, ConnectionMonitor, PublishBuffer, Reconnector},
+};
+use redis::{
+    streams::{StreamId, StreamMaxlen, StreamRangeReply, StreamReadOptions, StreamReadReply},
+    *,
 };
-use redis::*;
 
 #[derive(Clone, Debug)]
 pub struct Subscription(Sender);
@@ -22,19 +37,248 @@ pub struct RedisBroker {
     name: String,
     map: Arc<DashMap<String, Subscription>>,
     subscription_handler: mpsc::Sender<(String, Sender)>,
-    publish_handler: mpsc::Sender<(String, OutputMessage)>,
+    publish_buffer: Arc<PublishBuffer>,
     queue_handler: mpsc::Sender<(String, String, Sender)>,
 }
 
+const ENVELOPE_PREFIX: &[u8] = b"\0spin-message\0";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RedisEnvelope {
+    message: Vec<u8>,
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+/// Messages without headers are published as-is, so other redis clients can still
+/// read them. Messages with headers get wrapped in a prefixed messagepack envelope.
+fn encode_body(message: OutputMessage) -> Vec<u8> {
+    if message.headers.is_empty() {
+        return message.message;
+    }
+    let envelope = RedisEnvelope {
+        message: message.message,
+        headers: message.headers,
+    };
+    let mut body = ENVELOPE_PREFIX.to_vec();
+    match rmp_serde::encode::write(&mut body, &envelope) {
+        Ok(_) => body,
+        Err(_) => envelope.message,
+    }
+}
+
+fn decode_body(body: Vec<u8>) -> (Vec<u8>, Vec<(String, Vec<u8>)>) {
+    if let Some(envelope) = body.strip_prefix(ENVELOPE_PREFIX) {
+        if let Ok(RedisEnvelope { message, headers }) = rmp_serde::from_slice(envelope) {
+            return (message, headers);
+        }
+    }
+    (body, vec![])
+}
+
+/// Queue messages become visible again if they aren't acknowledged in time, so queues
+/// are also polled rather than only relying on the realtime notifications.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct RedisQueueAcker {
+    client: redis::Client,
+    subject: String,
+    group: String,
+    id: String,
+}
+
+#[async_trait]
+impl Acker for RedisQueueAcker {
+    async fn acknowledge(&self, ack: Acknowledgement) -> Result<()> {
+        let connection = self.client.get_tokio_connection().await?;
+        let mut rsmq = Rsmq::new_with_connection(connection, true, Some(&self.subject));
+        match ack {
+            Acknowledgement::Ack | Acknowledgement::Nack { requeue: false } => {
+                rsmq.delete_message(&self.group, &self.id).await?;
+            }
+            Acknowledgement::Nack { requeue: true } => {
+                rsmq.change_message_visibility(&self.group, &self.id, Duration::ZERO)
+                    .await?;
+            }
+            Acknowledgement::RetryAfter(delay) => {
+                rsmq.change_message_visibility(&self.group, &self.id, delay)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RedisStreamsConnectionInfo {
+    address: String,
+    /// The consumer name used within each consumer group - defaults to a random id
+    consumer: Option<String>,
+    /// Streams are trimmed to roughly this many entries when publishing
+    max_len: Option<usize>,
+    /// How long an entry can stay pending before another consumer reclaims it, in milliseconds
+    claim_idle_time: Option<u64>,
+    /// The maximum number of entries read or reclaimed at once
+    batch_size: Option<usize>,
+    reconnect: Option<ReconnectConfig>,
+}
+
+const DEFAULT_CLAIM_IDLE_TIME: u64 = 30000;
+const DEFAULT_STREAM_BATCH_SIZE: usize = 10;
+
+#[derive(Clone, Debug)]
+struct RedisStreamsOptions {
+    consumer: String,
+    max_len: Option<usize>,
+    claim_idle_time: Duration,
+    batch_size: usize,
+}
+
+impl From<&RedisStreamsConnectionInfo> for RedisStreamsOptions {
+    fn from(value: &RedisStreamsConnectionInfo) -> Self {
+        Self {
+            consumer: value
+                .consumer
+                .clone()
+                .unwrap_or_else(|| ulid::Ulid::new().to_string()),
+            max_len: value.max_len,
+            claim_idle_time: Duration::from_millis(
+                value.claim_idle_time.unwrap_or(DEFAULT_CLAIM_IDLE_TIME),
+            ),
+            batch_size: value.batch_size.unwrap_or(DEFAULT_STREAM_BATCH_SIZE),
+        }
+    }
+}
+
+/// Queues are either backed by rsmq, or by a redis stream per subject with a consumer group per queue group.
+#[derive(Clone, Debug)]
+enum QueueBackend {
+    Rsmq,
+    Streams(RedisStreamsOptions),
+}
+
+/// Request & response subjects are only ever used with topic subscriptions
+fn is_request_subject(subject: &str) -> bool {
+    subject.starts_with("request.") || subject.starts_with("response.")
+}
+
+fn stream_entry_fields(message: &OutputMessage) -> Vec<(&'static str, Vec<u8>)> {
+    let mut fields = vec![("message", message.message.clone())];
+    if !message.headers.is_empty() {
+        if let Ok(headers) = rmp_serde::to_vec(&message.headers) {
+            fields.push(("headers", headers));
+        }
+    }
+    if let Some(response_subject) = &message.response_subject {
+        fields.push(("response_subject", response_subject.as_bytes().to_vec()));
+    }
+    fields
+}
+
+fn stream_entry_to_message(name: &str, subject: &str, entry: &StreamId) -> InputMessage {
+    let headers = entry
+        .get::<Vec<u8>>("headers")
+        .and_then(|headers| rmp_serde::from_slice(&headers).ok())
+        .unwrap_or_default();
+    InputMessage {
+        message: entry.get("message").unwrap_or_default(),
+        subject: subject.to_string(),
+        broker: name.to_string(),
+        response_subject: entry
+            .get("response_subject")
+            .or_else(|| default_message_response_subject(subject)),
+        headers,
+    }
+}
+
+#[derive(Debug)]
+struct RedisStreamAcker {
+    client: redis::Client,
+    subject: String,
+    group: String,
+    consumer: String,
+    id: String,
+    claim_idle_time: Duration,
+}
+
+impl RedisStreamAcker {
+    /// Entries are redelivered by reclaiming them, so setting the idle time of a pending
+    /// entry controls how soon it gets picked up again.
+    async fn reset_idle_time(&self, idle: Duration) -> Result<()> {
+        let mut connection = self.client.get_tokio_connection().await?;
+        redis::cmd("XCLAIM")
+            .arg(&self.subject)
+            .arg(&self.group)
+            .arg(&self.consumer)
+            .arg(0)
+            .arg(&self.id)
+            .arg("IDLE")
+            .arg(idle.as_millis() as u64)
+            .arg("JUSTID")
+            .query_async::<_, Value>(&mut connection)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Acker for RedisStreamAcker {
+    async fn acknowledge(&self, ack: Acknowledgement) -> Result<()> {
+        match ack {
+            Acknowledgement::Ack | Acknowledgement::Nack { requeue: false } => {
+                let mut connection = self.client.get_tokio_connection().await?;
+                connection
+                    .xack::<_, _, _, Value>(&self.subject, &self.group, &[&self.id])
+                    .await?;
+            }
+            Acknowledgement::Nack { requeue: true } => {
+                self.reset_idle_time(self.claim_idle_time).await?;
+            }
+            Acknowledgement::RetryAfter(delay) => {
+                self.reset_idle_time(self.claim_idle_time.saturating_sub(delay))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl RedisBroker {
     pub fn new(address: String, name: String) -> Self {
+        Self::start(address, QueueBackend::Rsmq, Default::default(), name)
+    }
+
+    pub fn new_with_streams(options: RedisStreamsConnectionInfo, name: String) -> Self {
+        let backend = QueueBackend::Streams((&options).into());
+        let reconnect = options.reconnect.unwrap_or_default();
+        Self::start(options.address, backend, reconnect, name)
+    }
+
+    fn start(
+        address: String,
+        backend: QueueBackend,
+        reconnect: ReconnectConfig,
+        name: String,
+    ) -> Self {
         let (subscription_handler, sub_rx) = mpsc::channel(100);
-        let (publish_handler, pub_rx) = mpsc::channel(100);
         let (queue_handler, queue_rx) = mpsc::channel(100);
+        let publish_buffer = Arc::new(PublishBuffer::new(&reconnect, ConnectionMonitor::default()));
         let n = name.clone();
+        let buffer = publish_buffer.clone();
         tokio::spawn(async move {
-            if let Err(e) = RedisBroker::setup_client(n, address, sub_rx, pub_rx, queue_rx).await {
-                eprintln!("Redis Error: {e}");
+            if let Err(e) = RedisBroker::setup_client(
+                n.clone(),
+                address,
+                backend,
+                reconnect,
+                sub_rx,
+                buffer.clone(),
+                queue_rx,
+            )
+            .await
+            {
+                error!(broker = %n, "Redis Error: {e}");
+                buffer.fail(e);
             }
         });
 
@@ -42,120 +286,370 @@ impl RedisBroker {
             name,
             map: Default::default(),
             subscription_handler,
-            publish_handler,
+            publish_buffer,
             queue_handler,
         }
     }
 
+    async fn publish_on(
+        connection: aio::Connection,
+        backend: &QueueBackend,
+        subject: &str,
+        message: OutputMessage,
+    ) -> RedisResult<()> {
+        let mut connection = connection;
+        let fields = stream_entry_fields(&message);
+        let body = encode_body(message);
+        let _: Value = connection.publish(subject, &body).await?;
+        trace!(%subject, "Published");
+        if let QueueBackend::Streams(options) = backend {
+            // Each request's response would otherwise leave a stream behind that nothing reads
+            if is_request_subject(subject) {
+                return Ok(());
+            }
+            let id: String = match options.max_len {
+                Some(max_len) => {
+                    connection
+                        .xadd_maxlen(subject, StreamMaxlen::Approx(max_len), "*", &fields)
+                        .await?
+                }
+                None => connection.xadd(subject, "*", &fields).await?,
+            };
+            trace!(%subject, %id, "Published to stream");
+            return Ok(());
+        }
+        let mut rsmq = Rsmq::new_with_connection(connection, true, Some(subject));
+        let Ok(queues) = rsmq.list_queues().await else {
+            return Ok(());
+        };
+        for qname in queues.iter() {
+            let result = rsmq.send_message(qname, body.clone(), None).await;
+            match result {
+                Ok(_) => trace!(%subject, queue = %qname, "Published to queue"),
+                Err(e) => error!(%subject, queue = %qname, "Failed to publish to queue - {e:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe_on(
+        client: &redis::Client,
+        name: &str,
+        subject: &str,
+        sender: &Sender,
+        reconnector: &mut Reconnector,
+    ) -> Result<()> {
+        let connection = client.get_tokio_connection().await?;
+        let mut pubsub = connection.into_pubsub();
+        pubsub.psubscribe(subject).await?;
+        info!(broker = name, %subject, "Subscribed");
+        reconnector.connected();
+        let mut msgs = pubsub.on_message();
+        while let Some(msg) = msgs.next().await {
+            let (body, headers) = decode_body(msg.get_payload_bytes().to_owned());
+            let _ = sender.send(
+                InputMessage {
+                    message: body,
+                    subject: subject.to_string(),
+                    broker: name.to_string(),
+                    response_subject: default_message_response_subject(subject),
+                    headers,
+                }
+                .into(),
+            );
+        }
+        Ok(())
+    }
+
+    async fn consume_queue(
+        client: &redis::Client,
+        name: &str,
+        subject: &str,
+        group: &str,
+        sender: &Sender,
+        reconnector: &mut Reconnector,
+    ) -> Result<()> {
+        let connection = client.get_tokio_connection().await?;
+        let mut rsmq = Rsmq::new_with_connection(connection, true, Some(subject));
+        match rsmq.create_queue(group, None, None, None).await {
+            Ok(_) => {}
+            Err(e) => {
+                error!(broker = name, %subject, group, "Error creating queue - {e:?}");
+            }
+        };
+
+        let subscription = format!("{subject}:rt:{group}");
+
+        let connection = client.get_tokio_connection().await?;
+        let mut pubsub = connection.into_pubsub();
+        pubsub.psubscribe(subscription).await?;
+        reconnector.connected();
+        let mut msgs = pubsub.on_message();
+        let mut poll = tokio::time::interval(QUEUE_POLL_INTERVAL);
+        info!(broker = name, %subject, group, "Subscribed to queue");
+        loop {
+            tokio::select! {
+                msg = msgs.next() => if msg.is_none() { break; },
+                _ = poll.tick() => {}
+            }
+            while let Ok(Some(body)) = rsmq.receive_message::<Vec<u8>>(group, None).await {
+                let acker = Arc::new(RedisQueueAcker {
+                    client: client.clone(),
+                    subject: subject.to_string(),
+                    group: group.to_string(),
+                    id: body.id,
+                });
+                let (body, headers) = decode_body(body.message);
+                let _ = sender.send(Delivery::with_acker(
+                    InputMessage {
+                        message: body,
+                        subject: subject.to_string(),
+                        broker: name.to_string(),
+                        response_subject: default_message_response_subject(subject),
+                        headers,
+                    },
+                    acker,
+                ));
+            }
+        }
+        Ok(())
+    }
+
     async fn setup_client(
         name: String,
         address: String,
+        backend: QueueBackend,
+        reconnect: ReconnectConfig,
         mut sub_rx: mpsc::Receiver<(String, Sender)>,
-        mut pub_rx: mpsc::Receiver<(String, OutputMessage)>,
+        publish_buffer: Arc<PublishBuffer>,
         mut queue_rx: mpsc::Receiver<(String, String, Sender)>,
     ) -> Result<()> {
         let client = redis::Client::open(address)?;
+        let monitor = publish_buffer.monitor().clone();
+        // Every task holding a connection, so stopping them all closes the connections
+        let mut tasks = JoinSet::new();
         {
-            let cloned = client.clone();
-            tokio::spawn(async move {
-                while let Some((subject, message)) = pub_rx.recv().await {
-                    if let Ok(mut connection) = cloned.get_tokio_connection().await {
-                        println!("Publish redis connection ready");
-                        let body = message.message;
-                        println!("Publishing to {subject}");
-                        let result: RedisFuture<Value> = connection.publish(subject.clone(), &body);
-                        match result.await {
-                            Ok(_) => println!("Published to {subject}"),
-                            Err(e) => eprintln!("Failed to publish - {e:?}"),
+            let client = client.clone();
+            let backend = backend.clone();
+            let mut reconnector = Reconnector::new(reconnect.clone(), monitor.clone());
+            let monitor = monitor.clone();
+            let publish_buffer = publish_buffer.clone();
+            let name = name.clone();
+            tasks.spawn(async move {
+                loop {
+                    // Each publish uses its own connection, so check the server is reachable
+                    // before waiting on the buffer - otherwise nothing would mark it as connected
+                    if !monitor.is_connected() {
+                        match client.get_tokio_connection().await {
+                            Ok(_) => reconnector.connected(),
+                            Err(e) => {
+                                if let Err(e) = reconnector.disconnected(e).await {
+                                    error!(broker = %name, "Publisher stopped - {e}");
+                                    publish_buffer.fail(e);
+                                    return;
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    let pending = publish_buffer.next().await;
+                    let result = match client.get_tokio_connection().await {
+                        Ok(connection) => {
+                            RedisBroker::publish_on(
+                                connection,
+                                &backend,
+                                &pending.subject,
+                                pending.message.clone(),
+                            )
+                            .await
                         }
-                        let mut rsmq = Rsmq::new_with_connection(connection, true, Some(&subject));
-                        let Ok(queues) = rsmq.list_queues().await else {
-                            continue;
-                        };
-                        for qname in queues.iter() {
-                            let result = rsmq.send_message(qname, body.clone(), None).await;
-                            match result {
-                                Ok(_) => println!("Published to queue {subject} - {qname}"),
-                                Err(e) => eprintln!("Failed to publish queue - {e:?}"),
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(()) => pending.complete(Ok(())),
+                        Err(e) if e.is_connection_dropped() || e.is_io_error() => {
+                            warn!(broker = %name, "Lost connection while publishing - {e:?}");
+                            publish_buffer.retry(pending);
+                            if let Err(e) = reconnector.disconnected(e).await {
+                                error!(broker = %name, "Publisher stopped - {e}");
+                                publish_buffer.fail(e);
+                                return;
                             }
                         }
+                        Err(e) => {
+                            error!(broker = %name, "Failed to publish - {e:?}");
+                            pending.complete(Err(e.into()));
+                        }
                     }
                 }
             });
         }
 
-        {
-            let client = client.clone();
-            let name = name.to_string();
-            tokio::spawn(async move {
-                while let Some((subject, sender)) = sub_rx.recv().await {
-                    let cloned = client.clone();
+        loop {
+            tokio::select! {
+                _ = publish_buffer.drained() => break,
+                Some((subject, sender)) = sub_rx.recv() => {
+                    let client = client.clone();
                     let name = name.to_string();
-                    tokio::spawn(async move {
-                        if let Ok(connection) = cloned.get_tokio_connection().await {
-                            println!("Subscribed to redis: {subject}");
-                            let mut pubsub = connection.into_pubsub();
-                            if let Ok(()) = pubsub.psubscribe(subject.clone()).await {
-                                let mut msgs = pubsub.on_message();
-                                while let Some(msg) = msgs.next().await {
-                                    let body = msg.get_payload_bytes().to_owned();
-                                    let _ = sender.send(InputMessage {
-                                        message: body,
-                                        subject: subject.clone(),
-                                        broker: name.clone(),
-                                        response_subject: default_message_response_subject(
-                                            &subject,
-                                        ),
-                                    });
+                    let mut reconnector = Reconnector::new(reconnect.clone(), monitor.clone());
+                    tasks.spawn(async move {
+                        loop {
+                            let reason = match RedisBroker::subscribe_on(
+                                &client,
+                                &name,
+                                &subject,
+                                &sender,
+                                &mut reconnector,
+                            )
+                            .await
+                            {
+                                Ok(()) => anyhow::anyhow!("subscription to {subject} closed"),
+                                Err(e) => e,
+                            };
+                            if let Err(e) = reconnector.disconnected(reason).await {
+                                error!(broker = %name, %subject, "Subscription stopped - {e}");
+                                return;
+                            }
+                        }
+                    });
+                }
+                Some((subject, group, sender)) = queue_rx.recv() => {
+                    let client = client.clone();
+                    let name = name.to_string();
+                    let backend = backend.clone();
+                    let mut reconnector = Reconnector::new(reconnect.clone(), monitor.clone());
+                    tasks.spawn(async move {
+                        loop {
+                            let result = match &backend {
+                                QueueBackend::Streams(options) => {
+                                    consume_stream(
+                                        &name,
+                                        &client,
+                                        options,
+                                        &subject,
+                                        &group,
+                                        &sender,
+                                        &mut reconnector,
+                                    )
+                                    .await
+                                }
+                                QueueBackend::Rsmq => {
+                                    RedisBroker::consume_queue(
+                                        &client,
+                                        &name,
+                                        &subject,
+                                        &group,
+                                        &sender,
+                                        &mut reconnector,
+                                    )
+                                    .await
                                 }
+                            };
+                            let reason = match result {
+                                Ok(()) => anyhow::anyhow!("queue subscription closed"),
+                                Err(e) => e,
+                            };
+                            warn!(broker = %name, %subject, %group, "Queue error - {reason:?}");
+                            if let Err(e) = reconnector.disconnected(reason).await {
+                                error!(
+                                    broker = %name,
+                                    %subject,
+                                    %group,
+                                    "Queue subscription stopped - {e}"
+                                );
+                                return;
                             }
                         }
                     });
                 }
-            });
+                Some(_) = tasks.join_next() => {}
+            }
         }
 
-        while let Some((subject, group, sender)) = queue_rx.recv().await {
-            let cloned = client.clone();
-            let name = name.to_string();
-            tokio::spawn(async move {
-                let Ok(connection) = cloned.get_tokio_connection().await else {
-                    eprintln!("Couldn't get redis connectio - for queue n");
-                    return;
-                };
-                let mut rsmq = Rsmq::new_with_connection(connection, true, Some(&subject));
-                match rsmq.create_queue(&group, None, None, None).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("Error creating queue - {subject} - {group}: {e:?}");
-                    }
-                };
-
-                let subscription = format!("{subject}:rt:{group}");
-
-                let Ok(connection) = cloned.get_tokio_connection().await else {
-                    eprintln!("Couldn't get redis connectio - for queue n");
-                    return;
-                };
-                let mut pubsub = connection.into_pubsub();
-                if let Ok(()) = pubsub.psubscribe(subscription.clone()).await {
-                    let mut msgs = pubsub.on_message();
-                    println!("Subscribed to redis queue: {subject} - {group}");
-                    while (msgs.next().await).is_some() {
-                        if let Ok(Some(body)) = rsmq.pop_message::<Vec<u8>>(&group).await {
-                            let _ = sender.send(InputMessage {
-                                message: body.message,
-                                subject: subject.clone(),
-                                broker: name.clone(),
-                                response_subject: default_message_response_subject(&subject),
-                            });
-                        }
-                    }
+        // Dropping the subscriptions' connections closes them, which also unsubscribes them
+        tasks.shutdown().await;
+        info!(broker = %name, "Closed Redis connections");
+        monitor.disconnected("Shut down");
+        Ok(())
+    }
+}
+
+async fn consume_stream(
+    name: &str,
+    client: &redis::Client,
+    options: &RedisStreamsOptions,
+    subject: &str,
+    group: &str,
+    sender: &Sender,
+    reconnector: &mut Reconnector,
+) -> Result<()> {
+    let mut connection = client.get_tokio_connection().await?;
+    let created: RedisResult<Value> = connection.xgroup_create_mkstream(subject, group, "0").await;
+    if let Err(e) = created {
+        if e.code() != Some("BUSYGROUP") {
+            return Err(e.into());
+        }
+    }
+    info!(broker = name, subject, group, consumer = %options.consumer, "Subscribed to stream");
+    reconnector.connected();
+
+    let deliver = |entry: &StreamId| {
+        let acker = Arc::new(RedisStreamAcker {
+            client: client.clone(),
+            subject: subject.to_string(),
+            group: group.to_string(),
+            consumer: options.consumer.clone(),
+            id: entry.id.clone(),
+            claim_idle_time: options.claim_idle_time,
+        });
+        let _ = sender.send(Delivery::with_acker(
+            stream_entry_to_message(name, subject, entry),
+            acker,
+        ));
+    };
+
+    let read_options = StreamReadOptions::default()
+        .group(group, &options.consumer)
+        .count(options.batch_size)
+        .block(QUEUE_POLL_INTERVAL.as_millis() as usize);
+    let mut claim_cursor = "0-0".to_string();
+    let mut next_claim = Instant::now();
+
+    loop {
+        if Instant::now() >= next_claim {
+            next_claim = Instant::now() + QUEUE_POLL_INTERVAL;
+            // Entries pending on a consumer for longer than the claim idle time were
+            // either never acknowledged or asked to be retried, so take them over.
+            let reply: RedisResult<Vec<Value>> = redis::cmd("XAUTOCLAIM")
+                .arg(subject)
+                .arg(group)
+                .arg(&options.consumer)
+                .arg(options.claim_idle_time.as_millis() as u64)
+                .arg(&claim_cursor)
+                .arg("COUNT")
+                .arg(options.batch_size)
+                .query_async(&mut connection)
+                .await;
+            match reply {
+                Ok(reply) if reply.len() >= 2 => {
+                    claim_cursor = from_redis_value(&reply[0])?;
+                    let claimed: StreamRangeReply = from_redis_value(&reply[1])?;
+                    claimed.ids.iter().for_each(deliver);
                 }
-            });
+                Ok(_) => {}
+                Err(e) => warn!(
+                    broker = name,
+                    subject, "Failed to reclaim pending entries - {e:?}"
+                ),
+            }
         }
 
-        Ok(())
+        let reply: StreamReadReply = connection
+            .xread_options(&[subject], &[">"], &read_options)
+            .await?;
+        for key in reply.keys {
+            key.ids.iter().for_each(deliver);
+        }
     }
 }
 
@@ -165,15 +659,19 @@ impl MessageBroker for RedisBroker {
         &self.name
     }
 
+    /// Topic subscriptions use `PSUBSCRIBE` glob patterns
+    fn wildcards(&self) -> Wildcards {
+        Wildcards::Glob
+    }
+
     async fn publish(&self, message: OutputMessage) -> Result<()> {
         let subject = &message
             .subject
             .as_deref()
             .ok_or(anyhow::Error::msg("No Subject To Publish"))?;
-        self.publish_handler
-            .send((subject.to_string(), message))
-            .await?;
-        Ok(())
+        self.publish_buffer
+            .publish(subject.to_string(), message)
+            .await
     }
 
     async fn subscribe_to_topic(&self, subject: &str) -> Result<Receiver> {
@@ -197,4 +695,81 @@ impl MessageBroker for RedisBroker {
             .await?;
         Ok(sender.subscribe())
     }
+
+    fn connection_events(&self) -> Option<broadcast::Receiver<ConnectionEvent>> {
+        Some(self.publish_buffer.monitor().subscribe())
+    }
+
+    fn health(&self) -> BrokerHealth {
+        self.publish_buffer.monitor().health()
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.publish_buffer.close().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use redis::{streams::StreamId, Value};
+    use spin_message_types::OutputMessage;
+
+    use super::{decode_body, encode_body, stream_entry_fields, stream_entry_to_message};
+
+    #[test]
+    fn messages_without_headers_are_published_raw() {
+        let message = OutputMessage {
+            message: "test".as_bytes().to_owned(),
+            ..Default::default()
+        };
+
+        let body = encode_body(message);
+
+        assert_eq!(body, "test".as_bytes());
+        assert_eq!(decode_body(body), ("test".as_bytes().to_owned(), vec![]));
+    }
+
+    #[test]
+    fn headers_survive_the_envelope() {
+        let headers = vec![(
+            "content-type".to_string(),
+            "text/plain".as_bytes().to_owned(),
+        )];
+        let message = OutputMessage {
+            message: "test".as_bytes().to_owned(),
+            headers: headers.clone(),
+            ..Default::default()
+        };
+
+        let body = encode_body(message);
+
+        assert_eq!(decode_body(body), ("test".as_bytes().to_owned(), headers));
+    }
+
+    #[test]
+    fn stream_entries_keep_headers_and_response_subject() {
+        let headers = vec![("x-test".to_string(), "value".as_bytes().to_owned())];
+        let message = OutputMessage {
+            message: "test".as_bytes().to_owned(),
+            response_subject: Some("reply".to_string()),
+            headers: headers.clone(),
+            ..Default::default()
+        };
+
+        let entry = StreamId {
+            id: "1-0".to_string(),
+            map: stream_entry_fields(&message)
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), Value::Data(v)))
+                .collect(),
+        };
+        let input = stream_entry_to_message("broker", "subject", &entry);
+
+        assert_eq!(input.message, "test".as_bytes());
+        assert_eq!(input.subject, "subject");
+        assert_eq!(input.broker, "broker");
+        assert_eq!(input.response_subject, Some("reply".to_string()));
+        assert_eq!(input.headers, headers);
+    }
 }
@@ -0,0 +1,209 @@
+use std::future::Future;
+
+use spin_message_types::{InputMessage, OutputMessage};
+
+use crate::{broker::Acknowledgement, configs::RetryConfig};
+
+/// Handles a message, retrying failures with the policy's backoff. `handle` gets the attempt number.
+/// Once retries run out, the last error is passed to `dead_letter` if there is one - and if that
+/// fails too, the message goes back to the broker rather than being lost.
+pub async fn with_retries<H, HandleFuture, D, DeadLetterFuture>(
+    retry: &RetryConfig,
+    mut handle: H,
+    dead_letter: Option<D>,
+) -> Acknowledgement
+where
+    H: FnMut(u32) -> HandleFuture,
+    HandleFuture: Future<Output = anyhow::Result<Acknowledgement>>,
+    D: FnOnce(anyhow::Error, u32) -> DeadLetterFuture,
+    DeadLetterFuture: Future<Output = anyhow::Result<()>>,
+{
+    let mut attempt = 1;
+    loop {
+        match handle(attempt).await {
+            Ok(ack) => return ack,
+            Err(e) if attempt >= retry.max_attempts => {
+                return match dead_letter {
+                    Some(dead_letter) => match dead_letter(e, attempt).await {
+                        Ok(()) => Acknowledgement::Ack,
+                        Err(_) => Acknowledgement::Nack { requeue: true },
+                    },
+                    None => Acknowledgement::Nack { requeue: false },
+                };
+            }
+            Err(_) => {
+                tokio::time::sleep(retry.backoff(attempt)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// The message sent to a dead letter subject, with headers describing where it came from & why it failed
+pub fn dead_letter_message(
+    component: &str,
+    message: InputMessage,
+    error: &anyhow::Error,
+    attempts: u32,
+) -> OutputMessage {
+    let mut headers = message.headers;
+    headers.extend(
+        [
+            ("x-dead-letter-error", format!("{error:#}")),
+            ("x-dead-letter-component", component.to_string()),
+            ("x-dead-letter-broker", message.broker),
+            ("x-dead-letter-subject", message.subject),
+            ("x-dead-letter-attempts", attempts.to_string()),
+        ]
+        .map(|(name, value)| (name.to_string(), value.into_bytes())),
+    );
+    if let Some(response_subject) = message.response_subject {
+        headers.push((
+            "x-dead-letter-response-subject".to_string(),
+            response_subject.into_bytes(),
+        ));
+    }
+    OutputMessage {
+        message: message.message,
+        subject: None,
+        broker: None,
+        response_subject: None,
+        headers,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use anyhow::anyhow;
+    use spin_message_types::InputMessage;
+
+    use super::{dead_letter_message, with_retries};
+    use crate::{broker::Acknowledgement, configs::RetryConfig};
+
+    fn retry(max_attempts: u32) -> RetryConfig {
+        RetryConfig {
+            max_attempts,
+            initial_backoff: Some(20),
+            ..Default::default()
+        }
+    }
+
+    type DeadLetters = Arc<Mutex<Vec<(String, u32)>>>;
+
+    fn dead_letter(
+        dead_letters: &DeadLetters,
+        result: anyhow::Result<()>,
+    ) -> impl FnOnce(anyhow::Error, u32) -> std::future::Ready<anyhow::Result<()>> {
+        let dead_letters = dead_letters.clone();
+        move |error, attempts| {
+            dead_letters
+                .lock()
+                .unwrap()
+                .push((error.to_string(), attempts));
+            std::future::ready(result)
+        }
+    }
+
+    #[tokio::test]
+    async fn failures_are_retried_with_backoff() {
+        let dead_letters = DeadLetters::default();
+        let started = Instant::now();
+        let ack = with_retries(
+            &retry(3),
+            |attempt| async move {
+                if attempt < 3 {
+                    Err(anyhow!("attempt {attempt} failed"))
+                } else {
+                    Ok(Acknowledgement::Ack)
+                }
+            },
+            Some(dead_letter(&dead_letters, Ok(()))),
+        )
+        .await;
+        assert_eq!(ack, Acknowledgement::Ack);
+        // 20ms after the first attempt, then 40ms after the second
+        assert!(started.elapsed() >= Duration::from_millis(60));
+        assert!(dead_letters.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn messages_are_dead_lettered_once_retries_run_out() {
+        let dead_letters = DeadLetters::default();
+        let ack = with_retries(
+            &retry(2),
+            |attempt| async move { Err(anyhow!("attempt {attempt} failed")) },
+            Some(dead_letter(&dead_letters, Ok(()))),
+        )
+        .await;
+        assert_eq!(ack, Acknowledgement::Ack);
+        assert_eq!(
+            *dead_letters.lock().unwrap(),
+            vec![("attempt 2 failed".to_string(), 2)]
+        );
+
+        let ack = with_retries(
+            &retry(1),
+            |_| async { Err(anyhow!("failed")) },
+            None::<fn(_, _) -> std::future::Ready<anyhow::Result<()>>>,
+        )
+        .await;
+        assert_eq!(ack, Acknowledgement::Nack { requeue: false });
+    }
+
+    #[tokio::test]
+    async fn messages_are_requeued_if_dead_lettering_fails() {
+        let dead_letters = DeadLetters::default();
+        let ack = with_retries(
+            &retry(1),
+            |_| async { Err(anyhow!("failed")) },
+            Some(dead_letter(&dead_letters, Err(anyhow!("broker is down")))),
+        )
+        .await;
+        assert_eq!(ack, Acknowledgement::Nack { requeue: true });
+        assert_eq!(dead_letters.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn dead_letters_describe_the_failure() {
+        let message = InputMessage {
+            message: b"hello".to_vec(),
+            subject: "orders.created".to_string(),
+            broker: "nats".to_string(),
+            response_subject: Some("orders.reply".to_string()),
+            headers: vec![("trace".to_string(), b"123".to_vec())],
+        };
+        let error = anyhow!("boom").context("handling failed");
+        let dead_letter = dead_letter_message("orders", message, &error, 3);
+        assert_eq!(dead_letter.message, b"hello");
+        assert_eq!(dead_letter.subject, None);
+        assert_eq!(dead_letter.response_subject, None);
+        let header = |name: &str| {
+            dead_letter
+                .headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| String::from_utf8_lossy(value).to_string())
+        };
+        assert_eq!(header("trace").as_deref(), Some("123"));
+        assert_eq!(
+            header("x-dead-letter-error").as_deref(),
+            Some("handling failed: boom")
+        );
+        assert_eq!(header("x-dead-letter-component").as_deref(), Some("orders"));
+        assert_eq!(header("x-dead-letter-broker").as_deref(), Some("nats"));
+        assert_eq!(
+            header("x-dead-letter-subject").as_deref(),
+            Some("orders.created")
+        );
+        assert_eq!(header("x-dead-letter-attempts").as_deref(), Some("3"));
+        assert_eq!(
+            header("x-dead-letter-response-subject").as_deref(),
+            Some("orders.reply")
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{sync::watch, time::Instant};
+use tracing::{info, warn};
+
+pub const DEFAULT_DRAIN_TIMEOUT: u64 = 30000;
+
+/// Coordinates shutting the trigger down - once started, no new messages are picked up,
+/// and messages that are still being handled get until the drain deadline to finish
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    drain_timeout: Duration,
+    deadline: Arc<watch::Sender<Option<Instant>>>,
+}
+
+impl Shutdown {
+    pub fn new(drain_timeout: Duration) -> Self {
+        Self {
+            drain_timeout,
+            deadline: Arc::new(watch::channel(None).0),
+        }
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    /// Starts shutting down on SIGINT or SIGTERM. A second signal exits straight away.
+    pub fn listen_for_signals(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            signal().await;
+            info!(drain_timeout = ?shutdown.drain_timeout, "Shutting down");
+            shutdown.start();
+            signal().await;
+            warn!("Received a second signal - exiting without draining");
+            std::process::exit(1);
+        });
+    }
+
+    pub fn start(&self) {
+        let deadline = Instant::now() + self.drain_timeout;
+        self.deadline.send_if_modified(|current| {
+            let started = current.is_none();
+            current.get_or_insert(deadline);
+            started
+        });
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.deadline.borrow().is_some()
+    }
+
+    /// Resolves once shutting down has started
+    pub async fn started(&self) -> Instant {
+        let mut deadline = self.deadline.subscribe();
+        let deadline = deadline
+            .wait_for(Option::is_some)
+            .await
+            .expect("The sender is held by self");
+        deadline.expect("Waited for the deadline to be set")
+    }
+
+    /// Resolves once shutting down has started and the drain timeout has run out
+    pub async fn drain_expired(&self) {
+        let deadline = self.started().await;
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+#[cfg(unix)]
+async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Shutdown;
+
+    #[tokio::test]
+    async fn the_drain_expires_after_the_timeout() {
+        let shutdown = Shutdown::new(Duration::from_millis(100));
+        assert!(!shutdown.is_started());
+
+        let expired = shutdown.drain_expired();
+        tokio::pin!(expired);
+        let waiting = tokio::time::timeout(Duration::from_millis(150), &mut expired);
+        assert!(waiting.await.is_err());
+
+        shutdown.start();
+        assert!(shutdown.is_started());
+        let early = tokio::time::timeout(Duration::from_millis(20), &mut expired);
+        assert!(early.await.is_err());
+        let late = tokio::time::timeout(Duration::from_millis(500), &mut expired);
+        assert!(late.await.is_ok());
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use spin_message_types::{InputMessage, OutputMessage};
+use tokio::sync::broadcast;
+use tracing::{info, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer as _};
+
+use crate::{
+    broker::{MessageBroker, QueueReceiver, Receiver, Wildcards},
+    configs::{LogFormat, StreamSubscription, TelemetryConfig},
+    connection::{BrokerHealth, ConnectionEvent},
+};
+
+const DEFAULT_SERVICE_NAME: &str = "spin-message-trigger";
+
+static LOG_PAYLOADS: AtomicBool = AtomicBool::new(false);
+
+/// Sets up logging & trace context propagation. Logs are filtered with `RUST_LOG`, defaulting to `info`.
+/// Spans are exported over OTLP if an endpoint is configured in the trigger metadata or
+/// the `OTEL_EXPORTER_OTLP_ENDPOINT` environment variable.
+pub fn init(config: Option<&TelemetryConfig>) -> Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    LOG_PAYLOADS.store(
+        config.map(|config| config.log_payloads).unwrap_or_default(),
+        Ordering::Relaxed,
+    );
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let logs = match config.map(|config| config.log_format).unwrap_or_default() {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    };
+
+    let endpoint = config
+        .and_then(|config| config.otlp_endpoint.clone())
+        .or_else(|| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok());
+    let tracer = match &endpoint {
+        Some(endpoint) => {
+            let service_name = config
+                .and_then(|config| config.service_name.clone())
+                .or_else(|| std::env::var("OTEL_SERVICE_NAME").ok())
+                .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string());
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", service_name),
+                ])))
+                .install_batch(runtime::Tokio)?;
+            Some(tracer)
+        }
+        None => None,
+    };
+
+    // Filtering only the logs, so the exported spans don't depend on the log level
+    if let Err(e) = tracing_subscriber::registry()
+        .with(logs.with_filter(filter))
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .try_init()
+    {
+        eprintln!("Couldn't install the tracing subscriber - {e}");
+    }
+    if let Some(endpoint) = endpoint {
+        info!(endpoint, "Exporting traces");
+    }
+    Ok(())
+}
+
+/// Flushes any spans that haven't been exported yet
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// A message payload for logging - only its size is shown unless `log_payloads` is set
+pub struct Payload<'a>(&'a [u8]);
+
+pub fn payload(message: &[u8]) -> Payload<'_> {
+    Payload(message)
+}
+
+impl Display for Payload<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if LOG_PAYLOADS.load(Ordering::Relaxed) {
+            write!(f, "{}", String::from_utf8_lossy(self.0))
+        } else {
+            write!(f, "<{} bytes>", self.0.len())
+        }
+    }
+}
+
+/// The request id within a request or response subject
+pub fn request_id(subject: &str) -> Option<&str> {
+    let mut parts = subject.splitn(3, '.');
+    match (parts.next(), parts.next()) {
+        (Some("request" | "response"), Some(id)) => Some(id),
+        _ => None,
+    }
+}
+
+/// Message headers, as a carrier for the trace context
+struct MessageHeaders<'a>(&'a mut Vec<(String, Vec<u8>)>);
+
+impl Injector for MessageHeaders<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.retain(|(name, _)| !name.eq_ignore_ascii_case(key));
+        self.0.push((key.to_string(), value.into_bytes()));
+    }
+}
+
+struct MessageHeadersRef<'a>(&'a [(String, Vec<u8>)]);
+
+impl Extractor for MessageHeadersRef<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .and_then(|(_, value)| std::str::from_utf8(value).ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|(name, _)| name.as_str()).collect()
+    }
+}
+
+struct HttpHeadersRef<'a>(&'a HeaderMap);
+
+impl Extractor for HttpHeadersRef<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Continues the trace carried in a message's headers, if there is one
+pub fn set_parent_from_message(span: &Span, headers: &[(String, Vec<u8>)]) {
+    let context = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&MessageHeadersRef(headers))
+    });
+    span.set_parent(context);
+}
+
+/// Continues the trace carried in an HTTP request's headers, if there is one
+pub fn set_parent_from_http(span: &Span, headers: &HeaderMap) {
+    let context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HttpHeadersRef(headers)));
+    span.set_parent(context);
+}
+
+/// Adds the span's trace context to a message's headers, replacing any that was there
+fn inject_into_message(span: &Span, headers: &mut Vec<(String, Vec<u8>)>) {
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut MessageHeaders(headers))
+    });
+}
+
+/// Wraps a broker, tracing everything published or requested through it and
+/// passing the trace context along in the message headers
+pub struct TracedBroker(Arc<dyn MessageBroker>);
+
+impl TracedBroker {
+    pub fn wrap(broker: Arc<dyn MessageBroker>) -> Arc<dyn MessageBroker> {
+        Arc::new(Self(broker))
+    }
+}
+
+#[async_trait]
+impl MessageBroker for TracedBroker {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    async fn publish(&self, mut message: OutputMessage) -> Result<()> {
+        let span = info_span!(
+            "publish",
+            otel.kind = "producer",
+            broker = self.name(),
+            subject = message.subject.as_deref().unwrap_or_default(),
+        );
+        inject_into_message(&span, &mut message.headers);
+        self.0.publish(message).instrument(span).await
+    }
+
+    async fn subscribe_to_topic(&self, subject: &str) -> Result<Receiver> {
+        self.0.subscribe_to_topic(subject).await
+    }
+
+    async fn subscribe_to_topic_from(&self, subject: &str, after: u64) -> Result<Option<Receiver>> {
+        self.0.subscribe_to_topic_from(subject, after).await
+    }
+
+    fn sequence(&self, message: &InputMessage) -> Option<u64> {
+        self.0.sequence(message)
+    }
+
+    fn wildcards(&self) -> Wildcards {
+        self.0.wildcards()
+    }
+
+    async fn subscribe_to_queue(&self, topic: &str, group: &str) -> Result<QueueReceiver> {
+        self.0.subscribe_to_queue(topic, group).await
+    }
+
+    async fn subscribe_to_stream(
+        &self,
+        subscription: &StreamSubscription,
+    ) -> Result<QueueReceiver> {
+        self.0.subscribe_to_stream(subscription).await
+    }
+
+    fn connection_events(&self) -> Option<broadcast::Receiver<ConnectionEvent>> {
+        self.0.connection_events()
+    }
+
+    fn health(&self) -> BrokerHealth {
+        self.0.health()
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.0.shutdown().await
+    }
+
+    async fn request(&self, mut request: OutputMessage) -> Result<InputMessage> {
+        let span = info_span!(
+            "request",
+            otel.kind = "client",
+            broker = self.name(),
+            subject = request.subject.as_deref().unwrap_or_default(),
+        );
+        inject_into_message(&span, &mut request.headers);
+        self.0.request(request).instrument(span).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::{
+        propagation::TextMapPropagator,
+        trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState},
+        Context,
+    };
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+
+    use super::{payload, request_id, MessageHeaders, MessageHeadersRef};
+
+    #[test]
+    fn payloads_are_redacted_by_default() {
+        assert_eq!(payload(b"secret").to_string(), "<6 bytes>");
+    }
+
+    #[test]
+    fn request_ids_are_found_in_request_subjects() {
+        assert_eq!(
+            request_id("request.01HGW2N7EHJ8Y5T0E4Z9KXJ6QV.GET.users"),
+            Some("01HGW2N7EHJ8Y5T0E4Z9KXJ6QV")
+        );
+        assert_eq!(
+            request_id("response.01HGW2N7EHJ8Y5T0E4Z9KXJ6QV.GET"),
+            Some("01HGW2N7EHJ8Y5T0E4Z9KXJ6QV")
+        );
+        assert_eq!(request_id("orders.created"), None);
+    }
+
+    #[test]
+    fn trace_context_round_trips_through_message_headers() {
+        let span_context = SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let context = Context::new().with_remote_span_context(span_context.clone());
+        let propagator = TraceContextPropagator::new();
+
+        let mut headers = vec![(
+            "traceparent".to_string(),
+            b"00-00000000000000000000000000000001-0000000000000001-00".to_vec(),
+        )];
+        propagator.inject_context(&context, &mut MessageHeaders(&mut headers));
+        assert_eq!(
+            headers
+                .iter()
+                .filter(|(name, _)| name == "traceparent")
+                .count(),
+            1
+        );
+
+        let extracted = propagator.extract(&MessageHeadersRef(&headers));
+        assert_eq!(
+            extracted.span().span_context().trace_id(),
+            span_context.trace_id()
+        );
+        assert_eq!(
+            extracted.span().span_context().span_id(),
+            span_context.span_id()
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use std::{fs::File, io::BufReader, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+    server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
+use rustls_pemfile::Item;
+
+use crate::configs::GatewayTlsConfig;
+
+/// Loads the gateway's certificate & key, along with the CA used to check client certificates if there is one
+pub fn rustls_config(config: &GatewayTlsConfig) -> Result<RustlsConfig> {
+    let certs = load_certs(&config.cert)?;
+    let key = load_key(&config.key)?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &config.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(&cert)?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server = builder
+        .with_single_cert(certs, key)
+        .context("Invalid gateway certificate or key")?;
+    // Websockets need HTTP/1.1
+    server.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(RustlsConfig::from_config(Arc::new(server)))
+}
+
+fn open(path: &str) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("Couldn't open {path}"))?;
+    Ok(BufReader::new(file))
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)?;
+    if certs.is_empty() {
+        bail!("No certificates in {path}");
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<PrivateKey> {
+    for item in rustls_pemfile::read_all(&mut open(path)?)? {
+        if let Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) = item {
+            return Ok(PrivateKey(key));
+        }
+    }
+    bail!("No private key in {path}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load_certs, load_key};
+
+    #[test]
+    fn files_without_pem_items_are_rejected() {
+        let path = std::env::temp_dir().join(format!("not-a-cert-{}.pem", ulid::Ulid::new()));
+        std::fs::write(&path, "not a certificate").unwrap();
+        let path = path.to_str().unwrap();
+
+        assert!(load_certs(path).is_err());
+        assert!(load_key(path).is_err());
+        assert!(load_certs("/does/not/exist.pem").is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use tokio::sync::Notify;
+
+use crate::{broker::Delivery, configs::OverflowPolicy};
+
+pub const DEFAULT_QUEUE_SIZE: usize = 100;
+
+/// Received messages waiting for one of a component's instances
+pub struct WorkQueue {
+    policy: OverflowPolicy,
+    capacity: usize,
+    queue: Mutex<VecDeque<Delivery>>,
+    closed: AtomicBool,
+    items: Notify,
+    space: Notify,
+}
+
+impl WorkQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            policy,
+            capacity: capacity.max(1),
+            queue: Default::default(),
+            closed: Default::default(),
+            items: Notify::new(),
+            space: Notify::new(),
+        }
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    /// Queues a delivery, returning the one that didn't fit if the queue is full.
+    /// With the `Block` policy, this waits for room instead.
+    pub async fn push(&self, delivery: Delivery) -> Option<Delivery> {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if queue.len() < self.capacity {
+                    queue.push_back(delivery);
+                    break;
+                }
+                match self.policy {
+                    OverflowPolicy::Block => {}
+                    OverflowPolicy::DropOldest => {
+                        let dropped = queue.pop_front();
+                        queue.push_back(delivery);
+                        self.items.notify_one();
+                        return dropped;
+                    }
+                    OverflowPolicy::DropNewest | OverflowPolicy::DeadLetter => {
+                        return Some(delivery)
+                    }
+                }
+            }
+            self.space.notified().await;
+        }
+        self.items.notify_one();
+        None
+    }
+
+    /// Waits for the next delivery - returning `None` once the queue is closed and empty
+    pub async fn next(&self) -> Option<Delivery> {
+        loop {
+            if let Some(next) = self.queue.lock().unwrap().pop_front() {
+                self.space.notify_one();
+                return Some(next);
+            }
+            if self.closed.load(Ordering::Acquire) {
+                // Pass the wake up along, so every worker sees the queue closing
+                self.items.notify_one();
+                return None;
+            }
+            self.items.notified().await;
+        }
+    }
+
+    /// Stops the workers once they've handled everything that's already queued
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.items.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use spin_message_types::InputMessage;
+
+    use super::WorkQueue;
+    use crate::{broker::Delivery, configs::OverflowPolicy};
+
+    fn delivery(subject: &str) -> Delivery {
+        InputMessage {
+            subject: subject.to_string(),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn the_oldest_delivery_is_dropped_when_full() {
+        let queue = WorkQueue::new(2, OverflowPolicy::DropOldest);
+        assert!(queue.push(delivery("a")).await.is_none());
+        assert!(queue.push(delivery("b")).await.is_none());
+        let dropped = queue.push(delivery("c")).await.unwrap();
+
+        assert_eq!(dropped.subject, "a");
+        assert_eq!(queue.next().await.unwrap().subject, "b");
+        assert_eq!(queue.next().await.unwrap().subject, "c");
+    }
+
+    #[tokio::test]
+    async fn the_newest_delivery_is_returned_when_full() {
+        let queue = WorkQueue::new(1, OverflowPolicy::DropNewest);
+        assert!(queue.push(delivery("a")).await.is_none());
+        let dropped = queue.push(delivery("b")).await.unwrap();
+
+        assert_eq!(dropped.subject, "b");
+        queue.close();
+        assert_eq!(queue.next().await.unwrap().subject, "a");
+        assert!(queue.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn blocking_waits_for_room() {
+        let queue = WorkQueue::new(1, OverflowPolicy::Block);
+        assert!(queue.push(delivery("a")).await.is_none());
+        let blocked = tokio::time::timeout(Duration::from_millis(50), queue.push(delivery("b")));
+        assert!(blocked.await.is_err());
+
+        let (pushed, next) = tokio::join!(queue.push(delivery("c")), queue.next());
+        assert!(pushed.is_none());
+        assert_eq!(next.unwrap().subject, "a");
+        assert_eq!(queue.next().await.unwrap().subject, "c");
+    }
+
+    #[tokio::test]
+    async fn closing_drains_the_queue_first() {
+        let queue = WorkQueue::new(2, OverflowPolicy::Block);
+        queue.push(delivery("a")).await;
+        queue.close();
+
+        assert_eq!(queue.next().await.unwrap().subject, "a");
+        assert!(queue.next().await.is_none());
+        assert!(queue.next().await.is_none());
+    }
+}